@@ -1,8 +1,90 @@
 import React, { useState, useEffect, useRef } from "react";
-import { Message, User, webSocketService } from "../services/WebSocketService";
+import {
+  Message,
+  Span,
+  User,
+  UserStatus,
+  webSocketService,
+} from "../services/WebSocketService";
 import { toast } from "react-hot-toast";
 import { UserGroupIcon } from "@heroicons/react/24/outline";
 
+const statusColors: Record<UserStatus, string> = {
+  online: "bg-green-500",
+  away: "bg-yellow-400",
+  busy: "bg-red-500",
+  invisible: "bg-gray-400",
+};
+
+const applyReaction = (
+  message: Message,
+  emoji: string,
+  user: string,
+  added: boolean
+): Message => {
+  const reactions = { ...(message.reactions ?? {}) };
+  const users = (reactions[emoji] ?? []).filter((u) => u !== user);
+  if (added) {
+    users.push(user);
+  }
+  if (users.length > 0) {
+    reactions[emoji] = users;
+  } else {
+    delete reactions[emoji];
+  }
+  return { ...message, reactions };
+};
+
+// 渲染服务器解析好的片段；没有片段时显示原文
+const renderContent = (message: Message, currentName: string) => {
+  if (!message.spans || message.spans.length === 0) {
+    return message.content;
+  }
+  return message.spans.map((span: Span, i) => {
+    switch (span.type) {
+      case "bold":
+        return <strong key={i}>{span.text}</strong>;
+      case "italic":
+        return <em key={i}>{span.text}</em>;
+      case "strike":
+        return <s key={i}>{span.text}</s>;
+      case "code":
+        return (
+          <code key={i} className="px-1 rounded bg-gray-200 text-gray-800">
+            {span.text}
+          </code>
+        );
+      case "codeBlock":
+        return (
+          <pre key={i} className="p-2 rounded bg-gray-800 text-gray-100 text-xs overflow-x-auto">
+            {span.text}
+          </pre>
+        );
+      case "link":
+        return (
+          <a key={i} href={span.url} target="_blank" rel="noreferrer" className="underline">
+            {span.text}
+          </a>
+        );
+      case "mention":
+        return (
+          <span
+            key={i}
+            className={`font-semibold ${
+              span.username === currentName ? "bg-yellow-200 text-gray-800 rounded px-1" : ""
+            }`}
+          >
+            @{span.username}
+          </span>
+        );
+      case "lineBreak":
+        return <br key={i} />;
+      default:
+        return <React.Fragment key={i}>{span.text}</React.Fragment>;
+    }
+  });
+};
+
 interface ChatProps {
   username: string;
   onLogout: () => void;
@@ -12,27 +94,192 @@ const Chat: React.FC<ChatProps> = ({ username, onLogout }) => {
   const [messages, setMessages] = useState<Message[]>([]);
   const [inputMessage, setInputMessage] = useState("");
   const [users, setUsers] = useState<User[]>([]);
+  const [currentName, setCurrentName] = useState(username);
+  const [typingUsers, setTypingUsers] = useState<string[]>([]);
+  const [thread, setThread] = useState<{
+    parent: Message;
+    replies: Message[];
+  } | null>(null);
+  const [replyInput, setReplyInput] = useState("");
+  const [unread, setUnread] = useState(0);
+  const [muted, setMuted] = useState(false);
+  const mutedRef = useRef(false);
+  // 用户名 -> 最后已读的消息 id
+  const [readPositions, setReadPositions] = useState<Record<string, string>>(
+    {}
+  );
   const messagesEndRef = useRef<null | HTMLDivElement>(null);
 
   useEffect(() => {
     webSocketService.connect(username);
 
     const messageUnsubscribe = webSocketService.onMessage((message) => {
-      setMessages((prev) => [...prev, message]);
-      if (message.username !== username) {
+      // 滞后补发时可能收到重复消息，按 id 去重
+      setMessages((prev) =>
+        prev.some((m) => m.id === message.id) ? prev : [...prev, message]
+      );
+      if (
+        message.username !== webSocketService.getUsername() &&
+        !mutedRef.current
+      ) {
         toast(`${message.username}: ${message.content}`, {
           duration: 3000,
         });
       }
     });
 
+    // 被提及时总是提醒，即使屏蔽了房间
+    const mentionUnsubscribe = webSocketService.on("mention", (data) => {
+      toast(`${data.message.username} 提到了你: ${data.message.content}`, {
+        icon: "🔔",
+        duration: 6000,
+      });
+    });
+
     const userListUnsubscribe = webSocketService.onUserList((userList) => {
       setUsers(userList);
     });
 
+    const renameUnsubscribe = webSocketService.onRename((oldName, newName) => {
+      setCurrentName(webSocketService.getUsername());
+      setMessages((prev) =>
+        prev.map((m) =>
+          m.username === oldName ? { ...m, username: newName } : m
+        )
+      );
+    });
+
+    const presenceUnsubscribe = webSocketService.onPresence((user) => {
+      setUsers((prev) =>
+        prev.map((u) => (u.username === user.username ? user : u))
+      );
+    });
+
+    const editUnsubscribe = webSocketService.onMessageEdited(
+      (id, content, editedAt, spans) => {
+        setMessages((prev) =>
+          prev.map((m) =>
+            m.id === id ? { ...m, content, spans, edited_at: editedAt } : m
+          )
+        );
+      }
+    );
+
+    const deleteUnsubscribe = webSocketService.onMessageDeleted((id) => {
+      setMessages((prev) => prev.filter((m) => m.id !== id));
+    });
+
+    const typingUnsubscribe = webSocketService.onTyping((name, typing) => {
+      setTypingUsers((prev) => {
+        const others = prev.filter((u) => u !== name);
+        return typing ? [...others, name] : others;
+      });
+    });
+
+    const threadUnsubscribe = webSocketService.on("thread", (data) => {
+      setThread({ parent: data.parent, replies: data.replies });
+    });
+
+    const threadReplyUnsubscribe = webSocketService.on(
+      "threadReply",
+      (data) => {
+        const reply: Message = data.message;
+        setThread((prev) =>
+          prev && prev.parent.id === reply.parent_id
+            ? { ...prev, replies: [...prev.replies, reply] }
+            : prev
+        );
+        if (
+          reply.username !== webSocketService.getUsername() &&
+          !mutedRef.current
+        ) {
+          toast(`${reply.username} 在话题中回复: ${reply.content}`);
+        }
+      }
+    );
+
+    const threadUpdatedUnsubscribe = webSocketService.on(
+      "threadUpdated",
+      (data) => {
+        setMessages((prev) =>
+          prev.map((m) =>
+            m.id === data.id ? { ...m, reply_count: data.replyCount } : m
+          )
+        );
+      }
+    );
+
+    const reactionUnsubscribes = ["reactionAdded", "reactionRemoved"].map(
+      (type) =>
+        webSocketService.on(type, (data) => {
+          setMessages((prev) =>
+            prev.map((m) =>
+              m.id === data.id
+                ? applyReaction(
+                    m,
+                    data.emoji,
+                    data.username,
+                    type === "reactionAdded"
+                  )
+                : m
+            )
+          );
+        })
+    );
+
+    const roomListUnsubscribe = webSocketService.on("roomList", (data) => {
+      setUnread(data.rooms[0]?.unread ?? 0);
+      mutedRef.current = data.rooms[0]?.muted ?? false;
+      setMuted(mutedRef.current);
+    });
+
+    const readReceiptUnsubscribe = webSocketService.on(
+      "readReceipt",
+      (data) => {
+        setReadPositions((prev) => ({ ...prev, [data.username]: data.id }));
+      }
+    );
+
+    const enrichedUnsubscribe = webSocketService.on(
+      "messageEnriched",
+      (data) => {
+        setMessages((prev) =>
+          prev.map((m) =>
+            m.id === data.id ? { ...m, previews: data.previews } : m
+          )
+        );
+      }
+    );
+
+    const missedUnsubscribe = webSocketService.on(
+      "missedMessages",
+      (data) => {
+        toast(`网络过慢，漏掉了 ${data.count} 条消息，正在补发`);
+      }
+    );
+
+    const errorUnsubscribe = webSocketService.onError((message) => {
+      toast.error(message);
+    });
+
     return () => {
       messageUnsubscribe();
+      mentionUnsubscribe();
       userListUnsubscribe();
+      renameUnsubscribe();
+      presenceUnsubscribe();
+      typingUnsubscribe();
+      editUnsubscribe();
+      deleteUnsubscribe();
+      threadUnsubscribe();
+      threadReplyUnsubscribe();
+      threadUpdatedUnsubscribe();
+      reactionUnsubscribes.forEach((unsubscribe) => unsubscribe());
+      roomListUnsubscribe();
+      readReceiptUnsubscribe();
+      missedUnsubscribe();
+      enrichedUnsubscribe();
+      errorUnsubscribe();
       webSocketService.disconnect();
     };
   }, [username]);
@@ -43,11 +290,17 @@ const Chat: React.FC<ChatProps> = ({ username, onLogout }) => {
 
   useEffect(() => {
     scrollToBottom();
+    // 页面可见时把最新一条消息标记为已读
+    const last = messages[messages.length - 1];
+    if (last && document.visibilityState === "visible") {
+      webSocketService.markRead(last.id);
+    }
   }, [messages]);
 
   const handleSubmit = (e: React.FormEvent) => {
     e.preventDefault();
     if (inputMessage.trim()) {
+      webSocketService.sendTyping(false);
       webSocketService.sendMessage(inputMessage.trim());
       setInputMessage("");
     }
@@ -67,8 +320,17 @@ const Chat: React.FC<ChatProps> = ({ username, onLogout }) => {
               key={user.username}
               className="flex items-center space-x-2 p-2 rounded hover:bg-gray-100"
             >
-              <div className={`w-2 h-2 rounded-full bg-green-500`} />
+              <div
+                className={`w-2 h-2 rounded-full ${
+                  statusColors[user.status] ?? "bg-green-500"
+                }`}
+              />
               <span className="text-sm">{user.username}</span>
+              {user.status_text && (
+                <span className="text-xs text-gray-400 truncate">
+                  {user.status_text}
+                </span>
+              )}
             </div>
           ))}
         </div>
@@ -77,7 +339,41 @@ const Chat: React.FC<ChatProps> = ({ username, onLogout }) => {
       {/* 聊天主区域 */}
       <div className="flex-1 flex flex-col">
         {/* 顶部栏，添加登出按钮 */}
-        <div className="flex justify-end items-center p-2 bg-white border-b">
+        <div className="flex justify-end items-center p-2 bg-white border-b space-x-2">
+          {unread > 0 && (
+            <span className="text-xs text-white bg-red-500 rounded-full px-2">
+              {unread} 条未读
+            </span>
+          )}
+          <button
+            onClick={() => webSocketService.setMuted(!muted)}
+            className="px-3 py-1 bg-gray-200 rounded hover:bg-gray-300"
+          >
+            {muted ? "取消屏蔽" : "屏蔽通知"}
+          </button>
+          <select
+            onChange={(e) =>
+              webSocketService.sendStatus(e.target.value as UserStatus)
+            }
+            defaultValue="online"
+            className="px-2 py-1 border rounded"
+          >
+            <option value="online">在线</option>
+            <option value="away">离开</option>
+            <option value="busy">忙碌</option>
+            <option value="invisible">隐身</option>
+          </select>
+          <button
+            onClick={() => {
+              const newName = window.prompt("新的昵称", currentName);
+              if (newName && newName.trim() && newName.trim() !== currentName) {
+                webSocketService.sendRename(newName.trim());
+              }
+            }}
+            className="px-3 py-1 bg-gray-200 rounded hover:bg-gray-300"
+          >
+            改名
+          </button>
           <button
             onClick={() => {
               webSocketService.sendLogout();
@@ -93,27 +389,161 @@ const Chat: React.FC<ChatProps> = ({ username, onLogout }) => {
         </div>
         <div className="flex-1 p-4 overflow-y-auto">
           <div className="space-y-4">
-            {messages.map((message, index) => (
+            {messages.map((message) => (
               <div
-                key={index}
+                key={message.id}
                 className={`flex ${
-                  message.username === username
+                  message.username === currentName
                     ? "justify-end"
                     : "justify-start"
                 }`}
               >
                 <div
                   className={`max-w-xs lg:max-w-md px-4 py-2 rounded-lg ${
-                    message.username === username
+                    message.username === currentName
                       ? "bg-blue-500 text-white"
                       : "bg-white text-gray-800"
                   }`}
                 >
                   <div className="font-bold">{message.username}</div>
-                  <div>{message.content}</div>
+                  <div>{renderContent(message, currentName)}</div>
+                  {message.attachment?.width && (
+                    // 有缩略图时只加载缩略图，小图片直接显示原图
+                    <a
+                      href={webSocketService.fileUrl(message.attachment.id)}
+                      target="_blank"
+                      rel="noreferrer"
+                    >
+                      <img
+                        src={webSocketService.fileUrl(
+                          message.attachment.thumbnail?.id ??
+                            message.attachment.id
+                        )}
+                        width={
+                          message.attachment.thumbnail?.width ??
+                          message.attachment.width
+                        }
+                        height={
+                          message.attachment.thumbnail?.height ??
+                          message.attachment.height
+                        }
+                        alt={message.attachment.name}
+                        className="rounded mt-1"
+                      />
+                    </a>
+                  )}
+                  {message.attachment && (
+                    <a
+                      href={webSocketService.fileUrl(message.attachment.id)}
+                      download={message.attachment.name}
+                      target="_blank"
+                      rel="noreferrer"
+                      className="block text-sm underline"
+                    >
+                      📎 {message.attachment.name} (
+                      {Math.ceil(message.attachment.size / 1024)} KB)
+                    </a>
+                  )}
+                  {message.previews?.map((preview) => (
+                    <a
+                      key={preview.url}
+                      href={preview.url}
+                      target="_blank"
+                      rel="noreferrer"
+                      className="block mt-1 p-2 rounded border-l-4 border-gray-300 bg-gray-50 text-gray-800"
+                    >
+                      {preview.site_name && (
+                        <div className="text-xs text-gray-500">
+                          {preview.site_name}
+                        </div>
+                      )}
+                      <div className="font-semibold text-sm">
+                        {preview.title ?? preview.url}
+                      </div>
+                      {preview.description && (
+                        <div className="text-xs">{preview.description}</div>
+                      )}
+                      {preview.image && (
+                        <img
+                          src={preview.image}
+                          alt=""
+                          className="mt-1 max-h-32 rounded"
+                        />
+                      )}
+                    </a>
+                  ))}
                   <div className="text-xs opacity-75">
                     {new Date(message.timestamp).toLocaleTimeString()}
+                    {message.edited_at && " (已编辑)"}
                   </div>
+                  {message.username === currentName && (
+                    <div className="text-xs opacity-75">
+                      {Object.entries(readPositions)
+                        .filter(
+                          ([reader, id]) =>
+                            reader !== currentName && id >= message.id
+                        )
+                        .map(([reader]) => reader)
+                        .join("、") || null}
+                    </div>
+                  )}
+                  <div className="flex flex-wrap gap-1 mt-1">
+                    {Object.entries(message.reactions ?? {}).map(
+                      ([emoji, reactedUsers]) => (
+                        <button
+                          key={emoji}
+                          onClick={() =>
+                            webSocketService.react(
+                              message.id,
+                              emoji,
+                              !reactedUsers.includes(currentName)
+                            )
+                          }
+                          title={reactedUsers.join("、")}
+                          className="text-xs px-1 rounded bg-gray-200 text-gray-800"
+                        >
+                          {emoji} {reactedUsers.length}
+                        </button>
+                      )
+                    )}
+                    <button
+                      onClick={() =>
+                        webSocketService.react(message.id, "👍", true)
+                      }
+                      className="text-xs px-1 rounded opacity-50 hover:opacity-100"
+                    >
+                      +👍
+                    </button>
+                  </div>
+                  <button
+                    onClick={() => webSocketService.requestThread(message.id)}
+                    className="text-xs underline opacity-75 hover:opacity-100"
+                  >
+                    {message.reply_count
+                      ? `${message.reply_count} 条回复`
+                      : "回复"}
+                  </button>
+                  {message.username === currentName && (
+                    <div className="text-xs space-x-2 mt-1">
+                      <button
+                        onClick={() => {
+                          const content = window.prompt("编辑消息", message.content);
+                          if (content && content.trim()) {
+                            webSocketService.editMessage(message.id, content.trim());
+                          }
+                        }}
+                        className="underline opacity-75 hover:opacity-100"
+                      >
+                        编辑
+                      </button>
+                      <button
+                        onClick={() => webSocketService.deleteMessage(message.id)}
+                        className="underline opacity-75 hover:opacity-100"
+                      >
+                        删除
+                      </button>
+                    </div>
+                  )}
                 </div>
               </div>
             ))}
@@ -121,15 +551,39 @@ const Chat: React.FC<ChatProps> = ({ username, onLogout }) => {
           </div>
         </div>
 
+        {typingUsers.length > 0 && (
+          <div className="px-4 py-1 text-xs text-gray-500">
+            {typingUsers.join("、")} 正在输入...
+          </div>
+        )}
         <form onSubmit={handleSubmit} className="p-4 bg-white border-t">
           <div className="flex space-x-4">
             <input
               type="text"
               value={inputMessage}
-              onChange={(e) => setInputMessage(e.target.value)}
+              onChange={(e) => {
+                setInputMessage(e.target.value);
+                webSocketService.sendTyping(e.target.value.length > 0);
+              }}
               placeholder="输入消息..."
               className="flex-1 p-2 border rounded-lg focus:outline-none focus:border-blue-500"
             />
+            <label className="px-4 py-2 bg-gray-200 rounded-lg hover:bg-gray-300 cursor-pointer">
+              文件
+              <input
+                type="file"
+                className="hidden"
+                onChange={(e) => {
+                  const file = e.target.files?.[0];
+                  e.target.value = "";
+                  if (file) {
+                    webSocketService
+                      .uploadFile(file)
+                      .catch((err) => toast.error(err.message));
+                  }
+                }}
+              />
+            </label>
             <button
               type="submit"
               className="px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600 focus:outline-none"
@@ -139,6 +593,48 @@ const Chat: React.FC<ChatProps> = ({ username, onLogout }) => {
           </div>
         </form>
       </div>
+      {/* 话题面板 */}
+      {thread && (
+        <div className="w-80 bg-white border-l flex flex-col">
+          <div className="flex justify-between items-center p-2 border-b">
+            <h2 className="font-semibold">话题</h2>
+            <button onClick={() => setThread(null)} className="text-gray-500">
+              关闭
+            </button>
+          </div>
+          <div className="flex-1 p-2 overflow-y-auto space-y-2">
+            {[thread.parent, ...thread.replies].map((m) => (
+              <div key={m.id} className="p-2 rounded bg-gray-50">
+                <div className="font-bold text-sm">{m.username}</div>
+                {m.quote && (
+                  <div className="text-xs text-gray-400 border-l-2 pl-1">
+                    {m.quote}
+                  </div>
+                )}
+                <div className="text-sm">{m.content}</div>
+              </div>
+            ))}
+          </div>
+          <form
+            onSubmit={(e) => {
+              e.preventDefault();
+              if (replyInput.trim()) {
+                webSocketService.sendReply(thread.parent.id, replyInput.trim());
+                setReplyInput("");
+              }
+            }}
+            className="p-2 border-t"
+          >
+            <input
+              type="text"
+              value={replyInput}
+              onChange={(e) => setReplyInput(e.target.value)}
+              placeholder="回复话题..."
+              className="w-full p-2 border rounded-lg focus:outline-none focus:border-blue-500"
+            />
+          </form>
+        </div>
+      )}
     </div>
   );
 };
@@ -1,27 +1,92 @@
 export interface Message {
+  id: string;
   username: string;
   content: string;
   timestamp: Date;
+  edited_at?: string;
+  parent_id?: string;
+  quote?: string;
+  reply_count?: number;
+  reactions?: Record<string, string[]>;
+  attachment?: Attachment;
+  previews?: LinkPreview[];
+  spans?: Span[];
 }
 
+// 服务器解析后的 Markdown 片段，均按纯文本渲染
+export type Span =
+  | { type: "text" | "bold" | "italic" | "strike" | "code"; text: string }
+  | { type: "codeBlock"; text: string; lang?: string }
+  | { type: "link"; text: string; url: string }
+  | { type: "mention"; username: string }
+  | { type: "lineBreak" };
+
+export interface LinkPreview {
+  url: string;
+  title?: string;
+  description?: string;
+  image?: string;
+  site_name?: string;
+}
+
+export interface Attachment {
+  id: string;
+  name: string;
+  size: number;
+  content_type: string;
+  width?: number;
+  height?: number;
+  thumbnail?: {
+    id: string;
+    width: number;
+    height: number;
+    size: number;
+  };
+}
+
+const HTTP_BASE = "http://localhost:8080";
+
+const tokenKey = (username: string) => `chat-token:${username}`;
+
+export type UserStatus = "online" | "away" | "busy" | "invisible";
+
 export interface User {
   username: string;
   last_seen: string;
+  status: UserStatus;
+  status_text: string | null;
 }
 
 class WebSocketService {
   private socket: WebSocket | null = null;
   private messageHandlers: ((message: Message) => void)[] = [];
   private userListHandlers: ((users: User[]) => void)[] = [];
+  private renameHandlers: ((oldName: string, newName: string) => void)[] = [];
+  private errorHandlers: ((message: string) => void)[] = [];
+  private presenceHandlers: ((user: User) => void)[] = [];
+  private typingHandlers: ((username: string, typing: boolean) => void)[] =
+    [];
+  private lastTypingSent = 0;
+  private editHandlers: ((
+    id: string,
+    content: string,
+    editedAt: string,
+    spans?: Span[]
+  ) => void)[] = [];
+  private deleteHandlers: ((id: string) => void)[] = [];
+  // 其余服务器事件按 type 分发
+  private eventHandlers: Map<string, ((data: any) => void)[]> = new Map();
   private username: string = "";
+  private uploadToken: string | null = null;
 
   public connect(username: string) {
     this.username = username;
     this.socket = new WebSocket("ws://localhost:8080/ws");
 
     this.socket.onopen = () => {
-      // 登录
-      this.socket?.send(JSON.stringify({ username }));
+      // 登录，带上之前为这个名字保存的令牌
+      const token = localStorage.getItem(tokenKey(username)) ?? undefined;
+      this.socket?.send(JSON.stringify({ username, token }));
     };
 
     this.socket.onmessage = (event) => {
@@ -32,6 +97,60 @@ class WebSocketService {
           this.userListHandlers.forEach((handler) => handler(data.users));
           return;
         }
+        // 本次连接的上传令牌，HTTP 上传时用来确定上传者
+        if (data.type === "uploadToken") {
+          this.uploadToken = data.token;
+          return;
+        }
+        // 第一次使用这个名字时服务器生成的登录令牌
+        if (data.type === "credentials") {
+          localStorage.setItem(tokenKey(data.username), data.token);
+          return;
+        }
+        if (data.type === "userRenamed") {
+          if (data.oldUsername === this.username) {
+            // 令牌随名字一起转移
+            const token = localStorage.getItem(tokenKey(data.oldUsername));
+            if (token) {
+              localStorage.setItem(tokenKey(data.newUsername), token);
+              localStorage.removeItem(tokenKey(data.oldUsername));
+            }
+            this.username = data.newUsername;
+          }
+          this.renameHandlers.forEach((handler) =>
+            handler(data.oldUsername, data.newUsername)
+          );
+          return;
+        }
+        if (data.type === "messageEdited") {
+          this.editHandlers.forEach((handler) =>
+            handler(data.id, data.content, data.editedAt, data.spans)
+          );
+          return;
+        }
+        if (data.type === "messageDeleted") {
+          this.deleteHandlers.forEach((handler) => handler(data.id));
+          return;
+        }
+        if (data.type === "typing") {
+          this.typingHandlers.forEach((handler) =>
+            handler(data.username, data.typing)
+          );
+          return;
+        }
+        if (data.type === "presence") {
+          this.presenceHandlers.forEach((handler) => handler(data.user));
+          return;
+        }
+        if (data.type && this.eventHandlers.has(data.type)) {
+          this.eventHandlers.get(data.type)!.forEach((handler) => handler(data));
+          return;
+        }
+        // 被限流、踢出、封禁或服务器重启时同样作为错误提示显示
+        if (["error", "rateLimited", "kicked", "banned", "shutdown"].includes(data.type)) {
+          this.errorHandlers.forEach((handler) => handler(data.message));
+          return;
+        }
         // 过滤 system userList 消息
         if (data.username === "system" && typeof data.content === "string") {
           try {
@@ -62,12 +181,108 @@ class WebSocketService {
     };
   }
 
+  public getUsername() {
+    return this.username;
+  }
+
   public sendMessage(message: string) {
     if (this.socket && this.socket.readyState === WebSocket.OPEN) {
       this.socket.send(JSON.stringify({ content: message }));
     }
   }
 
+  public editMessage(id: string, content: string) {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(JSON.stringify({ type: "edit", id, content }));
+    }
+  }
+
+  public deleteMessage(id: string) {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(JSON.stringify({ type: "delete", id }));
+    }
+  }
+
+  public sendReply(parentId: string, content: string, quote = false) {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(
+        JSON.stringify({ type: "reply", parentId, content, quote })
+      );
+    }
+  }
+
+  public react(id: string, emoji: string, added: boolean) {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(
+        JSON.stringify({ type: added ? "react" : "unreact", id, emoji })
+      );
+    }
+  }
+
+  public markRead(id: string, room = "lobby") {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(JSON.stringify({ type: "read", room, id }));
+    }
+  }
+
+  public setMuted(muted: boolean, room = "lobby") {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(JSON.stringify({ type: muted ? "mute" : "unmute", room }));
+    }
+  }
+
+  public requestThread(id: string) {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(JSON.stringify({ type: "getThread", id }));
+    }
+  }
+
+  // 输入中提示最多每两秒发送一次，服务器会在超时后自动清除
+  public sendTyping(typing: boolean) {
+    if (!this.socket || this.socket.readyState !== WebSocket.OPEN) {
+      return;
+    }
+    const now = Date.now();
+    if (typing && now - this.lastTypingSent < 2000) {
+      return;
+    }
+    this.lastTypingSent = typing ? now : 0;
+    this.socket.send(JSON.stringify({ type: "typing", typing }));
+  }
+
+  // 文件通过 HTTP 上传，服务器保存后会以带附件的消息广播
+  public async uploadFile(file: File, content = "") {
+    if (!this.uploadToken) {
+      throw new Error("请先登录");
+    }
+    const params = new URLSearchParams({ name: file.name, content });
+    const response = await fetch(`${HTTP_BASE}/upload?${params}`, {
+      method: "POST",
+      headers: { Authorization: `Bearer ${this.uploadToken}` },
+      body: file,
+    });
+    if (!response.ok) {
+      const data = await response.json().catch(() => null);
+      throw new Error(data?.message ?? `上传失败 (${response.status})`);
+    }
+  }
+
+  public fileUrl(id: string) {
+    return `${HTTP_BASE}/files/${id}`;
+  }
+
+  public sendRename(newName: string) {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(JSON.stringify({ type: "rename", username: newName }));
+    }
+  }
+
+  public sendStatus(status: UserStatus, text?: string) {
+    if (this.socket && this.socket.readyState === WebSocket.OPEN) {
+      this.socket.send(JSON.stringify({ type: "status", status, text }));
+    }
+  }
+
   public sendLogout() {
     if (this.socket && this.socket.readyState === WebSocket.OPEN) {
       this.socket.send(JSON.stringify({ type: "logout" }));
@@ -90,7 +305,72 @@ class WebSocketService {
     };
   }
 
+  public onRename(handler: (oldName: string, newName: string) => void) {
+    this.renameHandlers.push(handler);
+    return () => {
+      this.renameHandlers = this.renameHandlers.filter((h) => h !== handler);
+    };
+  }
+
+  public onPresence(handler: (user: User) => void) {
+    this.presenceHandlers.push(handler);
+    return () => {
+      this.presenceHandlers = this.presenceHandlers.filter(
+        (h) => h !== handler
+      );
+    };
+  }
+
+  public onMessageEdited(
+    handler: (
+      id: string,
+      content: string,
+      editedAt: string,
+      spans?: Span[]
+    ) => void
+  ) {
+    this.editHandlers.push(handler);
+    return () => {
+      this.editHandlers = this.editHandlers.filter((h) => h !== handler);
+    };
+  }
+
+  public onMessageDeleted(handler: (id: string) => void) {
+    this.deleteHandlers.push(handler);
+    return () => {
+      this.deleteHandlers = this.deleteHandlers.filter((h) => h !== handler);
+    };
+  }
+
+  public onTyping(handler: (username: string, typing: boolean) => void) {
+    this.typingHandlers.push(handler);
+    return () => {
+      this.typingHandlers = this.typingHandlers.filter((h) => h !== handler);
+    };
+  }
+
+  public on(type: string, handler: (data: any) => void) {
+    this.eventHandlers.set(type, [
+      ...(this.eventHandlers.get(type) ?? []),
+      handler,
+    ]);
+    return () => {
+      this.eventHandlers.set(
+        type,
+        (this.eventHandlers.get(type) ?? []).filter((h) => h !== handler)
+      );
+    };
+  }
+
+  public onError(handler: (message: string) => void) {
+    this.errorHandlers.push(handler);
+    return () => {
+      this.errorHandlers = this.errorHandlers.filter((h) => h !== handler);
+    };
+  }
+
   public disconnect() {
+    this.uploadToken = null;
     if (this.socket) {
       this.socket.close();
       this.socket = null;
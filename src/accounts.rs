@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+/// 按用户名保存、跨连接保留的用户数据
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Account {
+    // 房间 -> 最后已读的消息 id
+    #[serde(default)]
+    pub read_positions: BTreeMap<String, String>,
+    // 屏蔽通知的房间，@提及仍会通知
+    #[serde(default)]
+    pub muted_rooms: BTreeSet<String>,
+    // 第一次登录的时间，用于识别新用户；早于该字段的账号从下次登录开始记录
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<DateTime<Utc>>,
+    // 登录令牌的 SHA-256，第一次登录时生成；之后用这个名字登录都需要提供令牌
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_hash: Option<String>,
+    // 稳定的账号 id，改名时随账号迁移。消息按它判断作者，旧名字被别人重新注册后会得到新的 id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// 登录时验证用户名归属的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Login {
+    // 提供了正确的令牌
+    Verified,
+    // 第一次使用这个名字，生成了新令牌，需要告诉客户端
+    Registered(String),
+}
+
+/// 账号数据的变更，经消息总线同步给其他节点
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum AccountChange {
+    // 某个名字下的全部数据，覆盖接收方的记录
+    Put { username: String, account: Account },
+    Rename { old: String, new: String },
+}
+
+/// 用户数据存储，整体保存为一个 JSON 文件
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: Mutex<BTreeMap<String, Account>>,
+}
+
+impl AccountStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let accounts = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    pub fn get(&self, username: &str) -> Account {
+        let accounts = self.accounts.lock().unwrap();
+        accounts.get(username).cloned().unwrap_or_default()
+    }
+
+    /// 修改用户数据，`f` 返回 true 表示有变化，此时写回文件
+    pub fn update(&self, username: &str, f: impl FnOnce(&mut Account) -> bool) -> bool {
+        let mut accounts = self.accounts.lock().unwrap();
+        let changed = f(accounts.entry(username.to_string()).or_default());
+        if changed {
+            self.save(&accounts);
+        }
+        changed
+    }
+
+    /// 验证登录令牌。名字还没有令牌时为其生成一个，已有令牌时必须提供正确的令牌
+    pub fn login(&self, username: &str, token: Option<&str>) -> Result<Login> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(username.to_string()).or_default();
+        match (&account.token_hash, token) {
+            (Some(expected), Some(token)) if constant_time_eq(hash_token(token).as_bytes(), expected.as_bytes()) => {
+                Ok(Login::Verified)
+            }
+            (Some(_), Some(_)) => anyhow::bail!("用户名 {} 的登录令牌不正确", username),
+            (Some(_), None) => anyhow::bail!("用户名 {} 已被注册，请提供登录令牌", username),
+            (None, _) => {
+                let token = new_token();
+                account.token_hash = Some(hash_token(&token));
+                self.save(&accounts);
+                Ok(Login::Registered(token))
+            }
+        }
+    }
+
+    /// 用户的账号 id，还没有时生成一个
+    pub fn account_id(&self, username: &str) -> String {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(username.to_string()).or_default();
+        if let Some(id) = &account.id {
+            return id.clone();
+        }
+        let id = ulid::Ulid::new().to_string();
+        account.id = Some(id.clone());
+        self.save(&accounts);
+        id
+    }
+
+    pub fn exists(&self, username: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(username)
+    }
+
+    /// 名字是否已有人通过令牌认领
+    pub fn is_registered(&self, username: &str) -> bool {
+        let accounts = self.accounts.lock().unwrap();
+        accounts.get(username).is_some_and(|account| account.token_hash.is_some())
+    }
+
+    /// 检查数据文件（尚未创建时检查所在目录）是否可写，供健康检查使用
+    pub fn check(&self) -> Result<()> {
+        let _accounts = self.accounts.lock().map_err(|_| anyhow::anyhow!("用户数据的锁已损坏"))?;
+        let target = if self.path.exists() {
+            self.path.as_path()
+        } else {
+            self.path.parent().unwrap_or(Path::new("."))
+        };
+        if std::fs::metadata(target)?.permissions().readonly() {
+            anyhow::bail!("{} 为只读", target.display());
+        }
+        Ok(())
+    }
+
+    /// 改名时把数据迁移到新名字下，新名字已有数据时保持不变（调用方应先用 `exists` 拒绝这种改名）
+    pub fn rename(&self, old: &str, new: &str) {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(new) {
+            return;
+        }
+        if let Some(account) = accounts.remove(old) {
+            accounts.insert(new.to_string(), account);
+            self.save(&accounts);
+        }
+    }
+
+    /// 应用其他节点同步过来的变更
+    pub fn apply(&self, change: AccountChange) {
+        match change {
+            AccountChange::Put { username, account } => {
+                let mut accounts = self.accounts.lock().unwrap();
+                accounts.insert(username, account);
+                self.save(&accounts);
+            }
+            AccountChange::Rename { old, new } => self.rename(&old, &new),
+        }
+    }
+
+    fn save(&self, accounts: &BTreeMap<String, Account>) {
+        if let Err(e) = crate::storage::save_json(&self.path, accounts) {
+            tracing::error!("保存用户数据失败: {:?}", e);
+        }
+    }
+}
+
+/// 随机生成的令牌，十六进制编码
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// 比较令牌时不因提前返回而泄露匹配的长度
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
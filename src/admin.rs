@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tracing_subscriber::EnvFilter;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use crate::accounts::constant_time_eq;
+use crate::chat::ChatState;
+use crate::telemetry::LogHandle;
+
+// 管理接口请求体的最大字节数
+const ADMIN_BODY_LIMIT: u64 = 64 * 1024;
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// 管理接口，监听单独的端口，所有请求都需要带上 `Authorization: Bearer <令牌>`：
+///
+/// - `GET /connections` 在线连接及其传输方式、地址、RTT 和收发字节数
+/// - `DELETE /connections/<id>?reason=..` 强制断开一个连接
+/// - `GET /rooms` 房间及其在线成员
+/// - `POST /announce` 发送服务器公告，请求体为 `{"message": ..}`
+/// - `GET /log-level`、`PUT /log-level` 查看或修改日志过滤规则，请求体为 `{"filter": "debug"}`
+/// - `GET /metrics` Prometheus 指标
+pub fn routes(
+    chat_state: Arc<ChatState>,
+    log: LogHandle,
+    token: String,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    let expected = format!("Bearer {}", token);
+    let auth = warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let authorized = header.is_some_and(|h| constant_time_eq(h.as_bytes(), expected.as_bytes()));
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one();
+    let state = warp::any().map(move || chat_state.clone());
+    let log = warp::any().map(move || log.clone());
+
+    let connections = warp::path!("connections")
+        .and(warp::get())
+        .and(state.clone())
+        .map(|chat_state: Arc<ChatState>| warp::reply::json(&chat_state.connections()).into_response());
+    let disconnect = warp::path!("connections" / usize)
+        .and(warp::delete())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state.clone())
+        .map(|id, query: HashMap<String, String>, chat_state: Arc<ChatState>| {
+            let reason = query.get("reason").map(|r| r.trim()).filter(|r| !r.is_empty());
+            if chat_state.force_disconnect(id, reason) {
+                warp::reply::json(&serde_json::json!({ "disconnected": id })).into_response()
+            } else {
+                error_reply(StatusCode::NOT_FOUND, format!("连接 {} 不存在", id))
+            }
+        });
+    let rooms = warp::path!("rooms")
+        .and(warp::get())
+        .and(state.clone())
+        .map(|chat_state: Arc<ChatState>| warp::reply::json(&chat_state.rooms()).into_response());
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(state.clone())
+        .map(|chat_state: Arc<ChatState>| {
+            warp::reply::with_header(chat_state.prometheus(), "content-type", "text/plain; version=0.0.4").into_response()
+        });
+    let announce = warp::path!("announce")
+        .and(warp::post())
+        .and(json_body())
+        .and(state)
+        .map(|body: serde_json::Value, chat_state: Arc<ChatState>| {
+            let content = body.get("message").and_then(|m| m.as_str()).unwrap_or_default();
+            match chat_state.announce(content) {
+                Ok(message) => warp::reply::with_status(warp::reply::json(&message), StatusCode::CREATED).into_response(),
+                Err(e) => error_reply(StatusCode::BAD_REQUEST, e),
+            }
+        });
+    let get_log_level = warp::path!("log-level")
+        .and(warp::get())
+        .and(log.clone())
+        .map(|log: LogHandle| match log.with_current(|filter| filter.to_string()) {
+            Ok(filter) => warp::reply::json(&serde_json::json!({ "filter": filter })).into_response(),
+            Err(e) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, e),
+        });
+    let set_log_level = warp::path!("log-level")
+        .and(warp::put())
+        .and(json_body())
+        .and(log)
+        .map(|body: serde_json::Value, log: LogHandle| {
+            let directives = body.get("filter").and_then(|f| f.as_str()).unwrap_or_default();
+            let filter = match EnvFilter::try_new(directives) {
+                Ok(filter) if !directives.trim().is_empty() => filter,
+                Ok(_) => return error_reply(StatusCode::BAD_REQUEST, "需要指定 filter"),
+                Err(e) => return error_reply(StatusCode::BAD_REQUEST, format!("无效的日志过滤规则: {}", e)),
+            };
+            match log.reload(filter) {
+                Ok(()) => {
+                    tracing::info!("日志过滤规则已修改为 {}", directives);
+                    warp::reply::json(&serde_json::json!({ "filter": directives })).into_response()
+                }
+                Err(e) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        });
+
+    auth.and(
+        connections
+            .or(disconnect)
+            .unify()
+            .or(rooms)
+            .unify()
+            .or(announce)
+            .unify()
+            .or(get_log_level)
+            .unify()
+            .or(set_log_level)
+            .unify()
+            .or(metrics)
+            .unify(),
+    )
+    .recover(handle_rejection)
+}
+
+// 请求体按 JSON 解析，不要求 Content-Type，方便直接用 curl -d 调用
+fn json_body() -> impl Filter<Extract = (serde_json::Value,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(ADMIN_BODY_LIMIT)
+        .and(warp::body::bytes())
+        .map(|body: warp::hyper::body::Bytes| serde_json::from_slice(&body).unwrap_or_default())
+}
+
+fn error_reply(status: StatusCode, message: impl std::fmt::Display) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "type": "error", "message": message.to_string() })),
+        status,
+    )
+    .into_response()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Response, Infallible> {
+    let reply = if rejection.find::<Unauthorized>().is_some() {
+        error_reply(StatusCode::UNAUTHORIZED, "需要有效的管理令牌")
+    } else if rejection.is_not_found() {
+        error_reply(StatusCode::NOT_FOUND, "未知的管理接口")
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        error_reply(StatusCode::METHOD_NOT_ALLOWED, "不支持的请求方法")
+    } else {
+        error_reply(StatusCode::BAD_REQUEST, format!("无效的请求: {:?}", rejection))
+    };
+    Ok(reply)
+}
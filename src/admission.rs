@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// 连接准入：限制总连接数和每个 IP 的连接数，QUIC 与 WebSocket 共用
+pub struct Admission {
+    max_total: usize,
+    max_per_ip: usize,
+    counts: Mutex<Counts>,
+}
+
+/// 占用一个连接名额，连接结束时释放
+pub struct Permit {
+    admission: Arc<Admission>,
+    ip: IpAddr,
+}
+
+impl Admission {
+    pub fn new(max_total: usize, max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_total,
+            max_per_ip,
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+
+    pub fn try_admit(self: &Arc<Self>, ip: IpAddr) -> Result<Permit> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total >= self.max_total {
+            anyhow::bail!("服务器连接数已满");
+        }
+        let per_ip = counts.per_ip.entry(ip).or_insert(0);
+        if *per_ip >= self.max_per_ip {
+            anyhow::bail!("来自该地址的连接过多");
+        }
+        *per_ip += 1;
+        counts.total += 1;
+        Ok(Permit {
+            admission: self.clone(),
+            ip,
+        })
+    }
+
+    /// 当前连接数
+    pub fn active(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.admission.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(per_ip) = counts.per_ip.get_mut(&self.ip) {
+            *per_ip -= 1;
+            if *per_ip == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,664 @@
+//! 多个服务器节点之间的消息总线：每个节点把本地产生的消息、事件和在线用户发布出去，
+//! 再把其他节点发布的内容推送给自己的连接。
+//!
+//! 节点通过 `node` 字段识别并丢弃自己发布的内容，避免重复推送或来回转发。
+//! 除了进程内实现外，`RedisBackplane` 使用 Redis 的 PUBLISH/SUBSCRIBE，
+//! 任何兼容 RESP 协议的服务都可以作为中转。
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use crate::accounts::AccountChange;
+use crate::chat::{ChatMessage, User};
+use crate::moderation::ModerationChange;
+
+// 订阅端缓存的消息数，处理不过来时丢弃最旧的
+const SUBSCRIBER_CAPACITY: usize = 1024;
+// 等待发布的消息数，连接断开期间超出的部分直接丢弃
+const PUBLISH_QUEUE_SIZE: usize = 1024;
+// 断线重连的等待时间，每次失败翻倍
+const RECONNECT_MIN: Duration = Duration::from_millis(200);
+const RECONNECT_MAX: Duration = Duration::from_secs(10);
+// 单条 RESP 回复的最大字节数
+const MAX_BULK_BYTES: usize = 16 * 1024 * 1024;
+
+/// 节点之间传递的内容，`node` 为发布者的节点 id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub node: String,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data", rename_all = "camelCase")]
+pub enum Payload {
+    // 消息和话题回复，接收方写入自己的消息记录，只推送普通消息
+    Message(Box<ChatMessage>),
+    // 在线状态、改名、编辑、表情回应等事件
+    Event(serde_json::Value),
+    // 发给某个用户的事件（话题回复、@提及等），由该用户所在的节点推送
+    Direct {
+        username: String,
+        event: serde_json::Value,
+    },
+    // 发布者当前的全部在线用户，同时作为心跳。包括隐身用户，接收方显示时过滤
+    Users(Vec<User>),
+    // 账号数据的变更，各节点的名字归属、账号 id 和已读位置保持一致
+    Account(AccountChange),
+    // 角色、禁言和封禁的变更
+    Moderation(ModerationChange),
+}
+
+/// 节点之间的消息总线。发布不等待送达，订阅者会收到所有节点（包括自己）发布的内容
+pub trait Backplane: Send + Sync {
+    fn publish(&self, envelope: &Envelope);
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope>;
+
+    /// 健康检查使用，返回当前连接状态的说明
+    fn status(&self) -> Result<String>;
+}
+
+/// 进程内的总线，单节点部署时使用，也可以让同一进程内的多个 `ChatState` 互通
+pub struct InProcessBackplane {
+    tx: broadcast::Sender<Envelope>,
+}
+
+impl InProcessBackplane {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(SUBSCRIBER_CAPACITY);
+        Self { tx }
+    }
+}
+
+impl Default for InProcessBackplane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backplane for InProcessBackplane {
+    fn publish(&self, envelope: &Envelope) {
+        // 没有订阅者时发送失败，忽略即可
+        let _ = self.tx.send(envelope.clone());
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.tx.subscribe()
+    }
+
+    fn status(&self) -> Result<String> {
+        Ok("进程内".to_string())
+    }
+}
+
+/// 基于 Redis PUBLISH/SUBSCRIBE 的总线。发布和订阅各用一条连接，断开后自动重连；
+/// 断线期间发布的内容会丢失，在线用户列表由心跳补齐
+pub struct RedisBackplane {
+    publish_tx: mpsc::Sender<String>,
+    tx: broadcast::Sender<Envelope>,
+    server: RedisServer,
+    subscribed: Arc<AtomicBool>,
+}
+
+/// `redis://[:密码@]主机[:端口][/数据库]`，PUBLISH/SUBSCRIBE 与数据库编号无关，忽略
+#[derive(Debug, Clone)]
+struct RedisServer {
+    addr: String,
+    password: Option<String>,
+}
+
+impl RedisServer {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| anyhow::anyhow!("只支持 redis:// 地址: {}", url))?;
+        let rest = rest.split('/').next().unwrap_or_default();
+        let (password, host) = match rest.rsplit_once('@') {
+            Some((userinfo, host)) => {
+                // 用户名部分忽略，只使用密码
+                let password = userinfo.rsplit(':').next().unwrap_or_default();
+                (Some(password.to_string()).filter(|p| !p.is_empty()), host)
+            }
+            None => (None, rest),
+        };
+        if host.is_empty() {
+            anyhow::bail!("Redis 地址缺少主机名: {}", url);
+        }
+        let addr = if host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            host.to_string()
+        } else {
+            format!("{}:6379", host)
+        };
+        Ok(Self { addr, password })
+    }
+
+    async fn connect(&self) -> Result<RespConnection> {
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .with_context(|| format!("无法连接 {}", self.addr))?;
+        stream.set_nodelay(true)?;
+        let mut connection = RespConnection::new(stream);
+        if let Some(password) = &self.password {
+            connection.command(&["AUTH", password]).await?;
+        }
+        Ok(connection)
+    }
+}
+
+impl RedisBackplane {
+    /// 启动发布和订阅任务，`channel` 为所有节点共用的频道名
+    pub fn connect(url: &str, channel: &str) -> Result<Self> {
+        let server = RedisServer::parse(url)?;
+        let (publish_tx, publish_rx) = mpsc::channel(PUBLISH_QUEUE_SIZE);
+        let (tx, _) = broadcast::channel(SUBSCRIBER_CAPACITY);
+        let subscribed = Arc::new(AtomicBool::new(false));
+        tokio::spawn(run_publisher(server.clone(), channel.to_string(), publish_rx));
+        tokio::spawn(run_subscriber(server.clone(), channel.to_string(), tx.clone(), subscribed.clone()));
+        Ok(Self { publish_tx, tx, server, subscribed })
+    }
+}
+
+impl Backplane for RedisBackplane {
+    fn publish(&self, envelope: &Envelope) {
+        let payload = match serde_json::to_string(envelope) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!("无法序列化要发布的内容: {:?}", e);
+                return;
+            }
+        };
+        if self.publish_tx.try_send(payload).is_err() {
+            tracing::warn!("发布队列已满，丢弃一条跨节点消息");
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.tx.subscribe()
+    }
+
+    fn status(&self) -> Result<String> {
+        if !self.subscribed.load(Ordering::Relaxed) {
+            anyhow::bail!("未连接 Redis {}", self.server.addr);
+        }
+        Ok(format!("已订阅 Redis {}", self.server.addr))
+    }
+}
+
+async fn run_publisher(server: RedisServer, channel: String, mut rx: mpsc::Receiver<String>) {
+    let mut backoff = RECONNECT_MIN;
+    // 连接失败时保留当前这一条，重连后重试
+    let mut pending: Option<String> = None;
+    loop {
+        let mut connection = match server.connect().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::warn!("Redis 发布连接失败，{:?} 后重试: {:?}", backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RECONNECT_MAX);
+                continue;
+            }
+        };
+        backoff = RECONNECT_MIN;
+        loop {
+            let payload = match pending.take() {
+                Some(payload) => payload,
+                None => match rx.recv().await {
+                    Some(payload) => payload,
+                    // 总线已释放
+                    None => return,
+                },
+            };
+            if let Err(e) = connection.command(&["PUBLISH", &channel, &payload]).await {
+                tracing::warn!("发布到 Redis 失败，重新连接: {:?}", e);
+                pending = Some(payload);
+                break;
+            }
+        }
+    }
+}
+
+async fn run_subscriber(
+    server: RedisServer,
+    channel: String,
+    tx: broadcast::Sender<Envelope>,
+    subscribed: Arc<AtomicBool>,
+) {
+    let mut backoff = RECONNECT_MIN;
+    loop {
+        let Err(e) = subscribe_once(&server, &channel, &tx, &subscribed).await;
+        // 订阅成功过说明服务可用，重新从最短的等待时间开始
+        if subscribed.swap(false, Ordering::Relaxed) {
+            backoff = RECONNECT_MIN;
+        }
+        tracing::warn!("Redis 订阅连接断开，{:?} 后重连: {:?}", backoff, e);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX);
+    }
+}
+
+// 只在连接出错时返回
+async fn subscribe_once(
+    server: &RedisServer,
+    channel: &str,
+    tx: &broadcast::Sender<Envelope>,
+    subscribed: &AtomicBool,
+) -> Result<std::convert::Infallible> {
+    let mut connection = server.connect().await?;
+    connection.send(&["SUBSCRIBE", channel]).await?;
+    loop {
+        match message_payload(connection.read().await?) {
+            Push::Subscribed => {
+                tracing::info!("已订阅 Redis 频道 {}", channel);
+                subscribed.store(true, Ordering::Relaxed);
+            }
+            Push::Message(payload) => match serde_json::from_slice::<Envelope>(&payload) {
+                Ok(envelope) => {
+                    let _ = tx.send(envelope);
+                }
+                Err(e) => tracing::warn!("忽略无法解析的跨节点消息: {}", e),
+            },
+            Push::Other => {}
+        }
+    }
+}
+
+/// 订阅连接上收到的推送
+enum Push {
+    Subscribed,
+    Message(Vec<u8>),
+    Other,
+}
+
+// 推送格式为 ["subscribe", 频道, 订阅数] 或 ["message", 频道, 内容]
+fn message_payload(frame: Frame) -> Push {
+    let Frame::Array(Some(mut items)) = frame else {
+        return Push::Other;
+    };
+    if items.len() != 3 {
+        return Push::Other;
+    }
+    let kind = match &items[0] {
+        Frame::Bulk(Some(kind)) => kind.to_ascii_lowercase(),
+        Frame::Simple(kind) => kind.to_ascii_lowercase().into_bytes(),
+        _ => return Push::Other,
+    };
+    match (kind.as_slice(), items.pop()) {
+        (b"subscribe", _) => Push::Subscribed,
+        (b"message", Some(Frame::Bulk(Some(payload)))) => Push::Message(payload),
+        _ => Push::Other,
+    }
+}
+
+/// RESP 协议的一个值
+#[derive(Debug)]
+enum Frame {
+    Simple(String),
+    Error(String),
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Frame>>),
+}
+
+struct RespConnection {
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl RespConnection {
+    fn new(stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self {
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    async fn send(&mut self, args: &[&str]) -> Result<()> {
+        write_command(&mut self.writer, args).await
+    }
+
+    async fn read(&mut self) -> Result<Frame> {
+        read_frame(&mut self.reader).await
+    }
+
+    /// 发送命令并等待回复，错误回复转为 `Err`
+    async fn command(&mut self, args: &[&str]) -> Result<Frame> {
+        self.send(args).await?;
+        match self.read().await? {
+            Frame::Error(e) => anyhow::bail!("{} 命令失败: {}", args[0], e),
+            frame => Ok(frame),
+        }
+    }
+}
+
+async fn write_command(writer: &mut (impl AsyncWrite + Unpin), args: &[&str]) -> Result<()> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+type FrameFuture<'a> = Pin<Box<dyn Future<Output = Result<Frame>> + Send + 'a>>;
+
+// 数组可以嵌套，所以返回装箱的 future
+fn read_frame<R>(reader: &mut BufReader<R>) -> FrameFuture<'_>
+where
+    R: tokio::io::AsyncRead + Unpin + Send,
+{
+    Box::pin(async move {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            anyhow::bail!("连接已关闭");
+        }
+        let line = line
+            .strip_suffix("\r\n")
+            .ok_or_else(|| anyhow::anyhow!("RESP 行没有以 CRLF 结尾"))?;
+        let (kind, rest) = line.split_at(line.len().min(1));
+        match kind {
+            "+" => Ok(Frame::Simple(rest.to_string())),
+            "-" => Ok(Frame::Error(rest.to_string())),
+            ":" => {
+                rest.parse::<i64>()?;
+                Ok(Frame::Integer)
+            }
+            "$" => {
+                let len: i64 = rest.parse()?;
+                if len < 0 {
+                    return Ok(Frame::Bulk(None));
+                }
+                let len = len as usize;
+                if len > MAX_BULK_BYTES {
+                    anyhow::bail!("RESP 字符串过长: {} 字节", len);
+                }
+                let mut data = vec![0; len + 2];
+                reader.read_exact(&mut data).await?;
+                data.truncate(len);
+                Ok(Frame::Bulk(Some(data)))
+            }
+            "*" => {
+                let len: i64 = rest.parse()?;
+                if len < 0 {
+                    return Ok(Frame::Array(None));
+                }
+                let mut items = Vec::with_capacity((len as usize).min(16));
+                for _ in 0..len {
+                    items.push(read_frame(reader).await?);
+                }
+                Ok(Frame::Array(Some(items)))
+            }
+            _ => anyhow::bail!("无法识别的 RESP 回复: {:?}", line),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+    use crate::chat::{ChatState, Transport, Traffic};
+    use crate::config::Config;
+
+    // 只支持 AUTH、PUBLISH 和 SUBSCRIBE 的 RESP 替身
+    struct StandIn {
+        addr: String,
+        // 发送后断开所有连接
+        disconnect: broadcast::Sender<()>,
+        subscriptions: Arc<AtomicUsize>,
+    }
+
+    async fn stand_in(password: &'static str) -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (messages, _) = broadcast::channel::<String>(16);
+        let (disconnect, _) = broadcast::channel(1);
+        let subscriptions = Arc::new(AtomicUsize::new(0));
+        let kill = disconnect.clone();
+        let count = subscriptions.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let messages = messages.clone();
+                let count = count.clone();
+                let mut kill = kill.subscribe();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = serve(stream, password, messages, count) => {}
+                        _ = kill.recv() => {}
+                    }
+                });
+            }
+        });
+        StandIn { addr, disconnect, subscriptions }
+    }
+
+    async fn serve(
+        stream: TcpStream,
+        password: &str,
+        messages: broadcast::Sender<String>,
+        subscriptions: Arc<AtomicUsize>,
+    ) -> Result<()> {
+        let mut connection = RespConnection::new(stream);
+        let mut authenticated = false;
+        loop {
+            let Frame::Array(Some(items)) = connection.read().await? else {
+                anyhow::bail!("命令必须是数组");
+            };
+            let args: Vec<String> = items
+                .into_iter()
+                .map(|item| match item {
+                    Frame::Bulk(Some(arg)) => String::from_utf8(arg).unwrap(),
+                    other => panic!("命令参数必须是字符串: {:?}", other),
+                })
+                .collect();
+            let reply: &[u8] = match args[0].as_str() {
+                "AUTH" if args[1] == password => {
+                    authenticated = true;
+                    b"+OK\r\n"
+                }
+                "AUTH" => b"-WRONGPASS invalid password\r\n",
+                _ if !authenticated => b"-NOAUTH Authentication required\r\n",
+                "PUBLISH" => {
+                    let _ = messages.send(args[2].clone());
+                    b":1\r\n"
+                }
+                "SUBSCRIBE" => {
+                    let mut rx = messages.subscribe();
+                    write_command(&mut connection.writer, &["subscribe", &args[1], "1"]).await?;
+                    subscriptions.fetch_add(1, Ordering::Relaxed);
+                    loop {
+                        let payload = rx.recv().await?;
+                        write_command(&mut connection.writer, &["message", &args[1], &payload]).await?;
+                    }
+                }
+                _ => b"-ERR unknown command\r\n",
+            };
+            connection.writer.write_all(reply).await?;
+        }
+    }
+
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("等待超时");
+    }
+
+    fn envelope(node: &str, text: &str) -> Envelope {
+        Envelope {
+            node: node.to_string(),
+            payload: Payload::Event(serde_json::json!({ "type": "test", "text": text })),
+        }
+    }
+
+    async fn next_text(rx: &mut broadcast::Receiver<Envelope>) -> String {
+        let envelope = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        let Payload::Event(event) = envelope.payload else {
+            panic!("应当收到事件");
+        };
+        event["text"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_redis_urls() {
+        let server = RedisServer::parse("redis://:secret@example.com/2").unwrap();
+        assert_eq!(server.addr, "example.com:6379");
+        assert_eq!(server.password.as_deref(), Some("secret"));
+        let server = RedisServer::parse("redis://user:pw@10.0.0.1:6380").unwrap();
+        assert_eq!(server.addr, "10.0.0.1:6380");
+        assert_eq!(server.password.as_deref(), Some("pw"));
+        let server = RedisServer::parse("redis://localhost").unwrap();
+        assert_eq!(server.addr, "localhost:6379");
+        assert!(server.password.is_none());
+        assert!(RedisServer::parse("http://localhost").is_err());
+        assert!(RedisServer::parse("redis://").is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_resp_frames() {
+        let mut reader = BufReader::new(&b"*3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$4\r\na\r\nb\r\n"[..]);
+        match message_payload(read_frame(&mut reader).await.unwrap()) {
+            Push::Message(payload) => assert_eq!(payload, b"a\r\nb"),
+            _ => panic!("应当解析为消息"),
+        }
+        let mut reader = BufReader::new(&b"*2\r\n*2\r\n:1\r\n$-1\r\n+OK\r\n-ERR x\r\n"[..]);
+        let frame = read_frame(&mut reader).await.unwrap();
+        let Frame::Array(Some(items)) = frame else {
+            panic!("应当是数组: {:?}", frame);
+        };
+        assert!(matches!(&items[0], Frame::Array(Some(inner)) if matches!(inner[..], [Frame::Integer, Frame::Bulk(None)])));
+        assert!(matches!(&items[1], Frame::Simple(s) if s == "OK"));
+        assert!(matches!(read_frame(&mut reader).await.unwrap(), Frame::Error(e) if e == "ERR x"));
+        // 连接关闭、缺少 CRLF、未知类型和超长字符串都是错误
+        assert!(read_frame(&mut reader).await.is_err());
+        for input in [&b"+OK\n"[..], b"?1\r\n", b"$99999999999\r\n", b"$3\r\nab"] {
+            assert!(read_frame(&mut BufReader::new(input)).await.is_err(), "{:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_backplane_round_trip_and_reconnect() {
+        let server = stand_in("secret").await;
+        let backplane = RedisBackplane::connect(&format!("redis://:secret@{}", server.addr), "chat").unwrap();
+        let mut rx = backplane.subscribe();
+        wait_until(|| backplane.status().is_ok()).await;
+        backplane.publish(&envelope("a", "一"));
+        assert_eq!(next_text(&mut rx).await, "一");
+
+        // 服务端断开所有连接后，订阅和发布都会重连，断线时正在发布的内容重试
+        let _ = server.disconnect.send(());
+        wait_until(|| server.subscriptions.load(Ordering::Relaxed) == 2).await;
+        wait_until(|| backplane.status().is_ok()).await;
+        backplane.publish(&envelope("a", "二"));
+        assert_eq!(next_text(&mut rx).await, "二");
+    }
+
+    #[tokio::test]
+    async fn redis_backplane_rejected_password() {
+        let server = stand_in("secret").await;
+        let backplane = RedisBackplane::connect(&format!("redis://:wrong@{}", server.addr), "chat").unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(backplane.status().is_err());
+        assert_eq!(server.subscriptions.load(Ordering::Relaxed), 0);
+    }
+
+    fn node(backplane: &Arc<InProcessBackplane>, id: &str, dir: &std::path::Path) -> Arc<ChatState> {
+        let mut config = Config::from_env();
+        config.node_id = id.to_string();
+        config.data_dir = dir.join(id);
+        let data = &config.data_dir;
+        let state = ChatState::new(
+            config.clone(),
+            crate::history::HistoryStore::open(data.join("history.jsonl")).unwrap(),
+            crate::accounts::AccountStore::open(data.join("accounts.json")).unwrap(),
+            crate::blobs::BlobStore::open(data.join("blobs"), config.max_upload_size, 1).unwrap(),
+            crate::moderation::ModerationStore::open(
+                data.join("moderation.json"),
+                data.join("audit.jsonl"),
+                Default::default(),
+            )
+            .unwrap(),
+            crate::filters::FilterChain::new(&config.filter_rules, crate::filters::default_filters()),
+            None,
+        )
+        .with_backplane(backplane.clone());
+        let state = Arc::new(state);
+        let runner = state.clone();
+        tokio::spawn(async move { runner.run_backplane().await });
+        state
+    }
+
+    #[tokio::test]
+    async fn two_nodes_share_an_in_process_backplane() {
+        let dir = std::env::temp_dir().join(format!("quic_chat_backplane_{}", ulid::Ulid::new()));
+        let backplane = Arc::new(InProcessBackplane::new());
+        let a = node(&backplane, "a", &dir);
+        let b = node(&backplane, "b", &dir);
+        let mut a_rx = a.subscribe();
+        let mut b_rx = b.subscribe();
+        // 让两个节点的总线任务先订阅
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // bob 连接在 b 上，a 从心跳中得知
+        let (bob, bob_queue) = b.register_client(Transport::WebSocket {
+            addr: None,
+            traffic: Arc::new(Traffic::default()),
+        });
+        b.add_user("bob".to_string(), bob, None).unwrap();
+        b.broadcast_user_list();
+        wait_until(|| a.get_users().iter().any(|u| u.username == "bob")).await;
+
+        // 每个节点只推送一次，自己发布的内容不会再从总线收到
+        let root = ChatMessage::new("alice".to_string(), "你好".to_string());
+        a.broadcast_message(root.clone());
+        for rx in [&mut a_rx, &mut b_rx] {
+            let message = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap();
+            assert_eq!(message.id, root.id);
+            assert!(tokio::time::timeout(Duration::from_millis(100), rx.recv()).await.is_err());
+        }
+
+        // 回复写入其他节点的消息记录，@提及送到 bob 所在的节点
+        a.post_reply("alice", &root.id, "@bob 看这里", false).unwrap();
+        wait_until(|| b.get_thread(&root.id).is_ok_and(|(_, replies)| replies.len() == 1)).await;
+        let (parent, _) = b.get_thread(&root.id).unwrap();
+        assert_eq!(parent.reply_count, 1);
+        let mention = loop {
+            let line = tokio::time::timeout(Duration::from_secs(1), bob_queue.pop()).await.unwrap().unwrap();
+            let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+            if event["type"] == "mention" {
+                break event;
+            }
+        };
+        assert_eq!(mention["message"]["content"], "@bob 看这里");
+
+        // 其他节点发来的删除按本节点的记录检查权限，mallory 不是作者也不是管理员
+        backplane.publish(&Envelope {
+            node: "x".to_string(),
+            payload: Payload::Event(serde_json::json!({
+                "type": "messageDeleted",
+                "id": root.id,
+                "by": "mallory",
+            })),
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(b.get_thread(&root.id).is_ok());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
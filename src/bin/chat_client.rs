@@ -1,9 +1,11 @@
 // src/bin/chat_client.rs
 use anyhow::{Context, Result};
 use quinn::{ClientConfig, Endpoint};
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::io::{BufReader, AsyncBufReadExt, AsyncWriteExt};
-use tokio::io::AsyncReadExt;
+use tokio::io::{BufReader, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
 use serde_json::Value;
 
 #[tokio::main]
@@ -11,98 +13,636 @@ async fn main() -> Result<()> {
     let server_addr: SocketAddr = "127.0.0.1:4433".parse()?;
     let endpoint = create_client_endpoint("0.0.0.0:0")?;
     
-    println!("正在连接到服务器 {}...", server_addr);
-    let connection = endpoint
-        .connect(server_addr, "localhost")?
-        .await
-        .context("连接失败")?;
-    
-    println!("连接成功！请输入你的用户名：");
+    println!("请输入你的用户名：");
     let mut username = String::new();
     let mut stdin = BufReader::new(tokio::io::stdin());
     stdin.read_line(&mut username).await?;
-    let username = username.trim();
-    
-    // 打开双向流
-    let (mut send, mut recv) = connection.open_bi().await?;
-    
-    // 发送用户名
-    send.write_all(username.as_bytes()).await?;
-    send.flush().await?;
-    
-    println!("已加入聊天室！输入消息开始聊天，输入 'quit' 退出。");
+    let mut username = username.trim().to_string();
     
-    // 启动接收消息任务
-    let _recv_task = tokio::spawn(async move {
-        let mut buf = [0u8; 1024];
-        let mut message_buffer = String::new();
-        
+    // 标准输入单独读取，断线重连期间输入的消息会在重连后发出
+    let (input_tx, mut input_rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        let mut input = String::new();
         loop {
-            match recv.read(&mut buf).await {
-                Ok(Some(n)) if n > 0 => {
-                    let msg = String::from_utf8_lossy(&buf[..n]);
-                    message_buffer.push_str(&msg);
-                    
-                    // 处理可能的多行消息
-                    while let Some(pos) = message_buffer.find('\n') {
-                        let line = message_buffer[..pos].trim().to_string();
-                        message_buffer = message_buffer[pos + 1..].to_string();
-                        
-                        if !line.is_empty() {
-                            if let Ok(json) = serde_json::from_str::<Value>(&line) {
-                                if let (Some(username), Some(content)) = (
-                                    json.get("username").and_then(|u| u.as_str()),
-                                    json.get("content").and_then(|c| c.as_str()),
-                                ) {
-                                    println!("{}: {}", username, content);
-                                }
-                            } else {
-                                println!("{}", line);
-                            }
-                        }
+            input.clear();
+            match stdin.read_line(&mut input).await {
+                Ok(n) if n > 0 => {
+                    let input = input.trim();
+                    if input == "quit" || input_tx.send(input.to_string()).is_err() {
+                        break;
                     }
                 }
-                Ok(Some(0)) | Ok(None) => {
-                    println!("连接已关闭");
-                    break;
-                }
-                Err(e) => {
-                    println!("接收消息错误: {}", e);
-                    break;
-                }
-                _ => continue,
+                _ => break,
             }
         }
-        Ok::<_, anyhow::Error>(())
     });
     
-    // 处理用户输入
-    let mut input = String::new();
+    println!("输入消息开始聊天，输入 'quit' 退出。");
+    println!("命令: /nick 新名字, /status online|away|busy|invisible [说明]");
+    println!("      /edit 消息ID 新内容, /delete 消息ID");
+    println!("      /reply 消息ID 内容, /thread 消息ID");
+    println!("      /react 消息ID 表情, /unreact 消息ID 表情");
+    println!("      /read 消息ID, /rooms, /mute, /unmute");
+    println!("      /upload 文件路径 [说明], /download 文件ID [保存路径]");
+    println!("管理: /kick 用户 [原因], /silence 用户 秒数 [原因], /unsilence 用户");
+    println!("      /ban 用户或IP [秒数] [原因], /unban 用户或IP, /role 用户 guest|member|moderator");
+    println!("      /held, /approve 审核ID, /discard 审核ID");
+    
+    // 登录令牌：第一次使用某个名字时由服务器生成，之后用这个名字登录都需要提供
+    let mut token: Option<String> = std::env::var("CHAT_TOKEN").ok().filter(|t| !t.is_empty());
+    let mut session: Option<String> = None;
+    let mut seen = SeenIds::default();
     loop {
-        input.clear();
-        stdin.read_line(&mut input).await?;
-        let input = input.trim();
+        println!("正在连接到服务器 {}...", server_addr);
+        let connection = match endpoint.connect(server_addr, "localhost")?.await {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("连接失败: {}，{} 秒后重试", e, RECONNECT_DELAY.as_secs());
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
         
-        if input == "quit" {
-            println!("正在退出...");
-            break;
+        // 打开双向流，带上会话 id 以便服务器补发断线期间的消息
+        let login = serde_json::json!({
+            "username": username,
+            "token": token,
+            "session": session,
+        });
+        let opened = async {
+            let (mut send, recv) = connection.open_bi().await?;
+            send.write_all(format!("{}\n", login).as_bytes()).await?;
+            send.flush().await?;
+            Ok::<_, anyhow::Error>((send, recv))
         }
+        .await
+        .context("加入聊天室失败");
+        let (mut send, recv) = match opened {
+            Ok(streams) => streams,
+            Err(e) => {
+                println!("{:#}，{} 秒后重试", e, RECONNECT_DELAY.as_secs());
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        println!("已加入聊天室！");
         
-        if !input.is_empty() {
-            if let Err(e) = send.write_all(input.as_bytes()).await {
-                println!("发送消息失败: {}", e);
-                break;
+        // 输入提示通过 datagram 接收
+        let datagram_conn = connection.clone();
+        let typing_task = tokio::spawn(async move {
+            while let Ok(datagram) = datagram_conn.read_datagram().await {
+                if let Ok(json) = serde_json::from_slice::<Value>(&datagram) {
+                    print_event(&json);
+                }
             }
-            if let Err(e) = send.flush().await {
-                println!("发送消息失败: {}", e);
-                break;
+        });
+        
+        let mut lines = BufReader::new(recv).lines();
+        let quit = loop {
+            tokio::select! {
+                input = input_rx.recv() => {
+                    let Some(input) = input else { break true };
+                    if input.is_empty() {
+                        continue;
+                    }
+                    // 文件传输各自使用单独的流，不阻塞聊天
+                    if let Some(args) = input.strip_prefix("/upload ") {
+                        let connection = connection.clone();
+                        let args = args.trim().to_string();
+                        tokio::spawn(async move {
+                            if let Err(e) = upload_file(&connection, &args).await {
+                                println!("上传失败: {:#}", e);
+                            }
+                        });
+                        continue;
+                    }
+                    if let Some(args) = input.strip_prefix("/download ") {
+                        let connection = connection.clone();
+                        let args = args.trim().to_string();
+                        tokio::spawn(async move {
+                            if let Err(e) = download_file(&connection, &args).await {
+                                println!("下载失败: {:#}", e);
+                            }
+                        });
+                        continue;
+                    }
+                    let line = parse_input(&input);
+                    if let Err(e) = send.write_all(format!("{}\n", line).as_bytes()).await {
+                        println!("发送消息失败: {}", e);
+                        break false;
+                    }
+                }
+                line = lines.next_line() => {
+                    let line = match line {
+                        Ok(Some(line)) => line,
+                        Ok(None) => {
+                            println!("连接已关闭");
+                            break false;
+                        }
+                        Err(e) => {
+                            // 服务器拒绝连接（如连接数超限）时会附带原因
+                            match connection.close_reason() {
+                                Some(quinn::ConnectionError::ApplicationClosed(close)) if !close.reason.is_empty() => {
+                                    println!("连接被服务器关闭: {}", String::from_utf8_lossy(&close.reason));
+                                }
+                                _ => println!("接收消息错误: {}", e),
+                            }
+                            break false;
+                        }
+                    };
+                    let Ok(json) = serde_json::from_str::<Value>(&line) else {
+                        println!("{}", line);
+                        continue;
+                    };
+                    match json.get("type").and_then(|t| t.as_str()) {
+                        Some("session") => {
+                            session = json.get("id").and_then(|i| i.as_str()).map(|s| s.to_string());
+                        }
+                        Some("credentials") => {
+                            token = json.get("token").and_then(|t| t.as_str()).map(|t| t.to_string());
+                            if let Some(token) = &token {
+                                println!("[系统] 已为 {} 生成登录令牌，下次以这个名字登录时请设置 CHAT_TOKEN={}", username, token);
+                            }
+                            continue;
+                        }
+                        Some("userRenamed") if json.get("oldUsername").and_then(|n| n.as_str()) == Some(username.as_str()) => {
+                            if let Some(new_name) = json.get("newUsername").and_then(|n| n.as_str()) {
+                                username = new_name.to_string();
+                            }
+                        }
+                        // 被踢出或封禁后不再自动重连
+                        Some("kicked" | "banned") => {
+                            print_event(&json);
+                            break true;
+                        }
+                        // 聊天消息需要确认，重复收到的只确认不显示
+                        None => {
+                            if let Some(id) = json.get("id").and_then(|i| i.as_str()) {
+                                if seen.insert(id) {
+                                    print_event(&json);
+                                }
+                                let ack = serde_json::json!({ "type": "ack", "id": id });
+                                let _ = send.write_all(format!("{}\n", ack).as_bytes()).await;
+                                continue;
+                            }
+                        }
+                        _ => {}
+                    }
+                    print_event(&json);
+                }
             }
+        };
+        typing_task.abort();
+        
+        if quit {
+            println!("正在退出...");
+            connection.close(0u32.into(), b"quit");
+            break;
         }
+        println!("{} 秒后重连...", RECONNECT_DELAY.as_secs());
+        tokio::time::sleep(RECONNECT_DELAY).await;
     }
     
+    endpoint.wait_idle().await;
     Ok(())
 }
 
+// 断线后的重连间隔
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+// 用于去重的最近消息 id 数量
+const SEEN_CAPACITY: usize = 1000;
+
+/// 最近收到的消息 id，用于丢弃重连后补发的重复消息
+#[derive(Default)]
+struct SeenIds {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl SeenIds {
+    /// 第一次见到该 id 时返回 true
+    fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// /upload 文件路径 [说明]：在单向流上发送 JSON 头和文件内容
+async fn upload_file(connection: &quinn::Connection, args: &str) -> Result<()> {
+    let mut parts = args.splitn(2, ' ');
+    let path = std::path::Path::new(parts.next().unwrap_or_default());
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("无法读取 {}", path.display()))?;
+    let header = serde_json::json!({
+        "name": path.file_name().and_then(|n| n.to_str()).unwrap_or("file"),
+        "size": data.len(),
+        "content": parts.next().unwrap_or(""),
+    });
+    let mut send = connection.open_uni().await?;
+    send.write_all(format!("{}\n", header).as_bytes()).await?;
+    send.write_all(&data).await?;
+    send.finish().await?;
+    println!("[已上传 {} ({} 字节)]", path.display(), data.len());
+    Ok(())
+}
+
+/// /download 文件ID [保存路径]：在新的双向流上请求文件
+async fn download_file(connection: &quinn::Connection, args: &str) -> Result<()> {
+    let mut parts = args.splitn(2, ' ');
+    let id = parts.next().unwrap_or_default();
+    let (mut send, recv) = connection.open_bi().await?;
+    let request = serde_json::json!({ "type": "download", "id": id });
+    send.write_all(format!("{}\n", request).as_bytes()).await?;
+    send.finish().await?;
+
+    let mut reader = BufReader::new(recv);
+    let mut header = String::new();
+    reader.read_line(&mut header).await?;
+    let header: Value = serde_json::from_str(&header).context("无效的响应")?;
+    if header.get("type").and_then(|t| t.as_str()) != Some("file") {
+        let message = header.get("message").and_then(|m| m.as_str()).unwrap_or("未知错误");
+        anyhow::bail!("{}", message);
+    }
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+
+    // 未指定保存路径时用文件 id 的前缀加上类型对应的扩展名
+    let content_type = header.get("contentType").and_then(|t| t.as_str()).unwrap_or_default();
+    let path = match parts.next().map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        Some(path) => path.to_string(),
+        None => format!("{}.{}", &id[..id.len().min(12)], extension_for(content_type)),
+    };
+    tokio::fs::write(&path, &data).await?;
+    println!("[已下载 {} 字节到 {}]", data.len(), path);
+    Ok(())
+}
+
+fn extension_for(content_type: &str) -> &'static str {
+    match content_type {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" => "gz",
+        t if t.starts_with("text/plain") => "txt",
+        _ => "bin",
+    }
+}
+
+/// 把输入转换为发给服务器的一行：命令转换为 JSON，其余作为普通消息
+fn parse_input(input: &str) -> String {
+    if let Some(new_name) = input.strip_prefix("/nick ") {
+        serde_json::json!({
+            "type": "rename",
+            "username": new_name.trim(),
+        }).to_string()
+    } else if let Some(args) = input.strip_prefix("/edit ") {
+        // /edit 消息ID 新内容
+        let mut parts = args.trim().splitn(2, ' ');
+        serde_json::json!({
+            "type": "edit",
+            "id": parts.next().unwrap_or(""),
+            "content": parts.next().unwrap_or(""),
+        }).to_string()
+    } else if let Some(args) = input.strip_prefix("/reply ") {
+        // /reply 消息ID 内容
+        let mut parts = args.trim().splitn(2, ' ');
+        serde_json::json!({
+            "type": "reply",
+            "parentId": parts.next().unwrap_or(""),
+            "content": parts.next().unwrap_or(""),
+        }).to_string()
+    } else if let Some((kind, args)) = input
+        .strip_prefix("/react ")
+        .map(|args| ("react", args))
+        .or_else(|| input.strip_prefix("/unreact ").map(|args| ("unreact", args)))
+    {
+        // /react 消息ID 表情
+        let mut parts = args.trim().splitn(2, ' ');
+        serde_json::json!({
+            "type": kind,
+            "id": parts.next().unwrap_or(""),
+            "emoji": parts.next().unwrap_or("").trim(),
+        }).to_string()
+    } else if let Some(id) = input.strip_prefix("/read ") {
+        serde_json::json!({
+            "type": "read",
+            "id": id.trim(),
+        }).to_string()
+    } else if input == "/mute" || input == "/unmute" {
+        serde_json::json!({
+            "type": &input[1..],
+            "room": "lobby",
+        }).to_string()
+    } else if input == "/rooms" {
+        serde_json::json!({ "type": "getRooms" }).to_string()
+    } else if let Some(id) = input.strip_prefix("/thread ") {
+        serde_json::json!({
+            "type": "getThread",
+            "id": id.trim(),
+        }).to_string()
+    } else if let Some(id) = input.strip_prefix("/delete ") {
+        serde_json::json!({
+            "type": "delete",
+            "id": id.trim(),
+        }).to_string()
+    } else if let Some(args) = input.strip_prefix("/kick ") {
+        // /kick 用户 原因
+        let mut parts = args.trim().splitn(2, ' ');
+        serde_json::json!({
+            "type": "kick",
+            "username": parts.next().unwrap_or(""),
+            "reason": parts.next(),
+        }).to_string()
+    } else if let Some(args) = input.strip_prefix("/silence ") {
+        // /silence 用户 秒数 原因
+        let mut parts = args.trim().splitn(3, ' ');
+        serde_json::json!({
+            "type": "muteUser",
+            "username": parts.next().unwrap_or(""),
+            "duration": parts.next().and_then(|d| d.parse::<u64>().ok()).unwrap_or(0),
+            "reason": parts.next(),
+        }).to_string()
+    } else if let Some(target) = input.strip_prefix("/unsilence ") {
+        serde_json::json!({
+            "type": "unmuteUser",
+            "username": target.trim(),
+        }).to_string()
+    } else if let Some((kind, args)) = input
+        .strip_prefix("/ban ")
+        .map(|args| ("ban", args))
+        .or_else(|| input.strip_prefix("/unban ").map(|args| ("unban", args)))
+    {
+        // /ban 用户或IP [秒数] [原因]，不填秒数为永久
+        let mut parts = args.trim().splitn(2, ' ');
+        let target = parts.next().unwrap_or("");
+        let rest = parts.next().unwrap_or("").trim();
+        let (duration, reason) = match rest.split_once(' ').unwrap_or((rest, "")) {
+            (secs, reason) if secs.parse::<u64>().is_ok() => (secs.parse::<u64>().ok(), reason),
+            _ => (None, rest),
+        };
+        let mut command = serde_json::json!({ "type": kind });
+        let key = if target.parse::<std::net::IpAddr>().is_ok() { "ip" } else { "username" };
+        command[key] = target.into();
+        if let Some(duration) = duration {
+            command["duration"] = duration.into();
+        }
+        if !reason.is_empty() {
+            command["reason"] = reason.into();
+        }
+        command.to_string()
+    } else if input == "/held" {
+        serde_json::json!({ "type": "getHeld" }).to_string()
+    } else if let Some((kind, id)) = input
+        .strip_prefix("/approve ")
+        .map(|id| ("approve", id))
+        .or_else(|| input.strip_prefix("/discard ").map(|id| ("discardHeld", id)))
+    {
+        serde_json::json!({
+            "type": kind,
+            "id": id.trim(),
+        }).to_string()
+    } else if let Some(args) = input.strip_prefix("/role ") {
+        // /role 用户 角色
+        let mut parts = args.trim().splitn(2, ' ');
+        serde_json::json!({
+            "type": "setRole",
+            "username": parts.next().unwrap_or(""),
+            "role": parts.next().unwrap_or("").trim(),
+        }).to_string()
+    } else if let Some(args) = input.strip_prefix("/status ") {
+        // /status away 开会中
+        let mut parts = args.trim().splitn(2, ' ');
+        serde_json::json!({
+            "type": "status",
+            "status": parts.next().unwrap_or("online"),
+            "text": parts.next().unwrap_or(""),
+        }).to_string()
+    } else {
+        input.to_string()
+    }
+}
+
+fn print_held(held: &Value) {
+    let field = |key| held.get(key).and_then(|v| v.as_str()).unwrap_or("");
+    println!("#{} {}: {}（{}）", field("id"), field("username"), field("content"), field("reason"));
+}
+
+fn print_event(json: &Value) {
+    match json.get("type").and_then(|t| t.as_str()) {
+        Some("error") => {
+            if let Some(message) = json.get("message").and_then(|m| m.as_str()) {
+                println!("[错误] {}", message);
+            }
+        }
+        Some("kicked" | "banned") => {
+            if let Some(message) = json.get("message").and_then(|m| m.as_str()) {
+                println!("[管理] {}", message);
+            }
+        }
+        Some("shutdown") => {
+            if let Some(message) = json.get("message").and_then(|m| m.as_str()) {
+                println!("[服务器] {}", message);
+            }
+        }
+        Some("messageHeld") => {
+            if let Some(held) = json.get("message") {
+                print!("[待审核] ");
+                print_held(held);
+            }
+        }
+        Some("heldMessages") => {
+            let messages = json.get("messages").and_then(|m| m.as_array());
+            if messages.is_none_or(|m| m.is_empty()) {
+                println!("[待审核] 没有待审核的消息");
+            }
+            for held in messages.into_iter().flatten() {
+                print!("[待审核] ");
+                print_held(held);
+            }
+        }
+        Some("heldReviewed") => {
+            if let (Some(id), Some(by)) = (
+                json.get("id").and_then(|i| i.as_str()),
+                json.get("by").and_then(|b| b.as_str()),
+            ) {
+                let result = if json.get("approved").and_then(|a| a.as_bool()) == Some(true) { "通过" } else { "丢弃" };
+                println!("[审核] {} 已{} #{}", by, result, id);
+            }
+        }
+        Some("rateLimited") => {
+            let message = json.get("message").and_then(|m| m.as_str()).unwrap_or("操作过于频繁");
+            let retry_after = json.get("retryAfterMs").and_then(|r| r.as_u64()).unwrap_or(0);
+            println!("[限流] {}（{:.1} 秒后可重试）", message, retry_after as f64 / 1000.0);
+        }
+        Some("userList") => {
+            if let Some(users) = json.get("users").and_then(|u| u.as_array()) {
+                let names: Vec<&str> = users
+                    .iter()
+                    .filter_map(|u| u.get("username").and_then(|n| n.as_str()))
+                    .collect();
+                println!("[在线用户] {}", names.join(", "));
+            }
+        }
+        Some("presence") => {
+            if let Some(user) = json.get("user") {
+                let name = user.get("username").and_then(|n| n.as_str()).unwrap_or("");
+                let status = user.get("status").and_then(|s| s.as_str()).unwrap_or("");
+                match user.get("status_text").and_then(|t| t.as_str()) {
+                    Some(text) => println!("[状态] {} -> {} ({})", name, status, text),
+                    None => println!("[状态] {} -> {}", name, status),
+                }
+            }
+        }
+        Some("typing") => {
+            if json.get("typing").and_then(|t| t.as_bool()) == Some(true) {
+                if let Some(name) = json.get("username").and_then(|n| n.as_str()) {
+                    println!("[{} 正在输入...]", name);
+                }
+            }
+        }
+        Some("threadReply") => {
+            if let Some(message) = json.get("message") {
+                let parent_id = message.get("parent_id").and_then(|p| p.as_str()).unwrap_or("");
+                print!("[话题 #{}] ", parent_id);
+                print_event(message);
+            }
+        }
+        Some("threadUpdated") => {
+            if let (Some(id), Some(count)) = (
+                json.get("id").and_then(|i| i.as_str()),
+                json.get("replyCount").and_then(|c| c.as_u64()),
+            ) {
+                println!("[话题 #{} 共 {} 条回复]", id, count);
+            }
+        }
+        Some("thread") => {
+            if let Some(parent) = json.get("parent") {
+                print!("[话题] ");
+                print_event(parent);
+            }
+            for reply in json.get("replies").and_then(|r| r.as_array()).into_iter().flatten() {
+                print!("  └ ");
+                print_event(reply);
+            }
+        }
+        Some(kind @ ("reactionAdded" | "reactionRemoved")) => {
+            if let (Some(id), Some(emoji), Some(name), Some(count)) = (
+                json.get("id").and_then(|i| i.as_str()),
+                json.get("emoji").and_then(|e| e.as_str()),
+                json.get("username").and_then(|u| u.as_str()),
+                json.get("count").and_then(|c| c.as_u64()),
+            ) {
+                let action = if kind == "reactionAdded" { "添加了" } else { "取消了" };
+                println!("[#{}] {} {} {} (共 {})", id, name, action, emoji, count);
+            }
+        }
+        Some("missedMessages") => {
+            if let Some(count) = json.get("count").and_then(|c| c.as_u64()) {
+                println!("[接收过慢，漏掉了 {} 条消息，正在补发]", count);
+            }
+        }
+        Some("roomList") => {
+            for room in json.get("rooms").and_then(|r| r.as_array()).into_iter().flatten() {
+                if let (Some(id), Some(unread)) = (
+                    room.get("id").and_then(|i| i.as_str()),
+                    room.get("unread").and_then(|u| u.as_u64()),
+                ) {
+                    let muted = room.get("muted").and_then(|m| m.as_bool()) == Some(true);
+                    let role = match room.get("role").and_then(|r| r.as_str()) {
+                        Some("owner") => "（所有者）",
+                        Some("moderator") => "（管理员）",
+                        Some("guest") => "（访客）",
+                        _ => "",
+                    };
+                    println!("[房间 {}] 未读 {} 条{}{}", id, unread, if muted { "（已屏蔽）" } else { "" }, role);
+                }
+            }
+        }
+        Some("readReceipt") => {
+            if let (Some(name), Some(id)) = (
+                json.get("username").and_then(|u| u.as_str()),
+                json.get("id").and_then(|i| i.as_str()),
+            ) {
+                println!("[{} 已读到 #{}]", name, id);
+            }
+        }
+        Some("messageEdited") => {
+            if let (Some(id), Some(content)) = (
+                json.get("id").and_then(|i| i.as_str()),
+                json.get("content").and_then(|c| c.as_str()),
+            ) {
+                println!("[已编辑 #{}] {}", id, content);
+            }
+        }
+        Some("mention") => {
+            if let Some(message) = json.get("message") {
+                print!("\x07[@提及] ");
+                print_event(message);
+            }
+        }
+        Some("messageEnriched") => {
+            for preview in json.get("previews").and_then(|p| p.as_array()).into_iter().flatten() {
+                let url = preview.get("url").and_then(|u| u.as_str()).unwrap_or("");
+                let title = preview.get("title").and_then(|t| t.as_str()).unwrap_or(url);
+                println!("  [链接] {}", title);
+                if let Some(description) = preview.get("description").and_then(|d| d.as_str()) {
+                    println!("         {}", description);
+                }
+            }
+        }
+        Some("messageDeleted") => {
+            if let Some(id) = json.get("id").and_then(|i| i.as_str()) {
+                println!("[已删除 #{}]", id);
+            }
+        }
+        Some(_) => {}
+        None => {
+            if let (Some(username), Some(content)) = (
+                json.get("username").and_then(|u| u.as_str()),
+                json.get("content").and_then(|c| c.as_str()),
+            ) {
+                if let Some(quote) = json.get("quote").and_then(|q| q.as_str()) {
+                    println!("> {}", quote);
+                }
+                match json.get("id").and_then(|i| i.as_str()) {
+                    Some(id) => println!("{}: {}  (#{})", username, content, id),
+                    None => println!("{}: {}", username, content),
+                }
+                if let Some(attachment) = json.get("attachment") {
+                    if let (Some(id), Some(name), Some(size)) = (
+                        attachment.get("id").and_then(|i| i.as_str()),
+                        attachment.get("name").and_then(|n| n.as_str()),
+                        attachment.get("size").and_then(|s| s.as_u64()),
+                    ) {
+                        match (
+                            attachment.get("width").and_then(|w| w.as_u64()),
+                            attachment.get("height").and_then(|h| h.as_u64()),
+                        ) {
+                            (Some(width), Some(height)) => println!(
+                                "  [图片] {} ({}x{}, {} 字节)  /download {}",
+                                name, width, height, size, id
+                            ),
+                            _ => println!("  [附件] {} ({} 字节)  /download {}", name, size, id),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 fn create_client_endpoint(bind_addr: &str) -> Result<Endpoint> {
     let client_cfg = configure_client()?;
     let mut endpoint = Endpoint::client(bind_addr.parse()?)?;
@@ -0,0 +1,213 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+// 文件名的最大字符数
+const NAME_MAX_CHARS: usize = 255;
+// 缩略图的最大边长，不超过该尺寸的图片不生成缩略图
+const THUMBNAIL_MAX_SIDE: u32 = 256;
+// 解码图片时允许的最大边长和内存，防止解压炸弹
+const IMAGE_MAX_SIDE: u32 = 16384;
+const IMAGE_MAX_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// 消息中的附件引用，文件内容按 SHA-256 存放在文件存储中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    // 内容的 SHA-256（十六进制），下载时用作文件 id
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    pub content_type: String,
+    // 图片的宽高，非图片或无法解码时为空
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// 图片缩略图（PNG），和原文件一样存放在文件存储中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub id: String,
+    pub width: u32,
+    pub height: u32,
+    pub size: u64,
+}
+
+/// 本地文件存储：每个文件以内容的 SHA-256 命名，相同内容只保存一份
+pub struct BlobStore {
+    dir: PathBuf,
+    max_size: u64,
+    // 限制同时生成缩略图的数量
+    thumbnail_permits: Semaphore,
+}
+
+impl BlobStore {
+    pub fn open(dir: impl AsRef<Path>, max_size: u64, thumbnail_workers: usize) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            max_size,
+            thumbnail_permits: Semaphore::new(thumbnail_workers.max(1)),
+        })
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// 保存文件内容，返回附件引用
+    pub async fn put(&self, name: &str, data: &[u8]) -> Result<Attachment> {
+        if data.is_empty() {
+            anyhow::bail!("文件不能为空");
+        }
+        if data.len() as u64 > self.max_size {
+            anyhow::bail!("文件超过大小限制（{} 字节）", self.max_size);
+        }
+
+        let id = self.store(data).await?;
+        let content_type = sniff_content_type(data);
+        let mut attachment = Attachment {
+            id,
+            name: sanitize_name(name),
+            size: data.len() as u64,
+            content_type: content_type.to_string(),
+            width: None,
+            height: None,
+            thumbnail: None,
+        };
+        if content_type.starts_with("image/") {
+            match self.make_thumbnail(data.to_vec()).await {
+                Ok((width, height, thumbnail)) => {
+                    attachment.width = Some(width);
+                    attachment.height = Some(height);
+                    attachment.thumbnail = thumbnail;
+                }
+                Err(e) => tracing::warn!("无法为 {} 生成缩略图: {:?}", attachment.id, e),
+            }
+        }
+        Ok(attachment)
+    }
+
+    /// 按内容的 SHA-256 保存，返回文件 id
+    async fn store(&self, data: &[u8]) -> Result<String> {
+        let id = format!("{:x}", Sha256::digest(data));
+        let path = self.dir.join(&id);
+        if !tokio::fs::try_exists(&path).await? {
+            // 先写临时文件再改名，避免下载到写了一半的文件
+            let tmp = self.dir.join(format!("{}.{}.tmp", id, ulid::Ulid::new()));
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+        }
+        Ok(id)
+    }
+
+    /// 解码图片得到宽高，较大的图片另外生成缩略图
+    async fn make_thumbnail(&self, data: Vec<u8>) -> Result<(u32, u32, Option<Thumbnail>)> {
+        // 解码和缩放都很耗 CPU，放到阻塞线程池并限制并发
+        let _permit = self.thumbnail_permits.acquire().await?;
+        let (width, height, rendered) = tokio::task::spawn_blocking(move || render_thumbnail(&data)).await??;
+        let thumbnail = match rendered {
+            Some(rendered) => Some(Thumbnail {
+                id: self.store(&rendered.png).await?,
+                width: rendered.width,
+                height: rendered.height,
+                size: rendered.png.len() as u64,
+            }),
+            None => None,
+        };
+        Ok((width, height, thumbnail))
+    }
+
+    /// 读取文件内容和类型
+    pub async fn get(&self, id: &str) -> Result<(Vec<u8>, &'static str)> {
+        if id.len() != 64 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("无效的文件 id");
+        }
+        match tokio::fs::read(self.dir.join(id.to_ascii_lowercase())).await {
+            Ok(data) => {
+                let content_type = sniff_content_type(&data);
+                Ok((data, content_type))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => anyhow::bail!("文件不存在"),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// 编码好的缩略图
+struct RenderedThumbnail {
+    width: u32,
+    height: u32,
+    png: Vec<u8>,
+}
+
+/// 返回原图宽高，以及缩略图（图片较小时没有）
+fn render_thumbnail(data: &[u8]) -> Result<(u32, u32, Option<RenderedThumbnail>)> {
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(IMAGE_MAX_SIDE);
+    limits.max_image_height = Some(IMAGE_MAX_SIDE);
+    limits.max_alloc = Some(IMAGE_MAX_ALLOC);
+    let mut reader = image::ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode()?;
+
+    let (width, height) = (image.width(), image.height());
+    if width <= THUMBNAIL_MAX_SIDE && height <= THUMBNAIL_MAX_SIDE {
+        return Ok((width, height, None));
+    }
+    let thumbnail = image.thumbnail(THUMBNAIL_MAX_SIDE, THUMBNAIL_MAX_SIDE);
+    let mut png = Vec::new();
+    thumbnail.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+    Ok((width, height, Some(RenderedThumbnail {
+        width: thumbnail.width(),
+        height: thumbnail.height(),
+        png,
+    })))
+}
+
+/// 只保留文件名部分，去掉控制字符并限制长度
+fn sanitize_name(name: &str) -> String {
+    let name: String = Path::new(name.trim())
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(NAME_MAX_CHARS)
+        .collect();
+    if name.is_empty() {
+        "file".to_string()
+    } else {
+        name
+    }
+}
+
+/// 根据文件头判断内容类型，不信任客户端提供的类型
+fn sniff_content_type(data: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return content_type;
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if std::str::from_utf8(data).is_ok() {
+        return "text/plain; charset=utf-8";
+    }
+    "application/octet-stream"
+}
@@ -1,90 +1,2192 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use tokio::sync::broadcast;
-use serde_json;
-use tokio::sync::mpsc::UnboundedSender;
+use std::sync::Arc;
+use crate::accounts::{constant_time_eq, AccountChange, AccountStore, Login};
+use crate::backplane::{Backplane, Envelope, InProcessBackplane, Payload};
+use crate::blobs::{Attachment, BlobStore};
+use crate::config::{Config, LagPolicy};
+use crate::filters::{FilterAction, FilterChain, FilterContext};
+use crate::history::HistoryStore;
+use crate::markup::{self, Span};
+use crate::metrics::{Exposition, Metrics};
+use crate::moderation::{AuditEntry, Ban, BanTarget, ModerationChange, ModerationStore, Role};
+use crate::outbound::{OutboundQueue, Push};
+use crate::outbox::Outboxes;
+use crate::preview::{self, LinkPreview, Unfurler};
+use crate::ratelimit::{Action, RateKey, RateLimiter, Verdict};
+use crate::validation;
+
+// 目前只有一个公共聊天室，按房间保存的数据都使用这个 id
+pub const DEFAULT_ROOM: &str = "lobby";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+    Online,
+    Away,
+    Busy,
+    // 隐身：不出现在用户列表中
+    Invisible,
+}
+
+impl std::str::FromStr for UserStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "online" => Ok(UserStatus::Online),
+            "away" => Ok(UserStatus::Away),
+            "busy" => Ok(UserStatus::Busy),
+            "invisible" => Ok(UserStatus::Invisible),
+            _ => anyhow::bail!("未知的状态: {}", s),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
     pub last_seen: DateTime<Utc>,
+    pub status: UserStatus,
+    pub status_text: Option<String>,
+    // 是否因空闲被自动标记为离开，有新动作时自动恢复在线
+    #[serde(skip)]
+    pub auto_away: bool,
+    // 该用户所在连接的 id，用于定向推送
+    #[serde(skip)]
+    pub client_id: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
+    // 服务器分配的 ULID，按时间有序
+    pub id: String,
     pub username: String,
     pub content: String,
     pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime<Utc>>,
+    // 回复所属话题的根消息 id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    // 引用的原消息摘要
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub reply_count: u32,
+    // 表情 -> 添加该表情的用户
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Box<Attachment>>,
+    // 消息中链接的预览，发送后异步补充
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previews: Vec<LinkPreview>,
+    // 解析后的 Markdown 和 @提及，纯文本消息为空
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<Span>,
+    // 作者的账号 id，服务器通知没有作者。用户改名后不变，编辑和删除按它判断归属
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_id: Option<String>,
+    // 写入本节点消息记录时分配的顺序号，只在本地有效。
+    // 其他节点生成的 id 受时钟影响，不能用来判断送达顺序
+    #[serde(skip)]
+    pub seq: u64,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+// 服务器通知使用的用户名，不能被注册
+const SYSTEM_USER: &str = "system";
+// 引用摘要的最大字符数
+const QUOTE_MAX_CHARS: usize = 100;
+// 单个表情回应的最大字符数（组合表情由多个字符组成）
+const REACTION_MAX_CHARS: usize = 16;
+// 管理操作原因的最大字符数
+const MODERATION_REASON_MAX_CHARS: usize = 200;
+// 最多保留的待审核消息数，超出时丢弃最早的
+const HELD_MAX: usize = 500;
+// 超过该时长没有收到其他节点的用户列表（心跳）时，认为该节点已下线
+const NODE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// 单调递增的 ULID 生成器，保证同一毫秒内生成的 id 也有序
+static ID_GENERATOR: Mutex<ulid::Generator> = Mutex::new(ulid::Generator::new());
+
+fn next_message_id() -> String {
+    ID_GENERATOR
+        .lock()
+        .unwrap()
+        .generate()
+        .unwrap_or_else(|_| ulid::Ulid::new())
+        .to_string()
+}
+
+impl ChatMessage {
+    pub fn new(username: String, content: String) -> Self {
+        Self {
+            id: next_message_id(),
+            username,
+            content,
+            timestamp: Utc::now(),
+            edited_at: None,
+            parent_id: None,
+            quote: None,
+            reply_count: 0,
+            reactions: BTreeMap::new(),
+            attachment: None,
+            previews: Vec::new(),
+            spans: Vec::new(),
+            author_id: None,
+            seq: 0,
+        }
+    }
+
+    /// 服务器产生的通知（加入、离开、改名、管理操作、公告），以 system 的名义发送
+    pub fn notice(content: String) -> Self {
+        Self::new(SYSTEM_USER.to_string(), content)
+    }
+}
+
+/// 正在输入提示，只经由临时通道转发，不进入消息记录
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "typing")]
+pub struct TypingEvent {
+    pub username: String,
+    pub typing: bool,
+    // 发出者的连接 id，写任务据此跳过自己的输入提示
+    #[serde(skip)]
+    pub client_id: Option<usize>,
+}
+
+// 两次“开始输入”之间的最短间隔，以及多久没有刷新就视为停止输入
+const TYPING_MIN_INTERVAL: Duration = Duration::from_secs(1);
+const TYPING_TTL: Duration = Duration::from_secs(5);
+
+struct TypingState {
+    last_signal: Instant,
+    active: bool,
+}
+
+/// 单个连接在广播通道上的读取位置，用于滞后后从消息记录补发并去重
+pub struct BroadcastCursor {
+    last_seq: u64,
+    // 已经补发过、稍后仍可能从广播通道收到的消息（顺序号）
+    resent: BTreeSet<u64>,
+}
+
+impl BroadcastCursor {
+    /// 收到一条广播消息。返回 false 表示它已经补发过，应当跳过
+    pub fn advance(&mut self, message: &ChatMessage) -> bool {
+        if self.resent.remove(&message.seq) {
+            return false;
+        }
+        // 广播通道按顺序号推送，更早的补发记录不会再出现
+        self.resent.retain(|&seq| seq > message.seq);
+        self.last_seq = message.seq;
+        true
+    }
+}
+
+/// 被过滤规则拦下、等待管理员审核的消息
+#[derive(Debug, Clone, Serialize)]
+pub struct HeldMessage {
+    pub id: String,
+    pub username: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    pub quote: bool,
+    pub reason: String,
+    pub held_at: DateTime<Utc>,
+}
+
+/// 需要经过过滤链的内容来源
+enum Submission<'a> {
+    Message,
+    Reply { parent_id: &'a str, quote: bool },
+    Edit,
+    Caption,
+}
+
+/// WebSocket 连接收发的字节数，QUIC 连接直接使用 quinn 的统计
+#[derive(Debug, Default)]
+pub struct Traffic {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+}
+
+/// 连接使用的传输方式
+#[derive(Clone)]
+pub enum Transport {
+    Quic(quinn::Connection),
+    WebSocket {
+        addr: Option<SocketAddr>,
+        traffic: Arc<Traffic>,
+    },
+}
+
+impl Transport {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Transport::Quic(connection) => Some(connection.remote_address()),
+            Transport::WebSocket { addr, .. } => *addr,
+        }
+    }
+}
+
+/// 连接从注册到写任务发完剩余内容期间持有，释放后该连接才算结束
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 管理接口中显示的连接信息
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    pub id: usize,
+    pub transport: &'static str,
+    pub remote_addr: Option<SocketAddr>,
+    // 尚未登录时为空
+    pub username: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // 以下只有 QUIC 连接有：RTT、拥塞窗口和丢包数
+    pub rtt_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwnd: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lost_packets: Option<u64>,
+}
+
+/// 一个在线连接
+struct Client {
+    queue: Arc<OutboundQueue>,
+    // 对端地址用于按 IP 限流，连接统计用于管理接口
+    transport: Transport,
+    connected_at: DateTime<Utc>,
+    // WebSocket 登录后生成，HTTP 上传凭它确定上传者
+    upload_token: Option<String>,
 }
 
 pub struct ChatState {
     users: Mutex<HashMap<String, User>>,
     tx: broadcast::Sender<ChatMessage>,
-    websockets: Mutex<Vec<UnboundedSender<String>>>,
+    // 所有在线连接（WebSocket 与 QUIC）的发送队列
+    clients: Mutex<HashMap<usize, Client>>,
+    next_client_id: AtomicUsize,
+    send_order: Mutex<()>,
+    // 尚未结束的连接数，见 `ConnectionGuard`
+    open_connections: Arc<AtomicUsize>,
+    // 开始关闭后新注册的连接直接关闭发送队列
+    shutting_down: AtomicBool,
+    typing: Mutex<HashMap<String, TypingState>>,
+    ephemeral_tx: broadcast::Sender<TypingEvent>,
+    history: HistoryStore,
+    accounts: AccountStore,
+    blobs: BlobStore,
+    moderation: ModerationStore,
+    filters: FilterChain,
+    // 等待审核的消息，按 id 排序，只保存在内存中
+    held: Mutex<BTreeMap<String, HeldMessage>>,
+    // 关闭链接预览时为 None
+    unfurler: Option<Unfurler>,
+    outboxes: Outboxes,
+    rate_limits: RateLimiter,
+    metrics: Metrics,
+    // 节点之间的消息总线，以及其他节点最近一次发布的在线用户
+    backplane: Arc<dyn Backplane>,
+    remote_users: Mutex<HashMap<String, RemoteNode>>,
+    config: Config,
+}
+
+struct RemoteNode {
+    users: Vec<User>,
+    seen_at: Instant,
 }
 
 impl ChatState {
-    pub fn new() -> Self {
+    pub fn new(
+        config: Config,
+        history: HistoryStore,
+        accounts: AccountStore,
+        blobs: BlobStore,
+        moderation: ModerationStore,
+        filters: FilterChain,
+        unfurler: Option<Unfurler>,
+    ) -> Self {
         let (tx, _) = broadcast::channel(100);
+        let (ephemeral_tx, _) = broadcast::channel(100);
         Self {
             users: Mutex::new(HashMap::new()),
             tx,
-            websockets: Mutex::new(Vec::new()),
+            clients: Mutex::new(HashMap::new()),
+            next_client_id: AtomicUsize::new(0),
+            send_order: Mutex::new(()),
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            shutting_down: AtomicBool::new(false),
+            typing: Mutex::new(HashMap::new()),
+            ephemeral_tx,
+            history,
+            accounts,
+            blobs,
+            moderation,
+            filters,
+            held: Mutex::new(BTreeMap::new()),
+            unfurler,
+            outboxes: Outboxes::new(),
+            rate_limits: RateLimiter::new(
+                config.message_rate,
+                config.join_rate,
+                config.upload_rate,
+                config.rate_limit_strikes,
+            ),
+            metrics: Metrics::default(),
+            backplane: Arc::new(InProcessBackplane::new()),
+            remote_users: Mutex::new(HashMap::new()),
+            config,
         }
     }
 
-    pub fn add_user(&self, username: String) {
-        let mut users = self.users.lock().unwrap();
-        users.insert(
-            username.clone(),
-            User {
-                username: username.clone(),
-                last_seen: Utc::now(),
+    /// 使用其他消息总线与别的节点互通，默认只在本进程内广播
+    pub fn with_backplane(mut self, backplane: Arc<dyn Backplane>) -> Self {
+        self.backplane = backplane;
+        self
+    }
+
+    /// 登录：名字已在线时拒绝。所有者使用配置的令牌，其他名字第一次使用时生成令牌并发给客户端，
+    /// 之后必须提供该令牌，角色因此只属于证明过名字归属的人
+    pub fn add_user(&self, username: String, client_id: usize, token: Option<&str>) -> anyhow::Result<()> {
+        if self.is_online(&username) || self.online_elsewhere(&username) {
+            anyhow::bail!("用户 {} 已在线", username);
+        }
+        let issued = match self.config.owners.get(&username) {
+            Some(expected) => {
+                if !token.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+                    anyhow::bail!("{} 是所有者，请提供配置的登录令牌", username);
+                }
+                None
+            }
+            None => match self.accounts.login(&username, token)? {
+                Login::Verified => None,
+                Login::Registered(token) => Some(token),
             },
-        );
+        };
+        // 新认领的名字不继承以前留下的管理角色
+        if issued.is_some() && self.change_moderation(ModerationChange::ClearRoles { username: username.clone() }) {
+            tracing::warn!("{} 重新认领，清除之前的管理角色", username);
+            self.moderation.audit(&AuditEntry::new("system", "clearRoles", &username));
+        }
+        {
+            let mut users = self.users.lock().unwrap();
+            // 验证令牌期间可能有同名的连接先完成了登录
+            if users.contains_key(&username) {
+                anyhow::bail!("用户 {} 已在线", username);
+            }
+            users.insert(
+                username.clone(),
+                User {
+                    username: username.clone(),
+                    last_seen: Utc::now(),
+                    status: UserStatus::Online,
+                    status_text: None,
+                    auto_away: false,
+                    client_id,
+                },
+            );
+        }
+        if let Some(token) = issued {
+            self.send_to(client_id, serde_json::json!({
+                "type": "credentials",
+                "username": username,
+                "token": token,
+            }));
+        }
+        self.accounts.account_id(&username);
+        // 记录第一次登录的时间，过滤器据此识别新用户
+        self.accounts.update(&username, |account| {
+            let first = account.first_seen.is_none();
+            account.first_seen.get_or_insert_with(Utc::now);
+            first
+        });
+        self.sync_account(&username);
+        Ok(())
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.users.lock().unwrap().contains_key(username)
+    }
+
+    // 名字是否在其他节点上在线（包括隐身的用户）
+    fn online_elsewhere(&self, username: &str) -> bool {
+        let nodes = self.remote_users.lock().unwrap();
+        nodes.values().any(|node| node.users.iter().any(|u| u.username == username))
+    }
+
+    /// 连接当前登录的用户名
+    pub fn username_of(&self, client_id: usize) -> Option<String> {
+        let users = self.users.lock().unwrap();
+        users
+            .values()
+            .find(|u| u.client_id == client_id)
+            .map(|u| u.username.clone())
     }
 
     pub fn remove_user(&self, username: &str) {
         let mut users = self.users.lock().unwrap();
         users.remove(username);
+        drop(users);
+        self.set_typing(username, false);
     }
 
+    /// 所有节点的在线用户列表，隐身用户不会出现在其中。
+    /// 同一用户同时连接多个节点时只保留一条，以本节点为准
     pub fn get_users(&self) -> Vec<User> {
-        let users = self.users.lock().unwrap();
-        users.values().cloned().collect()
+        let mut users = self.local_users();
+        let mut seen: HashSet<String> = users.iter().map(|u| u.username.clone()).collect();
+        let remote = self.remote_users.lock().unwrap();
+        for user in remote.values().flat_map(|node| &node.users) {
+            if seen.insert(user.username.clone()) {
+                users.push(user.clone());
+            }
+        }
+        users.retain(|u| u.status != UserStatus::Invisible);
+        users
+    }
+
+    // 连接在本节点上的在线用户，包括隐身的用户，发给其他节点用于检查重名登录
+    fn local_users(&self) -> Vec<User> {
+        self.users.lock().unwrap().values().cloned().collect()
+    }
+
+    /// 记录用户的一次活动；如果之前因空闲被标记为离开，则恢复在线
+    pub fn touch(&self, username: &str) {
+        let restored = {
+            let mut users = self.users.lock().unwrap();
+            match users.get_mut(username) {
+                Some(user) => {
+                    user.last_seen = Utc::now();
+                    if user.auto_away {
+                        user.auto_away = false;
+                        user.status = UserStatus::Online;
+                        Some(user.clone())
+                    } else {
+                        None
+                    }
+                }
+                None => None,
+            }
+        };
+        if let Some(user) = restored {
+            self.broadcast_presence(&user);
+        }
+    }
+
+    pub fn set_status(
+        &self,
+        username: &str,
+        status: UserStatus,
+        status_text: Option<String>,
+    ) -> anyhow::Result<()> {
+        let status_text = status_text
+            .map(|text| validation::sanitize_line(&text, validation::STATUS_TEXT_MAX_CHARS))
+            .filter(|text| !text.is_empty());
+        let (user, was_invisible) = {
+            let mut users = self.users.lock().unwrap();
+            let user = users
+                .get_mut(username)
+                .ok_or_else(|| anyhow::anyhow!("用户 {} 不在线", username))?;
+            let was_invisible = user.status == UserStatus::Invisible;
+            user.status = status;
+            user.status_text = status_text;
+            user.auto_away = false;
+            user.last_seen = Utc::now();
+            (user.clone(), was_invisible)
+        };
+
+        // 进入或离开隐身时需要刷新用户列表
+        if was_invisible || status == UserStatus::Invisible {
+            self.broadcast_user_list();
+        }
+        self.broadcast_presence(&user);
+        Ok(())
+    }
+
+    /// 把超过 `timeout` 没有活动的在线用户标记为离开
+    pub fn mark_idle_users(&self, timeout: std::time::Duration) {
+        let Ok(timeout) = chrono::Duration::from_std(timeout) else {
+            return;
+        };
+        let now = Utc::now();
+        let idle: Vec<User> = {
+            let mut users = self.users.lock().unwrap();
+            users
+                .values_mut()
+                .filter(|u| u.status == UserStatus::Online && now - u.last_seen > timeout)
+                .map(|u| {
+                    u.status = UserStatus::Away;
+                    u.auto_away = true;
+                    u.clone()
+                })
+                .collect()
+        };
+        for user in &idle {
+            self.broadcast_presence(user);
+        }
+    }
+
+    fn broadcast_presence(&self, user: &User) {
+        if user.status == UserStatus::Invisible {
+            return;
+        }
+        self.broadcast_event(serde_json::json!({
+            "type": "presence",
+            "user": user,
+        }));
+    }
+
+    /// 更新输入状态。开始输入受频率限制，停止输入或过期时广播一次 `typing: false`
+    pub fn set_typing(&self, username: &str, typing: bool) {
+        let now = Instant::now();
+        let changed = {
+            let mut states = self.typing.lock().unwrap();
+            if typing {
+                let state = states.entry(username.to_string()).or_insert(TypingState {
+                    last_signal: now - TYPING_MIN_INTERVAL,
+                    active: false,
+                });
+                if now.duration_since(state.last_signal) < TYPING_MIN_INTERVAL {
+                    return;
+                }
+                state.last_signal = now;
+                !std::mem::replace(&mut state.active, true)
+            } else {
+                match states.get_mut(username) {
+                    Some(state) => std::mem::replace(&mut state.active, false),
+                    None => false,
+                }
+            }
+        };
+        if changed {
+            let client_id = self.users.lock().unwrap().get(username).map(|u| u.client_id);
+            let _ = self.ephemeral_tx.send(TypingEvent {
+                username: username.to_string(),
+                typing,
+                client_id,
+            });
+        }
+    }
+
+    /// 清理过期的输入状态
+    pub fn expire_typing(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        {
+            let mut states = self.typing.lock().unwrap();
+            states.retain(|username, state| {
+                let age = now.duration_since(state.last_signal);
+                if state.active && age > TYPING_TTL {
+                    state.active = false;
+                    expired.push(username.clone());
+                }
+                state.active || age <= TYPING_TTL
+            });
+        }
+        for username in expired {
+            let client_id = self.users.lock().unwrap().get(&username).map(|u| u.client_id);
+            let _ = self.ephemeral_tx.send(TypingEvent {
+                username,
+                typing: false,
+                client_id,
+            });
+        }
+    }
+
+    pub fn subscribe_ephemeral(&self) -> broadcast::Receiver<TypingEvent> {
+        self.ephemeral_tx.subscribe()
+    }
+
+    /// 修改昵称：在同一把锁内完成重新插入，新名字在线或曾被使用过时返回错误
+    pub fn rename_user(&self, old: &str, new: &str) -> anyhow::Result<String> {
+        let new = &self.validate_username(new)?;
+        if self.moderation.find_ban(new, None).is_some() {
+            anyhow::bail!("用户名 {} 已被封禁", new);
+        }
+        // 改成别人用过的名字会继承对方的角色、禁言、令牌和已读位置
+        if self.config.owners.contains_key(new.as_str()) || self.moderation.has_records(new) || self.accounts.exists(new) {
+            anyhow::bail!("用户名 {} 已被使用", new);
+        }
+        if self.online_elsewhere(new) {
+            anyhow::bail!("用户名 {} 已被占用", new);
+        }
+        let (client_id, invisible) = {
+            let mut users = self.users.lock().unwrap();
+            if users.contains_key(new) {
+                anyhow::bail!("用户名 {} 已被占用", new);
+            }
+            let mut user = users
+                .remove(old)
+                .ok_or_else(|| anyhow::anyhow!("用户 {} 不在线", old))?;
+            user.username = new.to_string();
+            let renamed = (user.client_id, user.status == UserStatus::Invisible);
+            users.insert(new.to_string(), user);
+            renamed
+        };
+        self.set_typing(old, false);
+        self.outboxes.rename(old, new);
+        self.accounts.rename(old, new);
+        self.publish(Payload::Account(AccountChange::Rename {
+            old: old.to_string(),
+            new: new.to_string(),
+        }));
+        self.change_moderation(ModerationChange::Rename {
+            old: old.to_string(),
+            new: new.to_string(),
+        });
+
+        let renamed = serde_json::json!({
+            "type": "userRenamed",
+            "oldUsername": old,
+            "newUsername": new,
+        });
+        // 隐身时只告诉自己，不公开改名
+        if invisible {
+            self.send_to(client_id, renamed);
+        } else {
+            self.broadcast_event(renamed);
+            self.broadcast_message(ChatMessage::notice(format!("{} 现在改名为 {}", old, new)));
+        }
+        self.broadcast_user_list();
+        Ok(new.to_string())
     }
 
     pub fn broadcast_message(&self, message: ChatMessage) {
+        self.metrics.record_message();
+        self.publish(Payload::Message(Box::new(message.clone())));
+        self.append_and_send(message);
+    }
+
+    // 写入消息记录并推送给本地连接。加锁使推送顺序与顺序号一致
+    fn append_and_send(&self, mut message: ChatMessage) {
+        let _order = self.send_order.lock().unwrap();
+        self.history.append(&mut message);
         let _ = self.tx.send(message);
     }
 
+    /// 保存上传的文件，并以带附件的消息发出
+    pub async fn share_file(
+        &self,
+        username: &str,
+        name: &str,
+        caption: Option<&str>,
+        data: &[u8],
+    ) -> anyhow::Result<ChatMessage> {
+        self.ensure_can_post(username)?;
+        let caption = caption
+            .filter(|c| !c.trim().is_empty())
+            .map(|c| validation::sanitize_content(c, self.config.max_message_length))
+            .transpose()?
+            .map(|c| self.filter_content(username, c, Submission::Caption))
+            .transpose()?;
+        let attachment = self.blobs.put(name, data).await?;
+        let content = caption.unwrap_or_else(|| format!("分享了文件 {}", attachment.name));
+        let mut message = self.new_message(username, content);
+        message.attachment = Some(Box::new(attachment));
+        let mentions = self.render(&mut message);
+        self.touch(username);
+        self.broadcast_message(message.clone());
+        self.notify_mentions(&message, &mentions);
+        Ok(message)
+    }
+
+    pub fn history(&self) -> &HistoryStore {
+        &self.history
+    }
+
+    pub fn accounts(&self) -> &AccountStore {
+        &self.accounts
+    }
+
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    /// 消息中有链接时在后台抓取预览，完成后推送 messageEnriched
+    pub fn unfurl(self: &Arc<Self>, message: &ChatMessage) {
+        if self.unfurler.is_none() {
+            return;
+        }
+        let urls = preview::find_urls(&message.content);
+        if urls.is_empty() {
+            return;
+        }
+        let state = self.clone();
+        let id = message.id.clone();
+        tokio::spawn(async move {
+            let Some(unfurler) = &state.unfurler else { return };
+            let previews: Vec<LinkPreview> = futures::future::join_all(urls.iter().map(|url| unfurler.preview(url)))
+                .await
+                .into_iter()
+                .flatten()
+                .collect();
+            // 抓取期间消息可能已被删除
+            if previews.is_empty() || !state.history.enrich(&id, &previews) {
+                return;
+            }
+            state.broadcast_event(serde_json::json!({
+                "type": "messageEnriched",
+                "id": id,
+                "previews": previews,
+            }));
+        });
+    }
+
+    /// 发送一条普通消息
+    pub fn post_message(&self, username: &str, content: &str) -> anyhow::Result<ChatMessage> {
+        self.ensure_can_post(username)?;
+        let content = validation::sanitize_content(content, self.config.max_message_length)?;
+        let content = self.filter_content(username, content, Submission::Message)?;
+        Ok(self.publish_message(username, content))
+    }
+
+    /// 发布已经通过检查的消息
+    fn publish_message(&self, username: &str, content: String) -> ChatMessage {
+        let mut message = self.new_message(username, content);
+        let mentions = self.render(&mut message);
+        self.broadcast_message(message.clone());
+        self.notify_mentions(&message, &mentions);
+        message
+    }
+
+    /// 运行过滤链，返回（可能被改写的）内容。需要审核的新消息和回复会保存下来并通知管理员
+    fn filter_content(&self, username: &str, content: String, submission: Submission) -> anyhow::Result<String> {
+        let context = FilterContext {
+            username,
+            content: &content,
+            role: self.moderation.role(DEFAULT_ROOM, username),
+            first_seen: self.accounts.get(username).first_seen,
+        };
+        let reason = match self.filters.run(&context) {
+            FilterAction::Allow => return Ok(content),
+            FilterAction::Rewrite(rewritten) => return Ok(rewritten),
+            FilterAction::Reject(reason) => anyhow::bail!("{}", reason),
+            FilterAction::Hold(reason) => reason,
+        };
+        let (parent_id, quote) = match submission {
+            Submission::Message => (None, false),
+            Submission::Reply { parent_id, quote } => (Some(parent_id.to_string()), quote),
+            // 编辑和文件说明无法延后发布，直接拒绝
+            Submission::Edit | Submission::Caption => anyhow::bail!("{}，无法发送", reason),
+        };
+        let held = HeldMessage {
+            id: next_message_id(),
+            username: username.to_string(),
+            content,
+            parent_id,
+            quote,
+            reason: reason.clone(),
+            held_at: Utc::now(),
+        };
+        {
+            let mut queue = self.held.lock().unwrap();
+            queue.insert(held.id.clone(), held.clone());
+            if queue.len() > HELD_MAX {
+                queue.pop_first();
+            }
+        }
+        self.notify_moderators(serde_json::json!({
+            "type": "messageHeld",
+            "message": held,
+        }));
+        anyhow::bail!("消息已提交管理员审核：{}", reason)
+    }
+
+    fn notify_moderators(&self, event: serde_json::Value) {
+        let moderators: Vec<usize> = self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|u| self.moderation.role(DEFAULT_ROOM, &u.username) >= Role::Moderator)
+            .map(|u| u.client_id)
+            .collect();
+        for id in moderators {
+            self.send_to(id, event.clone());
+        }
+    }
+
+    /// 规则文件修改后重新加载过滤规则
+    pub fn reload_filters(&self) {
+        self.filters.reload_if_changed();
+    }
+
+    /// 待审核的消息，只有管理员可以查看
+    pub fn held_messages(&self, actor: &str) -> anyhow::Result<Vec<HeldMessage>> {
+        if self.moderation.role(DEFAULT_ROOM, actor) < Role::Moderator {
+            anyhow::bail!("没有管理权限");
+        }
+        Ok(self.held.lock().unwrap().values().cloned().collect())
+    }
+
+    /// 审核消息：通过后以原作者的名义发布，否则丢弃并告知作者
+    pub fn review_held(&self, actor: &str, id: &str, approve: bool) -> anyhow::Result<Option<ChatMessage>> {
+        if self.moderation.role(DEFAULT_ROOM, actor) < Role::Moderator {
+            anyhow::bail!("没有管理权限");
+        }
+        let held = self
+            .held
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| anyhow::anyhow!("待审核消息 {} 不存在", id))?;
+
+        let mut entry = AuditEntry::new(actor, if approve { "approve" } else { "discard" }, &held.username);
+        entry.room = Some(DEFAULT_ROOM.to_string());
+        entry.reason = Some(held.reason.clone());
+        self.moderation.audit(&entry);
+        self.notify_moderators(serde_json::json!({
+            "type": "heldReviewed",
+            "id": id,
+            "approved": approve,
+            "by": actor,
+        }));
+        if !approve {
+            self.send_to_user(&held.username, serde_json::json!({
+                "type": "error",
+                "message": "你的消息未通过管理员审核",
+            }));
+            return Ok(None);
+        }
+        let message = match &held.parent_id {
+            Some(parent_id) => self.publish_reply(&held.username, parent_id, held.content, held.quote)?,
+            None => self.publish_message(&held.username, held.content),
+        };
+        Ok(Some(message))
+    }
+
+    /// 按配置检查用户名，返回去掉首尾空白后的名字
+    pub fn validate_username(&self, name: &str) -> anyhow::Result<String> {
+        validation::validate_username(name, self.config.max_username_length)
+    }
+
+    /// 登录限流，按用户名和 IP 计数
+    pub fn check_join(&self, username: &str, ip: Option<IpAddr>) -> anyhow::Result<()> {
+        if let Some(ban) = self.moderation.find_ban(username, ip) {
+            anyhow::bail!("{}", ban_notice(&ban));
+        }
+        match self.check_rate(Action::Join, None, username, ip) {
+            Verdict::Allowed => Ok(()),
+            Verdict::Throttled(retry_after) => {
+                anyhow::bail!("登录过于频繁，请 {} 秒后再试", retry_after.as_secs_f64().ceil())
+            }
+            Verdict::Disconnect => anyhow::bail!("登录过于频繁，请稍后再试"),
+        }
+    }
+
+    /// 解析消息的 Markdown 和 @提及，返回被提及的用户
+    fn render(&self, message: &mut ChatMessage) -> Vec<String> {
+        // 其他节点上的在线用户同样可以被提及
+        let usernames: Vec<String> = {
+            let mut usernames: Vec<String> = self.users.lock().unwrap().keys().cloned().collect();
+            let remote = self.remote_users.lock().unwrap();
+            usernames.extend(remote.values().flat_map(|node| node.users.iter().map(|u| u.username.clone())));
+            usernames
+        };
+        let spans = markup::parse(&message.content, &usernames);
+        let mentions = markup::mentions(&spans);
+        message.spans = if markup::is_plain(&spans) { Vec::new() } else { spans };
+        mentions
+    }
+
+    /// 给被提及的用户发送高亮通知，屏蔽了房间的用户也会收到
+    fn notify_mentions(&self, message: &ChatMessage, mentions: &[String]) {
+        let event = serde_json::json!({
+            "type": "mention",
+            "room": DEFAULT_ROOM,
+            "highlight": true,
+            "message": message,
+        });
+        for user in mentions.iter().filter(|user| **user != message.username) {
+            self.send_to_user(user, event.clone());
+        }
+    }
+
+    /// 屏蔽或恢复房间的通知
+    pub fn set_muted(&self, username: &str, room: &str, muted: bool) {
+        let changed = self.accounts.update(username, |account| {
+            if muted {
+                account.muted_rooms.insert(room.to_string())
+            } else {
+                account.muted_rooms.remove(room)
+            }
+        });
+        if changed {
+            self.sync_account(username);
+        }
+    }
+
+    /// 访客和被禁言的用户不能发言
+    fn ensure_can_post(&self, username: &str) -> anyhow::Result<()> {
+        if self.moderation.role(DEFAULT_ROOM, username) == Role::Guest {
+            anyhow::bail!("访客不能发言");
+        }
+        if let Some(until) = self.moderation.muted_until(DEFAULT_ROOM, username) {
+            anyhow::bail!("你已被禁言，{} 秒后解除", (until - Utc::now()).num_seconds().max(1));
+        }
+        Ok(())
+    }
+
+    /// 管理操作的权限：至少是管理员，并且角色高于对方
+    fn ensure_outranks(&self, room: &str, actor: &str, target: &str) -> anyhow::Result<()> {
+        let role = self.moderation.role(room, actor);
+        if role < Role::Moderator {
+            anyhow::bail!("没有管理权限");
+        }
+        if role <= self.moderation.role(room, target) {
+            anyhow::bail!("不能对 {} 执行管理操作", target);
+        }
+        Ok(())
+    }
+
+    /// 把用户移出聊天室（断开连接），之后仍可重新登录
+    pub fn kick(&self, actor: &str, room: &str, target: &str, reason: Option<&str>) -> anyhow::Result<()> {
+        ensure_room(room)?;
+        self.ensure_outranks(room, actor, target)?;
+        let client_id = self
+            .users
+            .lock()
+            .unwrap()
+            .get(target)
+            .map(|u| u.client_id)
+            .ok_or_else(|| anyhow::anyhow!("用户 {} 不在线", target))?;
+        let reason = clean_reason(reason);
+        let notice = match &reason {
+            Some(reason) => format!("你被 {} 移出了聊天室（{}）", actor, reason),
+            None => format!("你被 {} 移出了聊天室", actor),
+        };
+        self.disconnect(client_id, serde_json::json!({
+            "type": "kicked",
+            "room": room,
+            "by": actor,
+            "reason": reason,
+            "message": notice,
+        }));
+
+        let mut entry = AuditEntry::new(actor, "kick", target);
+        entry.room = Some(room.to_string());
+        entry.reason = reason;
+        self.moderation.audit(&entry);
+        self.broadcast_message(ChatMessage::notice(format!("{} 将 {} 移出了聊天室", actor, target)));
+        Ok(())
+    }
+
+    /// 禁言一段时间，期间不能发消息、回复、编辑、回应或上传文件
+    pub fn mute_user(
+        &self,
+        actor: &str,
+        room: &str,
+        target: &str,
+        duration: Duration,
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        ensure_room(room)?;
+        self.ensure_outranks(room, actor, target)?;
+        if duration.is_zero() {
+            anyhow::bail!("禁言时长必须大于 0");
+        }
+        self.change_moderation(ModerationChange::Mute {
+            room: room.to_string(),
+            username: target.to_string(),
+            until: Utc::now() + chrono::Duration::from_std(duration)?,
+        });
+
+        let mut entry = AuditEntry::new(actor, "mute", target);
+        entry.room = Some(room.to_string());
+        entry.reason = clean_reason(reason);
+        entry.duration_secs = Some(duration.as_secs());
+        self.moderation.audit(&entry);
+        self.broadcast_message(ChatMessage::notice(format!(
+            "{} 将 {} 禁言 {} 秒",
+            actor,
+            target,
+            duration.as_secs(),
+        )));
+        Ok(())
+    }
+
+    pub fn unmute_user(&self, actor: &str, room: &str, target: &str) -> anyhow::Result<()> {
+        ensure_room(room)?;
+        self.ensure_outranks(room, actor, target)?;
+        let change = ModerationChange::Unmute {
+            room: room.to_string(),
+            username: target.to_string(),
+        };
+        if !self.change_moderation(change) {
+            anyhow::bail!("{} 没有被禁言", target);
+        }
+
+        let mut entry = AuditEntry::new(actor, "unmute", target);
+        entry.room = Some(room.to_string());
+        self.moderation.audit(&entry);
+        self.broadcast_message(ChatMessage::notice(format!("{} 解除了 {} 的禁言", actor, target)));
+        Ok(())
+    }
+
+    /// 封禁用户名或 IP，`duration` 为空表示永久。在线的对应连接会被断开
+    pub fn ban(
+        &self,
+        actor: &str,
+        room: &str,
+        target: BanTarget,
+        duration: Option<Duration>,
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        ensure_room(room)?;
+        if self.moderation.role(room, actor) < Role::Moderator {
+            anyhow::bail!("没有管理权限");
+        }
+        match &target {
+            BanTarget::User(username) => self.ensure_outranks(room, actor, username)?,
+            // 同一 IP 上不能有权限不低于自己的用户
+            BanTarget::Ip(_) => {
+                for id in self.banned_clients(&target) {
+                    if let Some(username) = self.username_of(id) {
+                        if username == actor {
+                            anyhow::bail!("不能封禁自己所在的 IP");
+                        }
+                        self.ensure_outranks(room, actor, &username)?;
+                    }
+                }
+            }
+        }
+
+        let ban = Ban {
+            target: target.clone(),
+            by: actor.to_string(),
+            reason: clean_reason(reason),
+            created_at: Utc::now(),
+            expires_at: duration.map(chrono::Duration::from_std).transpose()?.map(|d| Utc::now() + d),
+        };
+        let mut entry = AuditEntry::new(actor, "ban", &target);
+        entry.room = Some(room.to_string());
+        entry.reason = ban.reason.clone();
+        entry.duration_secs = duration.map(|d| d.as_secs());
+        self.moderation.audit(&entry);
+        self.disconnect_banned(&ban);
+        // 不公开被封禁的 IP
+        if let BanTarget::User(username) = &target {
+            self.broadcast_message(ChatMessage::notice(format!("{} 封禁了 {}", actor, username)));
+        }
+        self.change_moderation(ModerationChange::Ban { ban });
+        Ok(())
+    }
+
+    // 封禁对象在本节点上的连接
+    fn banned_clients(&self, target: &BanTarget) -> Vec<usize> {
+        match target {
+            BanTarget::User(username) => self.client_of(username).into_iter().collect(),
+            BanTarget::Ip(ip) => self
+                .clients
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, client)| client.transport.remote_addr().map(|addr| addr.ip()) == Some(*ip))
+                .map(|(id, _)| *id)
+                .collect(),
+        }
+    }
+
+    // 断开被封禁的连接并告知原因
+    fn disconnect_banned(&self, ban: &Ban) {
+        for id in self.banned_clients(&ban.target) {
+            self.disconnect(id, serde_json::json!({
+                "type": "banned",
+                "message": ban_notice(ban),
+                "reason": ban.reason,
+                "expiresAt": ban.expires_at,
+            }));
+        }
+    }
+
+    pub fn unban(&self, actor: &str, room: &str, target: BanTarget) -> anyhow::Result<()> {
+        ensure_room(room)?;
+        if self.moderation.role(room, actor) < Role::Moderator {
+            anyhow::bail!("没有管理权限");
+        }
+        if !self.change_moderation(ModerationChange::Unban { target: target.clone() }) {
+            anyhow::bail!("没有对 {} 的封禁", target);
+        }
+        let mut entry = AuditEntry::new(actor, "unban", &target);
+        entry.room = Some(room.to_string());
+        self.moderation.audit(&entry);
+        Ok(())
+    }
+
+    /// 设置角色：只能修改角色低于自己的用户，新角色也必须低于自己
+    pub fn set_role(&self, actor: &str, room: &str, target: &str, role: Role) -> anyhow::Result<()> {
+        ensure_room(room)?;
+        self.ensure_outranks(room, actor, target)?;
+        if role >= self.moderation.role(room, actor) {
+            anyhow::bail!("不能把 {} 设为{}", target, role.label());
+        }
+        // 没有人认领过的名字谁先登录就归谁，不能授予管理角色
+        if role > Role::Member && !self.accounts.is_registered(target) {
+            anyhow::bail!("{} 还没有登录过，不能设为{}", target, role.label());
+        }
+        self.change_moderation(ModerationChange::SetRole {
+            room: room.to_string(),
+            username: target.to_string(),
+            role,
+        });
+
+        let mut entry = AuditEntry::new(actor, "setRole", target);
+        entry.room = Some(room.to_string());
+        entry.role = Some(role);
+        self.moderation.audit(&entry);
+        self.send_to_user(target, self.room_list(target));
+        self.broadcast_message(ChatMessage::notice(format!("{} 将 {} 设为{}", actor, target, role.label())));
+        Ok(())
+    }
+
+    /// 作者修改自己的消息，编辑后新提及的用户会收到通知
+    pub fn edit_message(&self, username: &str, id: &str, content: &str) -> anyhow::Result<()> {
+        self.ensure_can_post(username)?;
+        let content = validation::sanitize_content(content, self.config.max_message_length)?;
+        let original = self.own_message(username, id)?;
+        let content = &self.filter_content(username, content, Submission::Edit)?;
+        let mut message = original.clone();
+        message.content = content.to_string();
+        message.edited_at = Some(Utc::now());
+        let mentions = self.render(&mut message);
+        self.history.edit(id, content, &message.spans, message.edited_at.unwrap());
+        self.broadcast_event(serde_json::json!({
+            "type": "messageEdited",
+            "id": id,
+            "by": username,
+            "content": content,
+            "spans": message.spans,
+            "editedAt": message.edited_at,
+        }));
+        let already_mentioned = markup::mentions(&original.spans);
+        let new_mentions: Vec<String> = mentions
+            .into_iter()
+            .filter(|user| !already_mentioned.contains(user))
+            .collect();
+        self.notify_mentions(&message, &new_mentions);
+        Ok(())
+    }
+
+    /// 删除消息。作者可以删除自己的消息，管理员可以删除权限更低的用户的消息，并记入审计日志
+    pub fn delete_message(&self, username: &str, id: &str) -> anyhow::Result<()> {
+        let (message, moderated) = self.deletable(username, id)?;
+        if moderated {
+            let mut entry = AuditEntry::new(username, "delete", &message.username);
+            entry.room = Some(DEFAULT_ROOM.to_string());
+            entry.reason = Some(format!("消息 {}", id));
+            self.moderation.audit(&entry);
+        }
+        self.history.delete(id);
+        self.broadcast_event(serde_json::json!({
+            "type": "messageDeleted",
+            "id": id,
+            "by": username,
+        }));
+        if let Some(parent) = message.parent_id.and_then(|parent_id| self.history.get(&parent_id)) {
+            self.broadcast_event(serde_json::json!({
+                "type": "threadUpdated",
+                "id": parent.id,
+                "replyCount": parent.reply_count,
+            }));
+        }
+        Ok(())
+    }
+
+    /// 在话题中回复。回复不进入主聊天流，只推送给话题参与者，其他人只收到回复数更新
+    pub fn post_reply(
+        &self,
+        username: &str,
+        parent_id: &str,
+        content: &str,
+        quote: bool,
+    ) -> anyhow::Result<ChatMessage> {
+        self.ensure_can_post(username)?;
+        if self.history.get(parent_id).is_none() {
+            anyhow::bail!("消息 {} 不存在", parent_id);
+        }
+        let content = validation::sanitize_content(content, self.config.max_message_length)?;
+        let content = self.filter_content(username, content, Submission::Reply { parent_id, quote })?;
+        self.publish_reply(username, parent_id, content, quote)
+    }
+
+    /// 发布已经通过检查的回复
+    fn publish_reply(
+        &self,
+        username: &str,
+        parent_id: &str,
+        content: String,
+        quote: bool,
+    ) -> anyhow::Result<ChatMessage> {
+        let parent = self
+            .history
+            .get(parent_id)
+            .ok_or_else(|| anyhow::anyhow!("消息 {} 不存在", parent_id))?;
+        // 回复的回复归入同一个话题
+        let root_id = parent.parent_id.clone().unwrap_or_else(|| parent.id.clone());
+
+        let mut message = self.new_message(username, content);
+        message.parent_id = Some(root_id.clone());
+        if quote {
+            message.quote = Some(parent.content.chars().take(QUOTE_MAX_CHARS).collect());
+        }
+        let mentions = self.render(&mut message);
+        self.metrics.record_message();
+        self.publish(Payload::Message(Box::new(message.clone())));
+        self.history.append(&mut message);
+
+        let (root, replies) = self.get_thread(&root_id)?;
+        let mut participants: Vec<&str> = replies.iter().map(|m| m.username.as_str()).collect();
+        participants.push(&root.username);
+        participants.sort_unstable();
+        participants.dedup();
+
+        let event = serde_json::json!({
+            "type": "threadReply",
+            "message": message,
+        });
+        for participant in participants {
+            self.send_to_user(participant, event.clone());
+        }
+        self.broadcast_event(serde_json::json!({
+            "type": "threadUpdated",
+            "id": root_id,
+            "replyCount": root.reply_count,
+        }));
+        self.notify_mentions(&message, &mentions);
+        Ok(message)
+    }
+
+    /// 添加或取消表情回应，只有实际发生变化时才广播增量
+    pub fn react(&self, username: &str, id: &str, emoji: &str, added: bool) -> anyhow::Result<()> {
+        self.ensure_can_post(username)?;
+        let emoji = emoji.trim();
+        if emoji.is_empty() || emoji.chars().count() > REACTION_MAX_CHARS {
+            anyhow::bail!("无效的表情");
+        }
+        if self.history.get(id).is_none() {
+            anyhow::bail!("消息 {} 不存在", id);
+        }
+        if let Some(count) = self.history.react(id, emoji, username, added) {
+            self.broadcast_event(serde_json::json!({
+                "type": if added { "reactionAdded" } else { "reactionRemoved" },
+                "id": id,
+                "emoji": emoji,
+                "username": username,
+                "count": count,
+            }));
+        }
+        Ok(())
+    }
+
+    /// 上报已读位置，只会向前推进
+    pub fn mark_read(&self, username: &str, room: &str, id: &str) -> anyhow::Result<()> {
+        if room != DEFAULT_ROOM {
+            anyhow::bail!("房间 {} 不存在", room);
+        }
+        if self.history.get(id).is_none() {
+            anyhow::bail!("消息 {} 不存在", id);
+        }
+        let advanced = self.accounts.update(username, |account| {
+            match account.read_positions.get(room) {
+                Some(last) if last.as_str() >= id => false,
+                _ => {
+                    account.read_positions.insert(room.to_string(), id.to_string());
+                    true
+                }
+            }
+        });
+        if advanced {
+            self.sync_account(username);
+            self.send_to_user(username, self.room_list(username));
+            if self.config.read_receipts {
+                self.broadcast_event(serde_json::json!({
+                    "type": "readReceipt",
+                    "room": room,
+                    "username": username,
+                    "id": id,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// 房间列表及该用户在每个房间的未读数
+    pub fn room_list(&self, username: &str) -> serde_json::Value {
+        let account = self.accounts.get(username);
+        let last_read = account.read_positions.get(DEFAULT_ROOM);
+        serde_json::json!({
+            "type": "roomList",
+            "rooms": [{
+                "id": DEFAULT_ROOM,
+                "unread": self.history.unread_count(last_read.map(|s| s.as_str()), username),
+                "lastReadId": last_read,
+                "muted": account.muted_rooms.contains(DEFAULT_ROOM),
+                "role": self.moderation.role(DEFAULT_ROOM, username),
+            }],
+        })
+    }
+
+    /// 话题的根消息及其全部回复
+    pub fn get_thread(&self, id: &str) -> anyhow::Result<(ChatMessage, Vec<ChatMessage>)> {
+        self.history
+            .thread(id)
+            .ok_or_else(|| anyhow::anyhow!("消息 {} 不存在", id))
+    }
+
+    // 按账号 id 判断归属，同名的新账号不能修改旧账号的消息
+    fn own_message(&self, username: &str, id: &str) -> anyhow::Result<ChatMessage> {
+        let message = self
+            .history
+            .get(id)
+            .ok_or_else(|| anyhow::anyhow!("消息 {} 不存在", id))?;
+        let author = self.accounts.get(username).id;
+        if message.author_id.is_none() || message.author_id != author {
+            anyhow::bail!("只能修改自己发送的消息");
+        }
+        Ok(message)
+    }
+
+    // 删除权限：作者本人，或角色高于作者的管理员。返回消息以及是否属于管理操作
+    fn deletable(&self, username: &str, id: &str) -> anyhow::Result<(ChatMessage, bool)> {
+        match self.own_message(username, id) {
+            Ok(message) => Ok((message, false)),
+            Err(e) => {
+                let Some(message) = self.history.get(id) else { return Err(e) };
+                if self.ensure_outranks(DEFAULT_ROOM, username, &message.username).is_err() {
+                    return Err(e);
+                }
+                Ok((message, true))
+            }
+        }
+    }
+
+    // 以用户的名义新建消息，记下作者的账号 id
+    fn new_message(&self, username: &str, content: String) -> ChatMessage {
+        let mut message = ChatMessage::new(username.to_string(), content);
+        message.author_id = self.accounts.get(username).id;
+        message
+    }
+
     pub fn subscribe(&self) -> broadcast::Receiver<ChatMessage> {
         self.tx.subscribe()
     }
 
-    pub fn register_ws(&self, sender: UnboundedSender<String>) -> usize {
-        let mut websockets = self.websockets.lock().unwrap();
-        websockets.push(sender);
-        websockets.len() - 1
+    /// 与 `subscribe` 配合使用，从当前最新的消息开始跟踪
+    pub fn cursor(&self) -> BroadcastCursor {
+        BroadcastCursor {
+            last_seq: self.history.latest_seq(),
+            resent: BTreeSet::new(),
+        }
     }
 
-    pub fn unregister_ws(&self, id: usize) {
-        let mut websockets = self.websockets.lock().unwrap();
-        if id < websockets.len() {
-            websockets.remove(id);
+    /// 连接在广播通道上落后了 `missed` 条消息。按配置返回需要补发的消息，
+    /// 返回 None 表示应断开该连接
+    pub fn handle_lag(&self, missed: u64, cursor: &mut BroadcastCursor) -> Option<Vec<ChatMessage>> {
+        self.metrics.record_lag(missed);
+        match self.config.lag_policy {
+            LagPolicy::Disconnect => {
+                self.metrics.record_lag_disconnect();
+                tracing::warn!("连接落后 {} 条消息，按配置断开", missed);
+                None
+            }
+            LagPolicy::Resync => {
+                let messages = self.history.messages_after(cursor.last_seq);
+                tracing::warn!("连接落后 {} 条消息，从消息记录补发 {} 条", missed, messages.len());
+                if let Some(last) = messages.last() {
+                    cursor.last_seq = last.seq;
+                }
+                cursor.resent.extend(messages.iter().map(|m| m.seq));
+                Some(messages)
+            }
         }
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// 打开 `username` 的 QUIC 会话。携带该用户有效的旧会话 id 时返回需要补发的消息（按顺序号去重并排序）。
+    /// `cursor` 需在此之前创建，补发的消息记入其中，之后从广播通道收到时跳过
+    pub fn open_session(
+        &self,
+        username: &str,
+        session: Option<&str>,
+        cursor: &mut BroadcastCursor,
+    ) -> (String, Vec<ChatMessage>) {
+        if let Some(id) = session {
+            if let Some((last_acked, pending)) = self.outboxes.resume(id, username) {
+                let mut messages: BTreeMap<u64, ChatMessage> = pending
+                    .into_iter()
+                    .map(|m| (m.seq, m))
+                    .collect();
+                for message in self.history.messages_after(last_acked) {
+                    messages.entry(message.seq).or_insert(message);
+                }
+                cursor.resent.extend(messages.keys().cloned());
+                return (id.to_string(), messages.into_values().collect());
+            }
+        }
+        (self.outboxes.create(username, self.history.latest_seq()), Vec::new())
+    }
+
+    /// 恢复会话前检查：会话仍然存在且属于其他用户时返回错误
+    pub fn check_session(&self, username: &str, session: &str) -> anyhow::Result<()> {
+        match self.outboxes.owner(session) {
+            Some(owner) if owner != username => anyhow::bail!("会话不属于 {}", username),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn track_delivery(&self, session: &str, message: &ChatMessage) {
+        self.outboxes.track(session, message);
+    }
+
+    pub fn ack(&self, session: &str, message_id: &str) {
+        self.outboxes.ack(session, message_id);
+    }
+
+    pub fn close_session(&self, session: &str) {
+        self.outboxes.detach(session);
+    }
+
+    pub fn expire_sessions(&self) {
+        self.outboxes.expire(self.config.outbox_retention);
+    }
+
+    pub fn register_client(&self, transport: Transport) -> (usize, Arc<OutboundQueue>) {
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(OutboundQueue::new(
+            self.config.outbound_queue_size,
+            self.config.overflow_policy,
+        ));
+        let mut clients = self.clients.lock().unwrap();
+        // 与 shutdown_clients 在同一把锁下检查，关闭后注册的连接不会漏掉
+        if self.shutting_down.load(Ordering::Relaxed) {
+            queue.close();
+        }
+        clients.insert(id, Client {
+            queue: queue.clone(),
+            transport,
+            connected_at: Utc::now(),
+            upload_token: None,
+        });
+        (id, queue)
+    }
+
+    pub fn client_ip(&self, id: usize) -> Option<IpAddr> {
+        self.clients.lock().unwrap().get(&id)?.transport.remote_addr().map(|addr| addr.ip())
+    }
+
+    /// 所有在线连接及其统计，按连接 id 排序
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let usernames: HashMap<usize, String> = self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|u| (u.client_id, u.username.clone()))
+            .collect();
+        let clients = self.clients.lock().unwrap();
+        let mut connections: Vec<ConnectionInfo> = clients
+            .iter()
+            .map(|(id, client)| {
+                let mut info = ConnectionInfo {
+                    id: *id,
+                    transport: "websocket",
+                    remote_addr: client.transport.remote_addr(),
+                    username: usernames.get(id).cloned(),
+                    connected_at: client.connected_at,
+                    bytes_sent: 0,
+                    bytes_received: 0,
+                    rtt_ms: None,
+                    cwnd: None,
+                    lost_packets: None,
+                };
+                match &client.transport {
+                    Transport::Quic(connection) => {
+                        let stats = connection.stats();
+                        info.transport = "quic";
+                        info.bytes_sent = stats.udp_tx.bytes;
+                        info.bytes_received = stats.udp_rx.bytes;
+                        info.rtt_ms = Some(stats.path.rtt.as_secs_f64() * 1000.0);
+                        info.cwnd = Some(stats.path.cwnd);
+                        info.lost_packets = Some(stats.path.lost_packets);
+                    }
+                    Transport::WebSocket { traffic, .. } => {
+                        info.bytes_sent = traffic.sent.load(Ordering::Relaxed);
+                        info.bytes_received = traffic.received.load(Ordering::Relaxed);
+                    }
+                }
+                info
+            })
+            .collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+
+    /// Prometheus 格式的运行指标：计数器、按传输方式统计的连接和在线用户，以及每个 QUIC 连接的统计
+    pub fn prometheus(&self) -> String {
+        let connections = self.connections();
+        let mut out = Exposition::default();
+        let by_transport = |logged_in: bool| {
+            ["quic", "websocket"].map(|transport| {
+                let count = connections
+                    .iter()
+                    .filter(|c| c.transport == transport && (!logged_in || c.username.is_some()))
+                    .count();
+                (format!("transport=\"{}\"", transport), count as f64)
+            })
+        };
+        out.metric("chat_connections", "gauge", "当前连接数", by_transport(false));
+        out.metric("chat_users", "gauge", "已登录的连接数", by_transport(true));
+        self.metrics.expose(&mut out);
+
+        // 只输出所有 QUIC 连接的汇总，单个连接的数据由管理接口的 /connections 提供
+        let quic: Vec<&ConnectionInfo> = connections.iter().filter(|c| c.transport == "quic").collect();
+        let rtts: Vec<f64> = quic.iter().filter_map(|c| c.rtt_ms).map(|ms| ms / 1000.0).collect();
+        let cwnds: Vec<f64> = quic.iter().filter_map(|c| c.cwnd).map(|cwnd| cwnd as f64).collect();
+        let average = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+        let stats = |values: &[f64], extreme: &'static str, pick: fn(f64, f64) -> f64| {
+            if values.is_empty() {
+                return Vec::new();
+            }
+            vec![
+                ("stat=\"avg\"".to_string(), average(values)),
+                (format!("stat=\"{}\"", extreme), values.iter().copied().reduce(pick).unwrap_or_default()),
+            ]
+        };
+        out.metric("chat_quic_rtt_seconds", "gauge", "QUIC 连接 RTT 的平均值和最大值", stats(&rtts, "max", f64::max));
+        out.metric("chat_quic_cwnd_bytes", "gauge", "QUIC 连接拥塞窗口的平均值和最小值", stats(&cwnds, "min", f64::min));
+        let sum = |value: fn(&ConnectionInfo) -> u64| [("", quic.iter().map(|c| value(c)).sum::<u64>() as f64)];
+        out.metric(
+            "chat_quic_lost_packets",
+            "gauge",
+            "当前 QUIC 连接丢失的包数之和",
+            sum(|c| c.lost_packets.unwrap_or_default()),
+        );
+        out.metric("chat_quic_sent_bytes", "gauge", "当前 QUIC 连接发送的 UDP 字节数之和", sum(|c| c.bytes_sent));
+        out.metric("chat_quic_received_bytes", "gauge", "当前 QUIC 连接收到的 UDP 字节数之和", sum(|c| c.bytes_received));
+        out.finish()
+    }
+
+    /// 房间及其在线成员，供管理接口使用；隐身用户同样列出
+    pub fn rooms(&self) -> serde_json::Value {
+        let mut members: Vec<serde_json::Value> = self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .map(|u| serde_json::json!({
+                "username": u.username,
+                "status": u.status,
+                "clientId": u.client_id,
+                "role": self.moderation.role(DEFAULT_ROOM, &u.username),
+                "mutedUntil": self.moderation.muted_until(DEFAULT_ROOM, &u.username),
+            }))
+            .collect();
+        members.sort_by(|a, b| a["username"].as_str().cmp(&b["username"].as_str()));
+        serde_json::json!([{
+            "id": DEFAULT_ROOM,
+            "members": members,
+        }])
+    }
+
+    /// 由管理员强制断开一个连接，连接不存在时返回 false
+    pub fn force_disconnect(&self, client_id: usize, reason: Option<&str>) -> bool {
+        if !self.clients.lock().unwrap().contains_key(&client_id) {
+            return false;
+        }
+        tracing::info!("管理员断开连接 {}{}", client_id, reason.map(|r| format!("（{}）", r)).unwrap_or_default());
+        let message = match reason {
+            Some(reason) => format!("连接已被管理员断开：{}", reason),
+            None => "连接已被管理员断开".to_string(),
+        };
+        self.disconnect(client_id, serde_json::json!({
+            "type": "error",
+            "message": message,
+        }));
+        true
+    }
+
+    /// 以 system 的名义向所有人发送服务器公告
+    pub fn announce(&self, content: &str) -> anyhow::Result<ChatMessage> {
+        let content = validation::sanitize_content(content, self.config.max_message_length)?;
+        let mut message = ChatMessage::notice(content);
+        self.render(&mut message);
+        self.broadcast_message(message.clone());
+        Ok(message)
+    }
+
+    /// 检查限流并消耗一个令牌，同时按会话（如有）、用户名和 IP 计数
+    pub fn check_rate(
+        &self,
+        action: Action,
+        client_id: Option<usize>,
+        username: &str,
+        ip: Option<IpAddr>,
+    ) -> Verdict {
+        let keys: Vec<RateKey> = client_id
+            .map(RateKey::Session)
+            .into_iter()
+            .chain([RateKey::User(username.to_string())])
+            .chain(ip.map(RateKey::Ip))
+            .collect();
+        let verdict = self.rate_limits.check(action, &keys);
+        match verdict {
+            Verdict::Allowed => {}
+            Verdict::Throttled(_) => self.metrics.record_rate_limited(),
+            Verdict::Disconnect => {
+                self.metrics.record_rate_limited();
+                self.metrics.record_rate_limit_disconnect();
+                tracing::warn!("{} 多次超过 {} 限流，断开连接", username, action.as_str());
+            }
+        }
+        verdict
+    }
+
+    /// 对已连接的客户端限流：超限时通知客户端，多次超限则断开连接。返回是否放行
+    pub fn enforce_rate(&self, client_id: usize, username: &str, action: Action) -> bool {
+        match self.check_rate(action, Some(client_id), username, self.client_ip(client_id)) {
+            Verdict::Allowed => true,
+            Verdict::Throttled(retry_after) => {
+                self.send_to(client_id, serde_json::json!({
+                    "type": "rateLimited",
+                    "action": action.as_str(),
+                    "retryAfterMs": retry_after.as_millis() as u64,
+                    "message": "操作过于频繁，请稍后再试",
+                }));
+                false
+            }
+            Verdict::Disconnect => {
+                self.disconnect(client_id, serde_json::json!({
+                    "type": "error",
+                    "message": "操作过于频繁，连接已断开",
+                }));
+                false
+            }
+        }
+    }
+
+    /// 发出最后一个事件后断开连接：关闭发送队列，写任务发完剩余内容后退出，读循环随之结束
+    pub fn disconnect(&self, client_id: usize, event: serde_json::Value) {
+        if let Some(client) = self.clients.lock().unwrap().get(&client_id) {
+            self.enqueue(&client.queue, &event);
+            client.queue.close();
+        }
+    }
+
+    /// 关闭服务器前通知所有连接并关闭发送队列，写任务发完剩余内容后退出
+    pub fn shutdown_clients(&self, message: &str) {
+        let clients = self.clients.lock().unwrap();
+        self.shutting_down.store(true, Ordering::Relaxed);
+        tracing::info!("通知 {} 个连接服务器即将关闭", clients.len());
+        let event = serde_json::json!({
+            "type": "shutdown",
+            "message": message,
+        });
+        for client in clients.values() {
+            self.enqueue(&client.queue, &event);
+            client.queue.close();
+        }
+    }
+
+    /// 开始处理一个连接。返回的 guard 应在写任务收尾后才释放
+    pub fn track_connection(&self) -> ConnectionGuard {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.open_connections.clone())
+    }
+
+    /// 是否还接受新的连接，开始关闭后为 false
+    pub fn accepting_connections(&self) -> bool {
+        !self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// 尚未结束的连接数。注销连接后写任务可能仍在发送，关闭服务器时以这个数为准
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    /// 把消息记录写入磁盘
+    pub fn flush(&self) {
+        if let Err(e) = self.history.flush() {
+            tracing::error!("写入消息记录失败: {:?}", e);
+        }
+    }
+
+    /// 定期清理限流记录
+    pub fn sweep_rate_limits(&self) {
+        self.rate_limits.sweep();
+    }
+
+    /// 为已登录的连接生成上传令牌并发给客户端。令牌随连接断开失效
+    pub fn issue_upload_token(&self, id: usize) {
+        let token = crate::accounts::new_token();
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.upload_token = Some(token.clone());
+        }
+        self.send_to(id, serde_json::json!({
+            "type": "uploadToken",
+            "token": token,
+        }));
+    }
+
+    /// 上传令牌所属连接当前登录的用户
+    pub fn upload_user(&self, token: &str) -> Option<String> {
+        let client_id = {
+            let clients = self.clients.lock().unwrap();
+            clients
+                .iter()
+                .find(|(_, client)| {
+                    client
+                        .upload_token
+                        .as_deref()
+                        .is_some_and(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+                })
+                .map(|(id, _)| *id)?
+        };
+        self.username_of(client_id)
+    }
+
+    pub fn unregister_client(&self, id: usize) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// 只发给某一个连接，例如错误提示
+    pub fn send_to(&self, id: usize, event: serde_json::Value) {
+        if let Some(client) = self.clients.lock().unwrap().get(&id) {
+            self.enqueue(&client.queue, &event);
+        }
+    }
+
+    /// 发给指定用户。用户不在本节点时经消息总线转给其他节点
+    pub fn send_to_user(&self, username: &str, event: serde_json::Value) {
+        match self.client_of(username) {
+            Some(id) => self.send_to(id, event),
+            None => self.publish(Payload::Direct {
+                username: username.to_string(),
+                event,
+            }),
+        }
+    }
+
+    fn client_of(&self, username: &str) -> Option<usize> {
+        self.users.lock().unwrap().get(username).map(|u| u.client_id)
+    }
+
+    pub fn send_error(&self, id: usize, message: impl std::fmt::Display) {
+        self.send_to(id, serde_json::json!({
+            "type": "error",
+            "message": message.to_string(),
+        }));
+    }
+
+    /// 发给所有节点的所有连接，不区分传输方式
+    pub fn broadcast_event(&self, event: serde_json::Value) {
+        self.publish(Payload::Event(event.clone()));
+        self.deliver_event(&event);
+    }
+
+    // 只发给本节点的连接
+    fn deliver_event(&self, event: &serde_json::Value) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, client| self.enqueue(&client.queue, event) != Push::Closed);
+    }
+
+    fn enqueue(&self, queue: &OutboundQueue, event: &serde_json::Value) -> Push {
+        let was_closed = queue.is_closed();
+        let result = queue.push(event);
+        match result {
+            Push::Overflowed => self.metrics.record_queue_overflow(),
+            Push::Closed if !was_closed => {
+                self.metrics.record_queue_overflow();
+                self.metrics.record_queue_disconnect();
+                tracing::warn!("连接发送队列已满，断开连接");
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// 把本节点的在线用户发布给其他节点，并向本节点的连接推送合并后的列表
     pub fn broadcast_user_list(&self) {
+        self.publish(Payload::Users(self.local_users()));
+        self.deliver_user_list();
+    }
+
+    fn deliver_user_list(&self) {
         let users = self.get_users();
-        let msg = serde_json::json!({
+        self.deliver_event(&serde_json::json!({
             "type": "userList",
             "users": users
+        }));
+    }
+
+    fn publish(&self, payload: Payload) {
+        self.backplane.publish(&Envelope {
+            node: self.config.node_id.clone(),
+            payload,
+        });
+    }
+
+    /// 订阅消息总线，把其他节点发布的内容推送给本节点的连接，直到总线关闭
+    pub async fn run_backplane(&self) {
+        let mut rx = self.backplane.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(envelope) => self.apply_remote(envelope),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("处理跨节点消息过慢，漏掉 {} 条", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    // 自己发布的内容已经在本地处理过，直接忽略；其他节点的内容只在本地推送，不再转发
+    fn apply_remote(&self, envelope: Envelope) {
+        if envelope.node == self.config.node_id {
+            return;
+        }
+        match envelope.payload {
+            // 回复只写入消息记录，话题参与者由发布回复的节点通知
+            Payload::Message(mut message) if message.parent_id.is_some() => self.history.append(&mut message),
+            Payload::Message(message) => self.append_and_send(*message),
+            Payload::Direct { username, event } => {
+                if let Some(id) = self.client_of(&username) {
+                    self.send_to(id, event);
+                }
+            }
+            Payload::Event(event) => {
+                if self.apply_remote_event(&event) {
+                    self.deliver_event(&event);
+                }
+            }
+            Payload::Account(change) => self.accounts.apply(change),
+            Payload::Moderation(change) => {
+                // 其他节点封禁的用户或 IP 在本节点上的连接同样断开
+                if let ModerationChange::Ban { ban } = &change {
+                    self.disconnect_banned(ban);
+                }
+                self.moderation.apply(change);
+            }
+            Payload::Users(users) => {
+                let joined = {
+                    let mut nodes = self.remote_users.lock().unwrap();
+                    let node = RemoteNode { users, seen_at: Instant::now() };
+                    nodes.insert(envelope.node.clone(), node).is_none()
+                };
+                // 新节点上线时立即回应本节点的用户，不必等下一次心跳
+                if joined {
+                    tracing::info!("节点 {} 已加入", envelope.node);
+                    self.publish(Payload::Users(self.local_users()));
+                }
+                self.deliver_user_list();
+            }
+        }
+    }
+
+    // 其他节点对消息的修改同步到本节点的消息记录，保证补发和查询话题时内容一致。
+    // 编辑和删除按本节点的记录重新检查权限，不通过时返回 false，事件也不再推送
+    fn apply_remote_event(&self, event: &serde_json::Value) -> bool {
+        let id = event.get("id").and_then(|id| id.as_str()).unwrap_or_default();
+        let by = event.get("by").and_then(|b| b.as_str()).unwrap_or_default();
+        match event.get("type").and_then(|t| t.as_str()) {
+            Some("messageEdited") => {
+                if let Err(e) = self.own_message(by, id) {
+                    tracing::warn!("忽略其他节点对消息 {} 的编辑（{}）: {}", id, by, e);
+                    return false;
+                }
+                let content = event.get("content").and_then(|c| c.as_str()).unwrap_or_default();
+                let spans: Vec<Span> = event
+                    .get("spans")
+                    .and_then(|spans| serde_json::from_value(spans.clone()).ok())
+                    .unwrap_or_default();
+                let edited_at = event
+                    .get("editedAt")
+                    .and_then(|t| serde_json::from_value(t.clone()).ok())
+                    .unwrap_or_else(Utc::now);
+                self.history.edit(id, content, &spans, edited_at);
+            }
+            Some("messageDeleted") => {
+                if let Err(e) = self.deletable(by, id) {
+                    tracing::warn!("忽略其他节点对消息 {} 的删除（{}）: {}", id, by, e);
+                    return false;
+                }
+                self.history.delete(id);
+            }
+            Some(kind @ ("reactionAdded" | "reactionRemoved")) => {
+                let emoji = event.get("emoji").and_then(|e| e.as_str()).unwrap_or_default();
+                let username = event.get("username").and_then(|u| u.as_str()).unwrap_or_default();
+                self.history.react(id, emoji, username, kind == "reactionAdded");
+            }
+            Some("messageEnriched") => {
+                let previews: Vec<LinkPreview> = event
+                    .get("previews")
+                    .and_then(|previews| serde_json::from_value(previews.clone()).ok())
+                    .unwrap_or_default();
+                self.history.enrich(id, &previews);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// 定期调用：重新发布本节点的用户作为心跳，并移除长时间没有心跳的节点
+    pub fn sync_nodes(&self) {
+        self.publish(Payload::Users(self.local_users()));
+        let expired: Vec<String> = {
+            let mut nodes = self.remote_users.lock().unwrap();
+            let expired = nodes
+                .iter()
+                .filter(|(_, node)| node.seen_at.elapsed() > NODE_TIMEOUT)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                nodes.remove(id);
+            }
+            expired
+        };
+        if !expired.is_empty() {
+            tracing::warn!("节点 {} 长时间没有心跳，移除其在线用户", expired.join(", "));
+            self.deliver_user_list();
+        }
+    }
+
+    // 把该用户的账号数据同步给其他节点
+    fn sync_account(&self, username: &str) {
+        self.publish(Payload::Account(AccountChange::Put {
+            username: username.to_string(),
+            account: self.accounts.get(username),
+        }));
+    }
+
+    // 修改管理数据并同步给其他节点，返回是否有变化
+    fn change_moderation(&self, change: ModerationChange) -> bool {
+        let changed = self.moderation.apply(change.clone());
+        if changed {
+            self.publish(Payload::Moderation(change));
+        }
+        changed
+    }
+
+    /// 消息总线的状态，供健康检查使用
+    pub fn backplane_status(&self) -> anyhow::Result<String> {
+        let status = self.backplane.status()?;
+        let nodes = self.remote_users.lock().unwrap().len();
+        Ok(format!("{}，节点 {}，其他节点 {} 个", status, self.config.node_id, nodes))
+    }
+}
+
+// 目前只有一个房间
+fn ensure_room(room: &str) -> anyhow::Result<()> {
+    if room != DEFAULT_ROOM {
+        anyhow::bail!("房间 {} 不存在", room);
+    }
+    Ok(())
+}
+
+fn clean_reason(reason: Option<&str>) -> Option<String> {
+    reason
+        .map(|r| validation::sanitize_line(r, MODERATION_REASON_MAX_CHARS))
+        .filter(|r| !r.is_empty())
+}
+
+/// 告诉被封禁的用户原因和解除时间
+fn ban_notice(ban: &Ban) -> String {
+    let mut notice = "你已被封禁".to_string();
+    if let Some(reason) = &ban.reason {
+        notice += &format!("（{}）", reason);
+    }
+    if let Some(expires_at) = ban.expires_at {
+        notice += &format!("，{} 解除", expires_at.format("%Y-%m-%d %H:%M:%S UTC"));
+    }
+    notice
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // 独立数据目录的节点，olive 是配置的所有者
+    pub(crate) fn state(dir: &std::path::Path) -> ChatState {
+        let mut config = Config::from_env();
+        config.data_dir = dir.to_path_buf();
+        config.owners = BTreeMap::from([("olive".to_string(), "secret".to_string())]);
+        ChatState::new(
+            config.clone(),
+            HistoryStore::open(dir.join("history.jsonl")).unwrap(),
+            AccountStore::open(dir.join("accounts.json")).unwrap(),
+            BlobStore::open(dir.join("blobs"), config.max_upload_size, 1).unwrap(),
+            ModerationStore::open(dir.join("moderation.json"), dir.join("audit.jsonl"), vec!["olive".to_string()]).unwrap(),
+            FilterChain::new(dir.join("filter_rules.txt"), Vec::new()),
+            None,
+        )
+    }
+
+    pub(crate) fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("quic_chat_state_{}", ulid::Ulid::new()))
+    }
+
+    fn login(state: &ChatState, username: &str, ip: &str) -> Arc<OutboundQueue> {
+        let (id, queue) = state.register_client(Transport::WebSocket {
+            addr: Some(SocketAddr::new(ip.parse().unwrap(), 40000)),
+            traffic: Arc::new(Traffic::default()),
         });
-        let msg_str = serde_json::to_string(&msg).unwrap();
-        let mut websockets = self.websockets.lock().unwrap();
-        websockets.retain(|tx| tx.send(msg_str.clone()).is_ok());
+        let token = (username == "olive").then_some("secret");
+        state.add_user(username.to_string(), id, token).unwrap();
+        queue
     }
-}
\ No newline at end of file
+
+    // 取出队列中已有的事件
+    fn drain(queue: &OutboundQueue) -> Vec<serde_json::Value> {
+        let mut events = Vec::new();
+        while let Some(Some(line)) = futures::FutureExt::now_or_never(queue.pop()) {
+            events.push(serde_json::from_str(&line).unwrap());
+        }
+        events
+    }
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn moderators_only_act_on_lower_roles() {
+        let dir = temp_dir();
+        let state = state(&dir);
+        for name in ["olive", "mod1", "mod2", "carol"] {
+            login(&state, name, "10.0.0.1");
+        }
+        state.set_role("olive", DEFAULT_ROOM, "mod1", Role::Moderator).unwrap();
+        state.set_role("olive", DEFAULT_ROOM, "mod2", Role::Moderator).unwrap();
+
+        // 管理员不能处理所有者和其他管理员
+        assert!(state.mute_user("mod1", DEFAULT_ROOM, "olive", MINUTE, None).is_err());
+        assert!(state.mute_user("mod1", DEFAULT_ROOM, "mod2", MINUTE, None).is_err());
+        assert!(state.kick("mod1", DEFAULT_ROOM, "mod2", None).is_err());
+        assert!(state.set_role("mod1", DEFAULT_ROOM, "mod2", Role::Member).is_err());
+        assert!(state.ban("mod1", DEFAULT_ROOM, BanTarget::User("olive".to_string()), None, None).is_err());
+        // 也不能任命和自己同级的角色
+        assert!(state.set_role("mod1", DEFAULT_ROOM, "carol", Role::Moderator).is_err());
+        // 普通成员没有管理权限
+        assert!(state.mute_user("carol", DEFAULT_ROOM, "mod1", MINUTE, None).is_err());
+
+        state.mute_user("mod1", DEFAULT_ROOM, "carol", MINUTE, None).unwrap();
+        assert!(state.moderation.muted_until(DEFAULT_ROOM, "carol").is_some());
+        state.set_role("olive", DEFAULT_ROOM, "mod2", Role::Member).unwrap();
+        state.mute_user("mod1", DEFAULT_ROOM, "mod2", MINUTE, None).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ip_bans_block_login() {
+        let dir = temp_dir();
+        let state = state(&dir);
+        login(&state, "olive", "10.0.0.1");
+        let mallory = login(&state, "mallory", "10.0.0.5");
+        let ip: IpAddr = "10.0.0.5".parse().unwrap();
+
+        state.ban("olive", DEFAULT_ROOM, BanTarget::Ip(ip), Some(MINUTE), Some("spam")).unwrap();
+        // 该 IP 上的连接被断开，换名字也不能再登录
+        assert!(mallory.is_closed());
+        let error = state.check_join("other", Some(ip)).unwrap_err();
+        assert!(error.to_string().contains("spam"), "{}", error);
+        assert!(state.check_join("other", Some("10.0.0.6".parse().unwrap())).is_ok());
+
+        state.unban("olive", DEFAULT_ROOM, BanTarget::Ip(ip)).unwrap();
+        assert!(state.check_join("other", Some(ip)).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn roles_and_mutes_follow_renames() {
+        let dir = temp_dir();
+        let state = state(&dir);
+        for name in ["olive", "mod1", "carol"] {
+            login(&state, name, "10.0.0.1");
+        }
+        state.set_role("olive", DEFAULT_ROOM, "mod1", Role::Moderator).unwrap();
+        state.mute_user("olive", DEFAULT_ROOM, "carol", MINUTE, None).unwrap();
+
+        state.rename_user("mod1", "mod9").unwrap();
+        assert_eq!(state.moderation.role(DEFAULT_ROOM, "mod9"), Role::Moderator);
+        assert_eq!(state.moderation.role(DEFAULT_ROOM, "mod1"), Role::Member);
+        assert!(state.mute_user("mod9", DEFAULT_ROOM, "olive", MINUTE, None).is_err());
+
+        // 改名不能绕过禁言
+        state.rename_user("carol", "carol2").unwrap();
+        assert!(state.moderation.muted_until(DEFAULT_ROOM, "carol2").is_some());
+        assert!(state.moderation.muted_until(DEFAULT_ROOM, "carol").is_none());
+        // 改回原名时记录一起带回，不能改成所有者或别人用过的名字
+        state.rename_user("carol2", "carol").unwrap();
+        assert!(state.moderation.muted_until(DEFAULT_ROOM, "carol").is_some());
+        assert!(state.rename_user("carol", "olive").is_err());
+        assert!(state.rename_user("carol", "mod9").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replies_count_as_messages() {
+        let dir = temp_dir();
+        let state = state(&dir);
+        login(&state, "carol", "10.0.0.1");
+        let root = state.post_message("carol", "hello").unwrap();
+        state.post_reply("carol", &root.id, "reply", false).unwrap();
+        let metrics = state.prometheus();
+        // 消息和回复各计一次
+        assert!(metrics.contains("\nchat_messages_total 2\n"), "{}", metrics);
+        assert!(!metrics.contains("connection="), "{}", metrics);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invisible_renames_are_not_announced() {
+        let dir = temp_dir();
+        let state = state(&dir);
+        let carol = login(&state, "carol", "10.0.0.1");
+        let dave = login(&state, "dave", "10.0.0.2");
+        let mut rx = state.subscribe();
+        state.set_status("carol", UserStatus::Invisible, None).unwrap();
+        drain(&carol);
+        drain(&dave);
+
+        state.rename_user("carol", "carol2").unwrap();
+        let own = drain(&carol);
+        assert!(own.iter().any(|event| event["type"] == "userRenamed" && event["newUsername"] == "carol2"));
+        let others = drain(&dave);
+        assert!(others.iter().all(|event| event["type"] != "userRenamed"), "{:?}", others);
+        assert!(rx.try_recv().is_err());
+        assert!(!state.get_users().iter().any(|u| u.username == "carol2"));
+
+        // 可见时照常公告
+        state.set_status("carol2", UserStatus::Online, None).unwrap();
+        drain(&dave);
+        state.rename_user("carol2", "carol3").unwrap();
+        assert!(drain(&dave).iter().any(|event| event["type"] == "userRenamed"));
+        assert!(rx.try_recv().is_ok_and(|message| message.content.contains("carol3")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
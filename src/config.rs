@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::ratelimit::Budget;
+use crate::telemetry::LogFormat;
+
+/// 连接跟不上广播速度时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    // 通知客户端并从消息记录补发
+    Resync,
+    // 直接断开连接
+    Disconnect,
+}
+
+impl FromStr for LagPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "resync" => Ok(LagPolicy::Resync),
+            "disconnect" => Ok(LagPolicy::Disconnect),
+            _ => anyhow::bail!("未知的滞后处理方式: {}", s),
+        }
+    }
+}
+
+/// 连接发送队列已满时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // 丢弃最旧的一条
+    DropOldest,
+    // 断开连接
+    Disconnect,
+    // 合并用户列表、在线状态等状态类事件，无法合并时断开
+    Coalesce,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            _ => anyhow::bail!("未知的队列溢出处理方式: {}", s),
+        }
+    }
+}
+
+/// 服务器配置，均可通过环境变量覆盖
+#[derive(Debug, Clone)]
+pub struct Config {
+    // QUIC 与 WebSocket（含 HTTP 接口）的监听地址，同一台机器上运行多个节点时需要修改
+    pub quic_addr: SocketAddr,
+    pub ws_addr: SocketAddr,
+    // 超过该时长没有活动的用户会被自动标记为离开
+    pub idle_timeout: Duration,
+    // 消息记录等持久化数据的存放目录
+    pub data_dir: PathBuf,
+    // 是否向所有人广播“已读”回执
+    pub read_receipts: bool,
+    // QUIC 会话断开后保留发件箱的时长，在此期间重连可以补发消息
+    pub outbox_retention: Duration,
+    pub lag_policy: LagPolicy,
+    // 每个连接发送队列的容量
+    pub outbound_queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    // 消息内容和用户名的最大字符数
+    pub max_message_length: usize,
+    pub max_username_length: usize,
+    // 上传文件的最大字节数
+    pub max_upload_size: u64,
+    // 同时生成缩略图的最大数量
+    pub thumbnail_workers: usize,
+    // 是否抓取消息中链接的预览，以及抓取的超时和页面大小上限
+    pub link_previews: bool,
+    pub preview_timeout: Duration,
+    pub preview_max_bytes: usize,
+    // 发消息、登录和上传文件的限流参数，按会话、用户名和 IP 分别计数
+    pub message_rate: Budget,
+    pub join_rate: Budget,
+    pub upload_rate: Budget,
+    // 一分钟内超限多少次后断开连接
+    pub rate_limit_strikes: u32,
+    // 总连接数和每个 IP 的连接数上限（QUIC 与 WebSocket 合计）
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    // 每个 QUIC 连接同时打开的双向流和单向流上限
+    pub max_streams_per_connection: u32,
+    // QUIC 连接数达到该值后要求客户端先验证地址（Retry）
+    pub retry_threshold: usize,
+    // QUIC 连接空闲超时
+    pub quic_idle_timeout: Duration,
+    // 所有房间的所有者（用户名 -> 登录令牌），可以任免管理员。
+    // 所有者的名字不会自动生成令牌，只能用配置的令牌登录
+    pub owners: BTreeMap<String, String>,
+    // 消息过滤规则文件，修改后自动重新加载
+    pub filter_rules: PathBuf,
+    // 管理接口的监听地址和访问令牌，未设置令牌时不启动管理接口
+    pub admin_addr: SocketAddr,
+    pub admin_token: Option<String>,
+    // 日志输出格式，以及导出链路追踪的 OTLP/HTTP 地址（为空时不导出）
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+    // 收到 SIGINT/SIGTERM 后等待连接关闭的最长时间
+    pub shutdown_timeout: Duration,
+    // 证书剩余有效期少于该值时健康检查报告警告
+    pub cert_warn_before: Duration,
+    // 本节点的 id，用于在节点之间区分消息来源，默认每次启动随机生成
+    pub node_id: String,
+    // 节点之间的消息总线（`redis://` 地址）和频道，未设置地址时只在本进程内广播
+    pub backplane_url: Option<String>,
+    pub backplane_channel: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let max_connections = env_or("CHAT_MAX_CONNECTIONS", 1000);
+        let data_dir: PathBuf = env_or("CHAT_DATA_DIR", PathBuf::from("data"));
+        Self {
+            quic_addr: env_or("CHAT_QUIC_ADDR", SocketAddr::from(([0, 0, 0, 0], 4433))),
+            ws_addr: env_or("CHAT_WS_ADDR", SocketAddr::from(([127, 0, 0, 1], 8080))),
+            // 为 0 时检查空闲的定时器无法创建，至少 1 秒
+            idle_timeout: Duration::from_secs(env_or("CHAT_IDLE_TIMEOUT_SECS", 300).max(1)),
+            data_dir: data_dir.clone(),
+            read_receipts: env_or("CHAT_READ_RECEIPTS", true),
+            outbox_retention: Duration::from_secs(env_or("CHAT_OUTBOX_RETENTION_SECS", 300)),
+            lag_policy: env_or("CHAT_LAG_POLICY", LagPolicy::Resync),
+            outbound_queue_size: env_or("CHAT_OUTBOUND_QUEUE_SIZE", 256),
+            overflow_policy: env_or("CHAT_OVERFLOW_POLICY", OverflowPolicy::Coalesce),
+            max_message_length: env_or("CHAT_MAX_MESSAGE_LENGTH", 4000),
+            max_username_length: env_or("CHAT_MAX_USERNAME_LENGTH", 32),
+            max_upload_size: env_or("CHAT_MAX_UPLOAD_BYTES", 10 * 1024 * 1024),
+            thumbnail_workers: env_or("CHAT_THUMBNAIL_WORKERS", 2),
+            link_previews: env_or("CHAT_LINK_PREVIEWS", true),
+            preview_timeout: Duration::from_secs(env_or("CHAT_PREVIEW_TIMEOUT_SECS", 5)),
+            preview_max_bytes: env_or("CHAT_PREVIEW_MAX_BYTES", 256 * 1024),
+            message_rate: env_or("CHAT_MESSAGE_RATE", Budget::new(1.0, 10.0)),
+            join_rate: env_or("CHAT_JOIN_RATE", Budget::new(0.2, 5.0)),
+            upload_rate: env_or("CHAT_UPLOAD_RATE", Budget::new(0.1, 5.0)),
+            rate_limit_strikes: env_or("CHAT_RATE_LIMIT_STRIKES", 10),
+            max_connections,
+            max_connections_per_ip: env_or("CHAT_MAX_CONNECTIONS_PER_IP", 16),
+            max_streams_per_connection: env_or("CHAT_MAX_STREAMS_PER_CONNECTION", 8),
+            retry_threshold: env_or("CHAT_RETRY_THRESHOLD", max_connections / 2),
+            quic_idle_timeout: Duration::from_secs(env_or("CHAT_QUIC_IDLE_TIMEOUT_SECS", 30)),
+            owners: std::env::var("CHAT_OWNERS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|owner| owner.split_once(':'))
+                .map(|(name, token)| (name.trim().to_string(), token.trim().to_string()))
+                .filter(|(name, token)| !name.is_empty() && !token.is_empty())
+                .collect(),
+            filter_rules: env_or("CHAT_FILTER_RULES", data_dir.join("filter_rules.txt")),
+            admin_addr: env_or("CHAT_ADMIN_ADDR", SocketAddr::from(([127, 0, 0, 1], 8081))),
+            admin_token: std::env::var("CHAT_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            log_format: env_or("CHAT_LOG_FORMAT", LogFormat::Text),
+            otlp_endpoint: std::env::var("CHAT_OTLP_ENDPOINT").ok().filter(|endpoint| !endpoint.is_empty()),
+            shutdown_timeout: Duration::from_secs(env_or("CHAT_SHUTDOWN_TIMEOUT_SECS", 10)),
+            cert_warn_before: Duration::from_secs(env_or("CHAT_CERT_WARN_DAYS", 14) * 24 * 60 * 60),
+            node_id: std::env::var("CHAT_NODE_ID")
+                .ok()
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(|| ulid::Ulid::new().to_string()),
+            backplane_url: std::env::var("CHAT_BACKPLANE_URL").ok().filter(|url| !url.is_empty()),
+            backplane_channel: env_or("CHAT_BACKPLANE_CHANNEL", "quic_chat".to_string()),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,482 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use crate::moderation::Role;
+
+// 默认的重复消息检测窗口
+const DUPLICATE_WINDOW_SECS: u64 = 30;
+// 每个用户最多记住的最近消息数
+const DUPLICATE_HISTORY: usize = 20;
+// 第一次登录后多少分钟内算作新用户，以及新用户每条消息允许的链接数
+const NEW_USER_MINUTES: i64 = 10;
+const NEW_USER_MAX_LINKS: usize = 1;
+
+/// 过滤器对一条消息的处理结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterAction {
+    Allow,
+    // 用改写后的内容继续
+    Rewrite(String),
+    // 交给管理员审核，附带原因
+    Hold(String),
+    // 拒绝发送，附带原因
+    Reject(String),
+}
+
+/// 待检查的消息
+pub struct FilterContext<'a> {
+    pub username: &'a str,
+    pub content: &'a str,
+    pub role: Role,
+    // 用户第一次登录的时间，用于识别新用户
+    pub first_seen: Option<DateTime<Utc>>,
+}
+
+/// 消息过滤器。实现这个 trait 并加入过滤链即可添加自定义规则
+pub trait MessageFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn check(&self, message: &FilterContext) -> FilterAction;
+    /// 整条过滤链放行后调用，`message` 是这个过滤器检查时看到的内容
+    fn accepted(&self, _message: &FilterContext) {}
+    /// 规则文件（重新）加载后调用，从中读取自己的参数
+    fn reload(&self, _rules: &RuleFile) {}
+}
+
+/// 词表规则的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordAction {
+    // 用 * 替换匹配的部分
+    Mask,
+    Reject,
+    Hold,
+}
+
+/// 规则文件，每行一条，`#` 开头为注释：
+///
+/// ```text
+/// # 词表规则：mask、reject 或 hold 后接正则表达式
+/// mask (?i)badword
+/// # 其他过滤器的参数：名字后接值
+/// duplicate_window_secs 30
+/// ```
+#[derive(Default)]
+pub struct RuleFile {
+    pub words: Vec<(WordAction, Regex)>,
+    settings: HashMap<String, String>,
+}
+
+impl RuleFile {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = RuleFile::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            let action = match key {
+                "mask" => Some(WordAction::Mask),
+                "reject" => Some(WordAction::Reject),
+                "hold" => Some(WordAction::Hold),
+                _ => None,
+            };
+            match action {
+                Some(action) => {
+                    let regex = Regex::new(value).with_context(|| format!("第 {} 行的正则表达式无效", number + 1))?;
+                    rules.words.push((action, regex));
+                }
+                None => {
+                    rules.settings.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    /// 读取参数，没有或无法解析时使用默认值
+    pub fn setting<T: FromStr>(&self, key: &str, default: T) -> T {
+        self.settings
+            .get(key)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    }
+}
+
+/// 按顺序运行的过滤链：改写的内容传给下一个过滤器，审核或拒绝时立即停止。
+/// 规则文件修改后可以在运行时重新加载
+pub struct FilterChain {
+    path: PathBuf,
+    filters: Vec<Box<dyn MessageFilter>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl FilterChain {
+    pub fn new(path: impl AsRef<Path>, filters: Vec<Box<dyn MessageFilter>>) -> Self {
+        let chain = Self {
+            path: path.as_ref().to_path_buf(),
+            filters,
+            modified: Mutex::new(None),
+        };
+        chain.reload_if_changed();
+        chain
+    }
+
+    pub fn run(&self, message: &FilterContext) -> FilterAction {
+        let mut content = None;
+        // 每个过滤器看到的内容，放行后交给 accepted
+        let mut seen = Vec::with_capacity(self.filters.len());
+        for filter in &self.filters {
+            let current = content.clone().unwrap_or_else(|| message.content.to_string());
+            let action = filter.check(&FilterContext {
+                content: &current,
+                ..*message
+            });
+            match action {
+                FilterAction::Allow => {}
+                FilterAction::Rewrite(rewritten) => content = Some(rewritten),
+                action => {
+                    tracing::info!("{} 的消息被过滤器 {} 拦截: {:?}", message.username, filter.name(), action);
+                    return action;
+                }
+            }
+            seen.push(current);
+        }
+        for (filter, current) in self.filters.iter().zip(&seen) {
+            filter.accepted(&FilterContext {
+                content: current,
+                ..*message
+            });
+        }
+        content.map_or(FilterAction::Allow, FilterAction::Rewrite)
+    }
+
+    /// 规则文件的修改时间变化时重新加载；文件有误时保留原来的规则
+    pub fn reload_if_changed(&self) {
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        let mut last = self.modified.lock().unwrap();
+        if *last == modified && last.is_some() {
+            return;
+        }
+        let rules = match modified {
+            Some(_) => std::fs::read_to_string(&self.path)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| RuleFile::parse(&text)),
+            // 没有规则文件时各过滤器使用默认参数
+            None => Ok(RuleFile::default()),
+        };
+        match rules {
+            Ok(rules) => {
+                for filter in &self.filters {
+                    filter.reload(&rules);
+                }
+                if modified.is_some() {
+                    tracing::info!("已加载过滤规则 {}（词表 {} 条）", self.path.display(), rules.words.len());
+                }
+            }
+            Err(e) => tracing::error!("加载过滤规则 {} 失败: {:?}", self.path.display(), e),
+        }
+        *last = modified;
+    }
+}
+
+/// 内置的过滤器：词表、重复消息、新用户链接限制
+pub fn default_filters() -> Vec<Box<dyn MessageFilter>> {
+    vec![
+        Box::new(WordListFilter::default()),
+        Box::new(DuplicateFilter::default()),
+        Box::new(LinkLimitFilter::default()),
+    ]
+}
+
+/// 正则词表
+#[derive(Default)]
+pub struct WordListFilter {
+    words: ArcSwap<Vec<(WordAction, Regex)>>,
+}
+
+impl MessageFilter for WordListFilter {
+    fn name(&self) -> &'static str {
+        "words"
+    }
+
+    fn check(&self, message: &FilterContext) -> FilterAction {
+        let words = self.words.load();
+        let mut content = message.content.to_string();
+        for (action, regex) in words.iter() {
+            if !regex.is_match(&content) {
+                continue;
+            }
+            match action {
+                WordAction::Mask => {
+                    content = regex
+                        .replace_all(&content, |caps: &regex::Captures| "*".repeat(caps[0].chars().count()))
+                        .into_owned();
+                }
+                WordAction::Reject => return FilterAction::Reject("消息包含不允许的内容".to_string()),
+                WordAction::Hold => return FilterAction::Hold("消息包含需要审核的内容".to_string()),
+            }
+        }
+        if content == message.content {
+            FilterAction::Allow
+        } else {
+            FilterAction::Rewrite(content)
+        }
+    }
+
+    fn reload(&self, rules: &RuleFile) {
+        self.words.store(rules.words.clone().into());
+    }
+}
+
+/// 拒绝同一用户在短时间内重复发送的相同内容。只记录整条过滤链放行的消息
+pub struct DuplicateFilter {
+    window_secs: ArcSwap<u64>,
+    recent: Mutex<HashMap<String, VecDeque<(Instant, String)>>>,
+}
+
+impl Default for DuplicateFilter {
+    fn default() -> Self {
+        Self {
+            window_secs: ArcSwap::from_pointee(DUPLICATE_WINDOW_SECS),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl MessageFilter for DuplicateFilter {
+    fn name(&self) -> &'static str {
+        "duplicate"
+    }
+
+    fn check(&self, message: &FilterContext) -> FilterAction {
+        let window = std::time::Duration::from_secs(**self.window_secs.load());
+        if window.is_zero() {
+            return FilterAction::Allow;
+        }
+        let normalized = message.content.trim().to_lowercase();
+        let mut recent = self.recent.lock().unwrap();
+        // 顺便清理其他用户的过期记录
+        recent.retain(|_, sent| {
+            sent.retain(|(at, _)| at.elapsed() < window);
+            !sent.is_empty()
+        });
+        let duplicate = recent
+            .get(message.username)
+            .is_some_and(|sent| sent.iter().any(|(_, content)| *content == normalized));
+        if duplicate {
+            return FilterAction::Reject("请勿重复发送相同的内容".to_string());
+        }
+        FilterAction::Allow
+    }
+
+    fn accepted(&self, message: &FilterContext) {
+        if **self.window_secs.load() == 0 {
+            return;
+        }
+        let mut recent = self.recent.lock().unwrap();
+        let sent = recent.entry(message.username.to_string()).or_default();
+        sent.push_back((Instant::now(), message.content.trim().to_lowercase()));
+        if sent.len() > DUPLICATE_HISTORY {
+            sent.pop_front();
+        }
+    }
+
+    fn reload(&self, rules: &RuleFile) {
+        self.window_secs
+            .store(rules.setting("duplicate_window_secs", DUPLICATE_WINDOW_SECS).into());
+    }
+}
+
+/// 新用户的消息中链接过多时交给管理员审核
+pub struct LinkLimitFilter {
+    // （新用户的时限（分钟），每条消息允许的链接数）
+    limits: ArcSwap<(i64, usize)>,
+}
+
+impl Default for LinkLimitFilter {
+    fn default() -> Self {
+        Self {
+            limits: ArcSwap::from_pointee((NEW_USER_MINUTES, NEW_USER_MAX_LINKS)),
+        }
+    }
+}
+
+impl MessageFilter for LinkLimitFilter {
+    fn name(&self) -> &'static str {
+        "links"
+    }
+
+    fn check(&self, message: &FilterContext) -> FilterAction {
+        let (minutes, max_links) = **self.limits.load();
+        let is_new = message.role <= Role::Member
+            && message
+                .first_seen
+                .is_none_or(|first_seen| Utc::now() - first_seen < chrono::Duration::minutes(minutes));
+        if !is_new {
+            return FilterAction::Allow;
+        }
+        let lower = message.content.to_lowercase();
+        let links = lower.matches("http://").count() + lower.matches("https://").count();
+        if links > max_links {
+            return FilterAction::Hold(format!("新用户每条消息最多包含 {} 个链接", max_links));
+        }
+        FilterAction::Allow
+    }
+
+    fn reload(&self, rules: &RuleFile) {
+        self.limits.store(
+            (
+                rules.setting("new_user_minutes", NEW_USER_MINUTES),
+                rules.setting("new_user_max_links", NEW_USER_MAX_LINKS),
+            )
+                .into(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 规则写到临时文件里，和运行时一样经过 reload_if_changed 加载
+    fn chain(rules: &str, filters: Vec<Box<dyn MessageFilter>>) -> FilterChain {
+        let path = std::env::temp_dir().join(format!("quic_chat_filters_{}.txt", ulid::Ulid::new()));
+        std::fs::write(&path, rules).unwrap();
+        let chain = FilterChain::new(&path, filters);
+        std::fs::remove_file(&path).unwrap();
+        chain
+    }
+
+    fn message<'a>(username: &'a str, content: &'a str) -> FilterContext<'a> {
+        FilterContext {
+            username,
+            content,
+            role: Role::Member,
+            first_seen: Some(Utc::now() - chrono::Duration::days(1)),
+        }
+    }
+
+    // 总是拒绝的过滤器，用来模拟排在重复检测后面的规则
+    struct RejectAll;
+
+    impl MessageFilter for RejectAll {
+        fn name(&self) -> &'static str {
+            "reject_all"
+        }
+
+        fn check(&self, _message: &FilterContext) -> FilterAction {
+            FilterAction::Reject("no".to_string())
+        }
+    }
+
+    #[test]
+    fn parses_rule_files() {
+        let rules = RuleFile::parse("# 注释\n\nmask (?i)bad\n  reject ^spam$ \nhold x+\nduplicate_window_secs 5\nnew_user_max_links oops\n")
+            .unwrap();
+        let actions: Vec<_> = rules.words.iter().map(|(action, _)| *action).collect();
+        assert_eq!(actions, vec![WordAction::Mask, WordAction::Reject, WordAction::Hold]);
+        assert_eq!(rules.words[1].1.as_str(), "^spam$");
+        assert_eq!(rules.setting("duplicate_window_secs", 30u64), 5);
+        // 无法解析或没有的参数使用默认值
+        assert_eq!(rules.setting("new_user_max_links", 1usize), 1);
+        assert_eq!(rules.setting("missing", 7i64), 7);
+
+        let error = RuleFile::parse("mask ok\nreject (unclosed").err().unwrap();
+        assert!(error.to_string().contains("第 2 行"), "{}", error);
+    }
+
+    #[test]
+    fn word_list_masks_rejects_and_holds() {
+        let chain = chain(
+            "mask (?i)darn\nreject forbidden\nhold review",
+            vec![Box::new(WordListFilter::default())],
+        );
+        assert_eq!(chain.run(&message("a", "hello")), FilterAction::Allow);
+        assert_eq!(
+            chain.run(&message("a", "DARN it, 该死darn")),
+            FilterAction::Rewrite("**** it, 该死****".to_string())
+        );
+        assert!(matches!(chain.run(&message("a", "darn forbidden")), FilterAction::Reject(_)));
+        assert!(matches!(chain.run(&message("a", "please review")), FilterAction::Hold(_)));
+    }
+
+    #[test]
+    fn duplicates_are_rejected_within_the_window() {
+        let chain = chain("duplicate_window_secs 30", vec![Box::new(DuplicateFilter::default())]);
+        assert_eq!(chain.run(&message("a", "Hello")), FilterAction::Allow);
+        // 忽略大小写和首尾空白
+        assert!(matches!(chain.run(&message("a", " hello ")), FilterAction::Reject(_)));
+        // 其他用户不受影响
+        assert_eq!(chain.run(&message("b", "hello")), FilterAction::Allow);
+        assert_eq!(chain.run(&message("a", "hello again")), FilterAction::Allow);
+
+        let chain = self::chain("duplicate_window_secs 0", vec![Box::new(DuplicateFilter::default())]);
+        assert_eq!(chain.run(&message("a", "hello")), FilterAction::Allow);
+        assert_eq!(chain.run(&message("a", "hello")), FilterAction::Allow);
+    }
+
+    #[test]
+    fn duplicate_window_expires() {
+        let filter = DuplicateFilter::default();
+        filter.accepted(&message("a", "hello"));
+        assert!(matches!(filter.check(&message("a", "hello")), FilterAction::Reject(_)));
+        for sent in filter.recent.lock().unwrap().values_mut() {
+            for (at, _) in sent.iter_mut() {
+                *at = at.checked_sub(std::time::Duration::from_secs(DUPLICATE_WINDOW_SECS)).unwrap();
+            }
+        }
+        assert_eq!(filter.check(&message("a", "hello")), FilterAction::Allow);
+        assert!(filter.recent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_accepted_messages_count_as_duplicates() {
+        let chain = chain("", vec![Box::new(DuplicateFilter::default()), Box::new(RejectAll)]);
+        assert!(matches!(chain.run(&message("a", "hello")), FilterAction::Reject(reason) if reason == "no"));
+        assert!(matches!(chain.run(&message("a", "hello")), FilterAction::Reject(reason) if reason == "no"));
+
+        // 被送去审核的消息也不算发送过
+        let chain = self::chain("", default_filters());
+        let new_user = FilterContext {
+            first_seen: None,
+            ..message("a", "http://a.example http://b.example")
+        };
+        assert!(matches!(chain.run(&new_user), FilterAction::Hold(_)));
+        assert!(matches!(chain.run(&new_user), FilterAction::Hold(_)));
+        assert_eq!(chain.run(&message("a", "http://a.example http://b.example")), FilterAction::Allow);
+        assert!(matches!(
+            chain.run(&message("a", "http://a.example http://b.example")),
+            FilterAction::Reject(_)
+        ));
+    }
+
+    #[test]
+    fn new_users_are_limited_to_few_links() {
+        let chain = chain("new_user_minutes 10\nnew_user_max_links 1", vec![Box::new(LinkLimitFilter::default())]);
+        let content = "see HTTPS://a.example and http://b.example";
+        let joined = |minutes: i64, role: Role| FilterContext {
+            role,
+            first_seen: Some(Utc::now() - chrono::Duration::minutes(minutes)),
+            ..message("a", content)
+        };
+        assert!(matches!(chain.run(&joined(1, Role::Member)), FilterAction::Hold(_)));
+        let one_link = FilterContext {
+            content: "one http://a.example",
+            ..joined(1, Role::Member)
+        };
+        assert_eq!(chain.run(&one_link), FilterAction::Allow);
+        assert_eq!(chain.run(&joined(11, Role::Member)), FilterAction::Allow);
+        assert_eq!(chain.run(&joined(1, Role::Moderator)), FilterAction::Allow);
+        assert!(matches!(
+            chain.run(&FilterContext { first_seen: None, ..message("a", content) }),
+            FilterAction::Hold(_)
+        ));
+    }
+}
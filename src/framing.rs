@@ -0,0 +1,111 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// 按行读取 QUIC 流，限制单行长度并严格检查 UTF-8。
+/// 读到一半的内容保存在结构体中，可以安全地用在 `select!` 里
+pub struct LineReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    max_bytes: usize,
+    // 当前行已超长，丢弃到下一个换行为止
+    too_long: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    pub fn new(reader: R, max_bytes: usize) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            max_bytes,
+            too_long: false,
+        }
+    }
+
+    /// 读取下一行（不含换行符）。流结束时返回 None；
+    /// 超长或不是有效 UTF-8 的行返回 Err 说明，之后仍可继续读取
+    pub async fn next_line(&mut self) -> std::io::Result<Option<Result<String, String>>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if self.buf.is_empty() && !self.too_long {
+                    return Ok(None);
+                }
+                // 最后一行没有换行符
+                return Ok(Some(self.take_line()));
+            }
+            let newline = available.iter().position(|&b| b == b'\n');
+            let chunk = &available[..newline.unwrap_or(available.len())];
+            if !self.too_long {
+                if self.buf.len() + chunk.len() > self.max_bytes {
+                    self.too_long = true;
+                    self.buf.clear();
+                } else {
+                    self.buf.extend_from_slice(chunk);
+                }
+            }
+            let consumed = newline.map_or(available.len(), |pos| pos + 1);
+            self.reader.consume(consumed);
+            if newline.is_some() {
+                return Ok(Some(self.take_line()));
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> Result<String, String> {
+        let mut line = std::mem::take(&mut self.buf);
+        if std::mem::take(&mut self.too_long) {
+            return Err(format!("消息过长（最多 {} 字节）", self.max_bytes));
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        String::from_utf8(line).map_err(|_| "消息不是有效的 UTF-8".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    // 缓冲区很小，让一行跨多次 fill_buf
+    fn reader(data: &[u8], max_bytes: usize) -> LineReader<BufReader<&[u8]>> {
+        LineReader::new(BufReader::with_capacity(3, data), max_bytes)
+    }
+
+    async fn lines(data: &[u8], max_bytes: usize) -> Vec<Result<String, String>> {
+        let mut reader = reader(data, max_bytes);
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next_line().await.unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn splits_lines() {
+        assert_eq!(
+            lines(b"hello\r\n\nworld\nlast", 16).await,
+            vec![Ok("hello".to_string()), Ok(String::new()), Ok("world".to_string()), Ok("last".to_string())]
+        );
+        assert!(lines(b"", 16).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn recovers_after_overlong_line() {
+        let lines = lines(b"ok\n0123456789abc\nnext\n0123456789", 8).await;
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], Ok("ok".to_string()));
+        assert!(lines[1].as_ref().unwrap_err().contains("8"));
+        assert_eq!(lines[2], Ok("next".to_string()));
+        // 没有换行的超长结尾同样报错
+        assert!(lines[3].is_err());
+    }
+
+    #[tokio::test]
+    async fn recovers_after_invalid_utf8() {
+        assert_eq!(
+            lines(b"\xffbad\n\xe4\xbd\xa0\xe5\xa5\xbd\n", 16).await,
+            vec![Err("消息不是有效的 UTF-8".to_string()), Ok("你好".to_string())]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use crate::chat::ChatState;
+
+/// 单项检查的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    // 仍然可用，但需要处理，例如证书即将过期
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => Self { name, status: Status::Ok, detail: Some(detail) },
+            Err(e) => Self { name, status: Status::Fail, detail: Some(e.to_string()) },
+        }
+    }
+}
+
+/// 健康检查：QUIC 端口、消息记录和用户数据是否可用，证书有效期，以及节点之间的消息总线
+pub struct Health {
+    chat_state: Arc<ChatState>,
+    endpoint: quinn::Endpoint,
+    cert_expires_at: DateTime<Utc>,
+    // 证书剩余有效期少于该值时报告 warn
+    cert_warn_before: chrono::Duration,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    pub fn new(
+        chat_state: Arc<ChatState>,
+        endpoint: quinn::Endpoint,
+        cert_der: &[u8],
+        cert_warn_before: std::time::Duration,
+    ) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            chat_state,
+            endpoint,
+            cert_expires_at: certificate_expiry(cert_der)?,
+            cert_warn_before: chrono::Duration::from_std(cert_warn_before)?,
+            shutting_down: AtomicBool::new(false),
+        }))
+    }
+
+    /// 开始关闭后不再报告就绪
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> (Status, serde_json::Value) {
+        let checks = [
+            Check::new("quic", self.check_quic()),
+            Check::new("history", self.chat_state.history().check().map(|_| "可写".to_string())),
+            Check::new("accounts", self.chat_state.accounts().check().map(|_| "可写".to_string())),
+            self.check_certificate(),
+            self.check_backplane(),
+        ];
+        let status = checks.iter().map(|c| c.status).max().unwrap_or(Status::Ok);
+        let report = serde_json::json!({
+            "status": status,
+            "checks": checks,
+            "certificateExpiresAt": self.cert_expires_at,
+        });
+        (status, report)
+    }
+
+    // 关闭后端口仍然绑定着，local_addr 照样成功，所以要看是否还在接受新连接
+    fn check_quic(&self) -> Result<String> {
+        let addr = self.endpoint.local_addr()?;
+        if self.shutting_down.load(Ordering::Relaxed) || !self.chat_state.accepting_connections() {
+            anyhow::bail!("服务器正在关闭，{} 不再接受新连接", addr);
+        }
+        Ok(format!("监听 {}", addr))
+    }
+
+    // 消息总线断开时本节点仍可服务自己的连接，只报告 warn
+    fn check_backplane(&self) -> Check {
+        match self.chat_state.backplane_status() {
+            Ok(detail) => Check { name: "backplane", status: Status::Ok, detail: Some(detail) },
+            Err(e) => Check { name: "backplane", status: Status::Warn, detail: Some(e.to_string()) },
+        }
+    }
+
+    fn check_certificate(&self) -> Check {
+        let remaining = self.cert_expires_at - Utc::now();
+        let status = if remaining <= chrono::Duration::zero() {
+            Status::Fail
+        } else if remaining < self.cert_warn_before {
+            Status::Warn
+        } else {
+            Status::Ok
+        };
+        let detail = if status == Status::Fail {
+            format!("证书已于 {} 过期", self.cert_expires_at)
+        } else {
+            format!("证书还有 {} 天过期", remaining.num_days())
+        };
+        Check { name: "certificate", status, detail: Some(detail) }
+    }
+}
+
+/// `GET /healthz` 总是返回 200 和各项检查的结果，用于存活探测和告警；
+/// `GET /readyz` 在任一项失败或服务器正在关闭时返回 503，用于决定是否转发流量
+pub fn routes(health: Arc<Health>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let health = warp::any().map(move || health.clone());
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .and(health.clone())
+        .map(|health: Arc<Health>| {
+            let (_, report) = health.report();
+            warp::reply::with_status(warp::reply::json(&report), StatusCode::OK)
+        });
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and(health)
+        .map(|health: Arc<Health>| {
+            let (status, report) = health.report();
+            let code = if status == Status::Fail { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+            warp::reply::with_status(warp::reply::json(&report), code)
+        });
+    healthz.or(readyz).unify()
+}
+
+/// 读取 DER 证书的到期时间
+fn certificate_expiry(cert_der: &[u8]) -> Result<DateTime<Utc>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| anyhow::anyhow!("无法解析证书: {}", e))?;
+    let timestamp = cert.validity().not_after.timestamp();
+    DateTime::from_timestamp(timestamp, 0).ok_or_else(|| anyhow::anyhow!("证书的到期时间无效"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Datelike;
+    use crate::chat::tests::{state, temp_dir};
+
+    // 在 expires_at 当天到期的自签名证书
+    fn certificate(expires_at: DateTime<Utc>) -> Vec<u8> {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.not_after = rcgen::date_time_ymd(expires_at.year(), expires_at.month() as u8, expires_at.day() as u8);
+        rcgen::Certificate::from_params(params).unwrap().serialize_der().unwrap()
+    }
+
+    fn health(dir: &std::path::Path, cert_expires_in: chrono::Duration) -> Arc<Health> {
+        let endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        let cert = certificate(Utc::now() + cert_expires_in);
+        let warn_before = std::time::Duration::from_secs(14 * 24 * 60 * 60);
+        Health::new(Arc::new(state(dir)), endpoint, &cert, warn_before).unwrap()
+    }
+
+    async fn get(health: &Arc<Health>, path: &str) -> (StatusCode, serde_json::Value) {
+        let response = warp::test::request().path(path).reply(&routes(health.clone())).await;
+        (response.status(), serde_json::from_slice(response.body()).unwrap())
+    }
+
+    fn check_status(report: &serde_json::Value, name: &str) -> String {
+        let checks = report.get("checks").and_then(|c| c.as_array()).unwrap();
+        let check = checks.iter().find(|c| c.get("name").and_then(|n| n.as_str()) == Some(name)).unwrap();
+        check.get("status").and_then(|s| s.as_str()).unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn ready_until_shutdown() {
+        let dir = temp_dir();
+        let health = health(&dir, chrono::Duration::days(365));
+        let (code, report) = get(&health, "/readyz").await;
+        assert_eq!(code, StatusCode::OK, "{}", report);
+        assert_eq!(report.get("status").and_then(|s| s.as_str()), Some("ok"));
+
+        // 关闭时端口仍然绑定，但不再就绪
+        health.chat_state.shutdown_clients("bye");
+        let (code, report) = get(&health, "/readyz").await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(check_status(&report, "quic"), "fail");
+        // 存活探测仍然返回 200
+        assert_eq!(get(&health, "/healthz").await.0, StatusCode::OK);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_shutting_down_fails_readiness() {
+        let dir = temp_dir();
+        let health = health(&dir, chrono::Duration::days(365));
+        health.set_shutting_down();
+        assert_eq!(get(&health, "/readyz").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_check_is_unavailable() {
+        let dir = temp_dir();
+        let health = health(&dir, chrono::Duration::days(365));
+        let history = dir.join("history.jsonl");
+        let mut permissions = std::fs::metadata(&history).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&history, permissions).unwrap();
+
+        let (code, report) = get(&health, "/readyz").await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(check_status(&report, "history"), "fail");
+        assert_eq!(check_status(&report, "quic"), "ok");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn certificate_expiry_warns_then_fails() {
+        let dir = temp_dir();
+        let (code, report) = get(&health(&dir, chrono::Duration::days(5)), "/readyz").await;
+        // warn 不影响就绪
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(report.get("status").and_then(|s| s.as_str()), Some("warn"));
+        assert_eq!(check_status(&report, "certificate"), "warn");
+
+        let (code, report) = get(&health(&dir, chrono::Duration::days(-2)), "/readyz").await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(check_status(&report, "certificate"), "fail");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use crate::chat::ChatMessage;
+use crate::markup::Span;
+use crate::preview::LinkPreview;
+
+// 内存中最多保留的消息条数，更早的消息只存在于日志文件中
+const MAX_HISTORY: usize = 1000;
+
+/// 日志中的一条记录，编辑和删除也会被记录下来
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum Record {
+    Message(ChatMessage),
+    Edit {
+        id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        spans: Vec<Span>,
+        edited_at: DateTime<Utc>,
+    },
+    Delete {
+        id: String,
+        deleted_at: DateTime<Utc>,
+    },
+    React {
+        id: String,
+        emoji: String,
+        username: String,
+        added: bool,
+    },
+    Enrich {
+        id: String,
+        previews: Vec<LinkPreview>,
+    },
+}
+
+/// 消息记录：内存中保留最近的消息，所有变更以 JSON Lines 追加到日志文件
+pub struct HistoryStore {
+    path: PathBuf,
+    messages: Mutex<VecDeque<ChatMessage>>,
+    // 最近分配的顺序号，按写入本节点的先后递增
+    last_seq: AtomicU64,
+    log: Mutex<File>,
+}
+
+impl HistoryStore {
+    /// 打开日志文件并重放其中的记录
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut messages = VecDeque::new();
+        let mut last_seq = 0;
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(Record::Message(mut message)) => {
+                        last_seq += 1;
+                        message.seq = last_seq;
+                        apply(&mut messages, Record::Message(message));
+                    }
+                    Ok(record) => apply(&mut messages, record),
+                    Err(e) => tracing::warn!("跳过无法解析的历史记录: {:?}", e),
+                }
+            }
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            messages: Mutex::new(messages),
+            last_seq: AtomicU64::new(last_seq),
+            log: Mutex::new(log),
+        })
+    }
+
+    /// 追加一条消息并为它分配顺序号
+    pub fn append(&self, message: &mut ChatMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        message.seq = self.last_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        self.write(&Record::Message(message.clone()));
+        apply(&mut messages, Record::Message(message.clone()));
+    }
+
+    pub fn get(&self, id: &str) -> Option<ChatMessage> {
+        let messages = self.messages.lock().unwrap();
+        messages.iter().find(|m| m.id == id).cloned()
+    }
+
+    /// 主聊天流中顺序号大于 `after` 的消息，按写入顺序排列
+    pub fn messages_after(&self, after: u64) -> Vec<ChatMessage> {
+        let messages = self.messages.lock().unwrap();
+        messages
+            .iter()
+            .filter(|m| m.parent_id.is_none() && m.seq > after)
+            .cloned()
+            .collect()
+    }
+
+    pub fn latest_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Relaxed)
+    }
+
+    /// 主聊天流中 `after` 之后、不是 `username` 自己发送的消息数量
+    pub fn unread_count(&self, after: Option<&str>, username: &str) -> usize {
+        let messages = self.messages.lock().unwrap();
+        messages
+            .iter()
+            .filter(|m| m.parent_id.is_none() && m.username != username)
+            .filter(|m| after.is_none_or(|after| m.id.as_str() > after))
+            .count()
+    }
+
+    /// 根消息及其回复，回复按时间顺序排列
+    pub fn thread(&self, id: &str) -> Option<(ChatMessage, Vec<ChatMessage>)> {
+        let messages = self.messages.lock().unwrap();
+        let root = messages.iter().find(|m| m.id == id)?.clone();
+        let replies = messages
+            .iter()
+            .filter(|m| m.parent_id.as_deref() == Some(id))
+            .cloned()
+            .collect();
+        Some((root, replies))
+    }
+
+    pub fn edit(&self, id: &str, content: &str, spans: &[Span], edited_at: DateTime<Utc>) {
+        let record = Record::Edit {
+            id: id.to_string(),
+            content: content.to_string(),
+            spans: spans.to_vec(),
+            edited_at,
+        };
+        self.write(&record);
+        apply(&mut self.messages.lock().unwrap(), record);
+    }
+
+    /// 更新表情回应，返回该表情新的计数；没有变化时返回 None
+    pub fn react(&self, id: &str, emoji: &str, username: &str, added: bool) -> Option<usize> {
+        let mut messages = self.messages.lock().unwrap();
+        let message = messages.iter().find(|m| m.id == id)?;
+        let reacted = message
+            .reactions
+            .get(emoji)
+            .is_some_and(|users| users.contains(username));
+        if reacted == added {
+            return None;
+        }
+
+        let record = Record::React {
+            id: id.to_string(),
+            emoji: emoji.to_string(),
+            username: username.to_string(),
+            added,
+        };
+        self.write(&record);
+        apply(&mut messages, record);
+        let message = messages.iter().find(|m| m.id == id)?;
+        Some(message.reactions.get(emoji).map_or(0, |users| users.len()))
+    }
+
+    /// 记录链接预览，消息已不存在时返回 false
+    pub fn enrich(&self, id: &str, previews: &[LinkPreview]) -> bool {
+        let mut messages = self.messages.lock().unwrap();
+        if !messages.iter().any(|m| m.id == id) {
+            return false;
+        }
+        let record = Record::Enrich {
+            id: id.to_string(),
+            previews: previews.to_vec(),
+        };
+        self.write(&record);
+        apply(&mut messages, record);
+        true
+    }
+
+    pub fn delete(&self, id: &str) {
+        let record = Record::Delete {
+            id: id.to_string(),
+            deleted_at: Utc::now(),
+        };
+        self.write(&record);
+        apply(&mut self.messages.lock().unwrap(), record);
+    }
+
+    /// 检查日志文件是否仍然存在且可写，供健康检查使用
+    pub fn check(&self) -> Result<()> {
+        let _log = self.log.lock().map_err(|_| anyhow::anyhow!("消息记录的锁已损坏"))?;
+        if std::fs::metadata(&self.path)?.permissions().readonly() {
+            anyhow::bail!("{} 为只读", self.path.display());
+        }
+        Ok(())
+    }
+
+    /// 把日志文件写入磁盘，退出前调用
+    pub fn flush(&self) -> Result<()> {
+        Ok(self.log.lock().unwrap().sync_data()?)
+    }
+
+    fn write(&self, record: &Record) {
+        let line = serde_json::to_string(record).unwrap() + "\n";
+        let mut log = self.log.lock().unwrap();
+        if let Err(e) = log.write_all(line.as_bytes()) {
+            tracing::error!("写入消息记录失败: {:?}", e);
+        }
+    }
+}
+
+fn apply(messages: &mut VecDeque<ChatMessage>, record: Record) {
+    match record {
+        Record::Message(message) => {
+            if let Some(parent_id) = &message.parent_id {
+                if let Some(parent) = messages.iter_mut().find(|m| &m.id == parent_id) {
+                    parent.reply_count += 1;
+                }
+            }
+            messages.push_back(message);
+            while messages.len() > MAX_HISTORY {
+                messages.pop_front();
+            }
+        }
+        Record::Edit { id, content, spans, edited_at } => {
+            if let Some(message) = messages.iter_mut().find(|m| m.id == id) {
+                message.content = content;
+                message.spans = spans;
+                message.edited_at = Some(edited_at);
+            }
+        }
+        Record::Delete { id, .. } => {
+            let parent_id = messages
+                .iter()
+                .find(|m| m.id == id)
+                .and_then(|m| m.parent_id.clone());
+            if let Some(parent_id) = parent_id {
+                if let Some(parent) = messages.iter_mut().find(|m| m.id == parent_id) {
+                    parent.reply_count = parent.reply_count.saturating_sub(1);
+                }
+            }
+            messages.retain(|m| m.id != id);
+        }
+        Record::React { id, emoji, username, added } => {
+            if let Some(message) = messages.iter_mut().find(|m| m.id == id) {
+                let users = message.reactions.entry(emoji.clone()).or_default();
+                if added {
+                    users.insert(username);
+                } else {
+                    users.remove(&username);
+                }
+                if users.is_empty() {
+                    message.reactions.remove(&emoji);
+                }
+            }
+        }
+        Record::Enrich { id, previews } => {
+            if let Some(message) = messages.iter_mut().find(|m| m.id == id) {
+                message.previews = previews;
+            }
+        }
+    }
+}
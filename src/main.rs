@@ -1,135 +1,473 @@
 use anyhow::Result;
 use quinn::{Endpoint, ServerConfig};
-use std::{net::SocketAddr, sync::Arc};
+use std::collections::HashMap;
+use std::{net::{IpAddr, SocketAddr}, sync::Arc};
 use chat::ChatMessage;
 use warp::Filter;
 use futures::{StreamExt, SinkExt};
-use tokio::sync::mpsc;
-use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tracing::Instrument;
 
+mod accounts;
+mod admin;
+mod admission;
+mod backplane;
+mod blobs;
 mod chat;
+mod framing;
+mod health;
+mod config;
+mod filters;
+mod history;
+mod markup;
+mod metrics;
+mod moderation;
+mod outbound;
+mod outbox;
+mod preview;
+mod ratelimit;
+mod storage;
+mod telemetry;
+mod validation;
+
+// 连接关闭后等待写任务发完剩余内容的最长时间
+const WRITER_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+// WebSocket 连接后等待登录消息的最长时间
+const LOGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+// 强制关闭后等待 CONNECTION_CLOSE 发出的最长时间
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+// 上传请求头的最大字节数
+const UPLOAD_HEADER_LIMIT: u64 = 4096;
+// 拒绝上传时停止单向流使用的错误码
+const UPLOAD_REJECTED: quinn::VarInt = quinn::VarInt::from_u32(1);
+// 连接数超限时关闭连接使用的错误码
+const CONNECTION_REFUSED: quinn::VarInt = quinn::VarInt::from_u32(2);
+// 服务器关闭时关闭连接使用的错误码
+const SERVER_SHUTDOWN: quinn::VarInt = quinn::VarInt::from_u32(3);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let config = config::Config::from_env();
+    // 日志过滤规则可以通过管理接口在运行时修改
+    let log_handle = telemetry::init(config.log_format, config.otlp_endpoint.as_deref())?;
     
-    let addr: SocketAddr = "0.0.0.0:4433".parse()?;
-    let server_config = configure_server()?;
+    let addr = config.quic_addr;
+    let mut server_config = configure_server(&config)?;
     
-    let endpoint = Endpoint::server(server_config, addr)?;
+    let endpoint = Endpoint::server(server_config.clone(), addr)?;
     
     tracing::info!("QUIC chat server listening on {}", addr);
     
-    let chat_state = Arc::new(chat::ChatState::new());
+    let history = history::HistoryStore::open(config.data_dir.join("history.jsonl"))?;
+    let accounts = accounts::AccountStore::open(config.data_dir.join("accounts.json"))?;
+    let blobs = blobs::BlobStore::open(
+        config.data_dir.join("blobs"),
+        config.max_upload_size,
+        config.thumbnail_workers,
+    )?;
+    let moderation = moderation::ModerationStore::open(
+        config.data_dir.join("moderation.json"),
+        config.data_dir.join("audit.jsonl"),
+        config.owners.keys().cloned().collect(),
+    )?;
+    let unfurler = if config.link_previews {
+        let fetcher = preview::HttpFetcher::new(config.preview_max_bytes)?;
+        Some(preview::Unfurler::new(Arc::new(fetcher), config.preview_timeout))
+    } else {
+        None
+    };
+    let filters = filters::FilterChain::new(&config.filter_rules, filters::default_filters());
+    let chat_state = chat::ChatState::new(
+        config.clone(),
+        history,
+        accounts,
+        blobs,
+        moderation,
+        filters,
+        unfurler,
+    );
+    // 多个节点通过 Redis 互通，未配置时只在本进程内广播
+    let chat_state = Arc::new(match &config.backplane_url {
+        Some(url) => {
+            tracing::info!("节点 {} 通过 {} 的频道 {} 与其他节点互通", config.node_id, url, config.backplane_channel);
+            chat_state.with_backplane(Arc::new(backplane::RedisBackplane::connect(url, &config.backplane_channel)?))
+        }
+        None => chat_state,
+    });
     let chat_state_ws = chat_state.clone();
+    let admission = admission::Admission::new(config.max_connections, config.max_connections_per_ip);
+    let admission_ws = admission.clone();
+    
+    // 定期把空闲用户标记为离开
+    let chat_state_idle = chat_state.clone();
+    let idle_timeout = config.idle_timeout;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(idle_timeout.min(std::time::Duration::from_secs(30)));
+        loop {
+            interval.tick().await;
+            chat_state_idle.mark_idle_users(idle_timeout);
+            chat_state_idle.expire_sessions();
+            chat_state_idle.sweep_rate_limits();
+        }
+    });
+    
+    // 接收其他节点的消息，并定期发送心跳
+    let chat_state_backplane = chat_state.clone();
+    tokio::spawn(async move { chat_state_backplane.run_backplane().await });
+    let chat_state_nodes = chat_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            chat_state_nodes.sync_nodes();
+        }
+    });
+    
+    // 规则文件修改后自动重新加载过滤规则
+    let chat_state_filters = chat_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            chat_state_filters.reload_filters();
+        }
+    });
+    
+    // 定期输出广播滞后和发送队列溢出统计
+    let chat_state_metrics = chat_state.clone();
+    let admission_metrics = admission.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        let mut last_events = 0;
+        let mut last_overflows = 0;
+        let mut last_limited = 0;
+        let mut last_rejected = 0;
+        loop {
+            interval.tick().await;
+            let metrics = chat_state_metrics.metrics();
+            let events = metrics.broadcast_lag_events.load(std::sync::atomic::Ordering::Relaxed);
+            if events != last_events {
+                tracing::info!(
+                    "广播滞后统计: 次数 {}, 漏掉消息 {}, 断开连接 {}",
+                    events,
+                    metrics.broadcast_lag_messages.load(std::sync::atomic::Ordering::Relaxed),
+                    metrics.lag_disconnects.load(std::sync::atomic::Ordering::Relaxed),
+                );
+                last_events = events;
+            }
+            let overflows = metrics.queue_overflows.load(std::sync::atomic::Ordering::Relaxed);
+            if overflows != last_overflows {
+                tracing::info!(
+                    "发送队列溢出统计: 次数 {}, 断开连接 {}",
+                    overflows,
+                    metrics.queue_disconnects.load(std::sync::atomic::Ordering::Relaxed),
+                );
+                last_overflows = overflows;
+            }
+            let limited = metrics.rate_limited.load(std::sync::atomic::Ordering::Relaxed);
+            if limited != last_limited {
+                tracing::info!(
+                    "限流统计: 次数 {}, 断开连接 {}",
+                    limited,
+                    metrics.rate_limit_disconnects.load(std::sync::atomic::Ordering::Relaxed),
+                );
+                last_limited = limited;
+            }
+            let rejected = metrics.connections_rejected.load(std::sync::atomic::Ordering::Relaxed);
+            if rejected != last_rejected {
+                tracing::info!("连接统计: 当前 {}, 拒绝 {}", admission_metrics.active(), rejected);
+                last_rejected = rejected;
+            }
+        }
+    });
+    
+    // 清理过期的“正在输入”状态
+    let chat_state_typing = chat_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            chat_state_typing.expire_typing();
+        }
+    });
     
     // WebSocket 路由
+    let chat_state_handshake = chat_state.clone();
     let ws_route = warp::path("ws")
-        .and(warp::ws())
-        .map(move |ws: warp::ws::Ws| {
+        .and(warp::ws().or_else(move |rejection| {
+            // 不是有效的 WebSocket 升级请求
+            chat_state_handshake.metrics().record_ws_handshake_error();
+            async move { Err::<(warp::ws::Ws,), _>(rejection) }
+        }))
+        .and(warp::addr::remote())
+        .map(move |ws: warp::ws::Ws, addr: Option<SocketAddr>| {
+            use warp::Reply;
             let chat_state = chat_state_ws.clone();
-            ws.on_upgrade(move |socket| async move {
-                handle_ws_connection(socket, chat_state).await;
-            })
+            let ip = addr.map_or(IpAddr::from([0, 0, 0, 0]), |a| a.ip());
+            let permit = match admission_ws.try_admit(ip) {
+                Ok(permit) => permit,
+                Err(e) => {
+                    chat_state.metrics().record_connection_rejected();
+                    return warp::reply::with_status(e.to_string(), warp::http::StatusCode::SERVICE_UNAVAILABLE)
+                        .into_response();
+                }
+            };
+            ws.max_message_size(max_line_bytes(&chat_state)).on_upgrade(move |socket| {
+                let span = tracing::info_span!(
+                    "connection",
+                    transport = "websocket",
+                    remote = addr.map(tracing::field::display),
+                    username = tracing::field::Empty,
+                );
+                async move {
+                    handle_ws_connection(socket, chat_state, addr).await;
+                    drop(permit);
+                }
+                .instrument(span)
+            }).into_response()
         });
     
-    // 启动 WebSocket 服务器
-    let ws_addr = "127.0.0.1:8080";
-    tokio::spawn(async move {
-        warp::serve(ws_route)
-            .run(ws_addr.parse::<SocketAddr>().unwrap())
-            .await;
-    });
+    // 文件上传与下载，供 WebSocket 客户端使用
+    let chat_state_upload = chat_state.clone();
+    let upload_route = warp::path("upload")
+        .and(warp::post())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::content_length_limit(config.max_upload_size))
+        .and(warp::body::bytes())
+        .then(move |addr, authorization, query, body| {
+            handle_http_upload(chat_state_upload.clone(), addr, authorization, query, body)
+        });
+    let chat_state_download = chat_state.clone();
+    let download_route = warp::path!("files" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .then(move |id, authorization, query| {
+            handle_http_download(chat_state_download.clone(), id, authorization, query)
+        });
+    let file_routes = upload_route
+        .or(download_route)
+        .with(warp::cors().allow_any_origin().allow_methods(vec!["GET", "POST"]).allow_header("authorization"));
+    
+    // 管理接口使用单独的端口，未设置令牌时不启动
+    match config.admin_token.clone() {
+        Some(token) => {
+            let admin_routes = admin::routes(chat_state.clone(), log_handle, token);
+            let admin_addr = config.admin_addr;
+            tracing::info!("管理接口监听 {}", admin_addr);
+            tokio::spawn(warp::serve(admin_routes).run(admin_addr));
+        }
+        None => tracing::info!("未设置 CHAT_ADMIN_TOKEN，管理接口未启动"),
+    }
+    
+    // 健康检查
+    let health = health::Health::new(
+        chat_state.clone(),
+        endpoint.clone(),
+        &std::fs::read("cert.der")?,
+        config.cert_warn_before,
+    )?;
+    let health_routes = health::routes(health.clone());
+    
+    // 启动 WebSocket 服务器，关闭时停止接受新的连接
+    let ws_addr = config.ws_addr;
+    let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(false);
+    let (_, ws_server) = warp::serve(ws_route.or(file_routes).or(health_routes))
+        .try_bind_with_graceful_shutdown(ws_addr, async move {
+            let _ = stop_rx.changed().await;
+        })?;
+    let ws_server = tokio::spawn(ws_server);
     
     // QUIC 服务器
-    while let Some(conn) = endpoint.accept().await {
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut retrying = false;
+    loop {
+        let conn = tokio::select! {
+            conn = endpoint.accept() => match conn {
+                Some(conn) => conn,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        // 负载较高时要求新连接先完成地址验证（Retry），防止伪造源地址占用资源。
+        // 设置对之后的新连接生效
+        let under_load = admission.active() >= config.retry_threshold;
+        if under_load != retrying {
+            retrying = under_load;
+            server_config.use_retry(retrying);
+            endpoint.set_server_config(Some(server_config.clone()));
+            tracing::info!("当前连接数 {}，{}地址验证", admission.active(), if retrying { "开启" } else { "关闭" });
+        }
+
+        let remote = conn.remote_address();
+        let permit = match admission.try_admit(remote.ip()) {
+            Ok(permit) => permit,
+            Err(e) => {
+                chat_state.metrics().record_connection_rejected();
+                tracing::warn!("拒绝来自 {} 的连接: {}", remote, e);
+                // 握手完成后带上原因关闭，客户端可以看到被拒绝的原因
+                tokio::spawn(async move {
+                    if let Ok(connection) = conn.await {
+                        connection.close(CONNECTION_REFUSED, e.to_string().as_bytes());
+                    }
+                });
+                continue;
+            }
+        };
         let chat_state = chat_state.clone();
+        let span = tracing::info_span!(
+            "connection",
+            transport = "quic",
+            remote = %remote,
+            username = tracing::field::Empty,
+        );
         tokio::spawn(async move {
             if let Err(e) = handle_connection(conn, chat_state).await {
                 tracing::error!("Connection failed: {:?}", e);
             }
-        });
+            drop(permit);
+        }.instrument(span));
     }
     
+    tracing::info!("正在关闭服务器");
+    health.set_shutting_down();
+    endpoint.set_server_config(None);
+    let _ = stop_tx.send(true);
+    let drained = tokio::time::timeout(
+        config.shutdown_timeout,
+        graceful_shutdown(&endpoint, &chat_state, ws_server),
+    ).await;
+    if drained.is_err() {
+        tracing::warn!("{} 秒内未能关闭所有连接，直接退出", config.shutdown_timeout.as_secs());
+        endpoint.close(SERVER_SHUTDOWN, b"server restarting");
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, endpoint.wait_idle()).await;
+    }
+    chat_state.flush();
+    telemetry::shutdown();
+    tracing::info!("服务器已关闭");
     Ok(())
 }
 
-async fn handle_ws_connection(ws: warp::ws::WebSocket, chat_state: Arc<chat::ChatState>) {
-    let (mut ws_sender, mut ws_receiver) = ws.split();
-    let mut rx = chat_state.subscribe();
-    let (tx, mut rx_ws) = mpsc::unbounded_channel::<String>();
-    let ws_id = chat_state.register_ws(tx);
+/// 等待 SIGINT 或 SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("无法监听 SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// 通知所有客户端服务器即将重启，等各连接发完剩余内容后关闭 QUIC 连接
+async fn graceful_shutdown(
+    endpoint: &Endpoint,
+    chat_state: &chat::ChatState,
+    ws_server: tokio::task::JoinHandle<()>,
+) {
+    chat_state.shutdown_clients("服务器正在重启，请稍后重新连接");
+    // 发送队列关闭后读写任务会陆续结束，写任务发完剩余内容后连接才算结束
+    while chat_state.open_connections() > 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    endpoint.close(SERVER_SHUTDOWN, b"server restarting");
+    endpoint.wait_idle().await;
+    let _ = ws_server.await;
+}
+
+async fn handle_ws_connection(
+    ws: warp::ws::WebSocket,
+    chat_state: Arc<chat::ChatState>,
+    addr: Option<SocketAddr>,
+) {
+    let _connection = chat_state.track_connection();
+    let (ws_sender, ws_receiver) = ws.split();
+    let traffic = Arc::new(chat::Traffic::default());
+    let (client_id, queue) = chat_state.register_client(chat::Transport::WebSocket {
+        addr,
+        traffic: traffic.clone(),
+    });
+    // 发送放在单独的任务里，慢连接不会阻塞读取
+    let writer = tokio::spawn(
+        ws_writer(ws_sender, chat_state.clone(), client_id, queue.clone(), traffic.clone()).in_current_span(),
+    );
+    // 统计收到的字节数
+    let mut ws_receiver = ws_receiver.inspect(|msg| {
+        if let Ok(msg) = msg {
+            traffic.received.fetch_add(msg.as_bytes().len() as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+    });
     let mut username: Option<String> = None;
 
-    // 等待用户名
-    if let Some(Ok(msg)) = ws_receiver.next().await {
-        if let Ok(login) = serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap()) {
-            if let Some(name) = login.get("username").and_then(|u| u.as_str()) {
-                let name = name.to_string();
-                chat_state.add_user(name.clone());
+    // 等待用户名；服务器关闭或超时未登录时直接断开
+    let first = tokio::select! {
+        msg = ws_receiver.next() => msg,
+        _ = queue.closed() => None,
+        _ = tokio::time::sleep(LOGIN_TIMEOUT) => {
+            chat_state.send_error(client_id, "登录超时");
+            None
+        }
+    };
+    if let Some(Ok(msg)) = first {
+        let login = serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap_or_default()).ok();
+        let name = login
+            .as_ref()
+            .and_then(|login| login.get("username"))
+            .and_then(|u| u.as_str())
+            .map(|name| {
+                let name = chat_state.validate_username(name)?;
+                chat_state.check_join(&name, addr.map(|a| a.ip()))?;
+                let token = login.as_ref().and_then(|login| login.get("token")).and_then(|t| t.as_str());
+                chat_state.add_user(name.clone(), client_id, token)?;
+                Ok::<_, anyhow::Error>(name)
+            });
+        match name {
+            Some(Ok(mut name)) => {
+                tracing::Span::current().record("username", name.as_str());
+                tracing::info!("{} 加入聊天室", name);
+                chat_state.issue_upload_token(client_id);
                 chat_state.broadcast_user_list();
-                username = Some(name.clone());
+                chat_state.send_to(client_id, chat_state.room_list(&name));
 
-                let message = ChatMessage {
-                    username: name.clone(),
-                    content: format!("{} 加入了聊天室", name),
-                    timestamp: chrono::Utc::now(),
-                };
+                let message = ChatMessage::notice(format!("{} 加入了聊天室", name));
                 chat_state.broadcast_message(message);
 
-                // 只给当前用户推送一次完整用户列表
-                let users = chat_state.get_users();
-                let _ = ws_sender.send(warp::ws::Message::text(
-                    serde_json::to_string(&serde_json::json!({
-                        "type": "userList",
-                        "users": users
-                    })).unwrap()
-                )).await;
-
                 loop {
-                    let mut should_break = false;
                     tokio::select! {
                         msg = ws_receiver.next() => {
                             match msg {
-                                Some(Ok(msg)) => {
-                                    if let Ok(message) = serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap()) {
-                                        if let Some(msg_type) = message.get("type").and_then(|t| t.as_str()) {
-                                            if msg_type == "logout" {
-                                                chat_state.remove_user(&name);
-                                                chat_state.broadcast_user_list();
-                                                should_break = true;
-                                                continue;
+                                Some(Ok(msg)) if msg.is_text() => {
+                                    match serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap_or_default()) {
+                                        Ok(message) => {
+                                            if handle_client_command(&chat_state, client_id, &mut name, &message) {
+                                                break;
                                             }
                                         }
-                                        if let Some(content) = message.get("content").and_then(|c| c.as_str()) {
-                                            let message = ChatMessage {
-                                                username: name.clone(),
-                                                content: content.to_string(),
-                                                timestamp: chrono::Utc::now(),
-                                            };
-                                            chat_state.broadcast_message(message);
-                                        }
+                                        Err(_) => chat_state.send_error(client_id, "无效的 JSON 消息"),
                                     }
                                 },
-                                _ => { should_break = true; },
+                                Some(Ok(msg)) if msg.is_binary() => chat_state.send_error(client_id, "只支持文本消息"),
+                                // ping、pong 和关闭帧
+                                Some(Ok(_)) => {},
+                                _ => break,
                             }
                         },
-                        Ok(broadcast_msg) = rx.recv() => {
-                            if ws_sender.send(warp::ws::Message::text(
-                                serde_json::to_string(&broadcast_msg).unwrap()
-                            )).await.is_err() {
-                                should_break = true;
-                            }
-                        },
-                        Some(user_list_msg) = rx_ws.recv() => {
-                            if ws_sender.send(warp::ws::Message::text(user_list_msg)).await.is_err() {
-                                should_break = true;
-                            }
-                        }
+                        // 写任务已退出，或发送队列溢出被断开
+                        _ = queue.closed() => break,
                     }
-                    if should_break { break; }
                 }
+                username = Some(name);
             }
+            Some(Err(e)) => chat_state.send_error(client_id, e),
+            None => chat_state.send_error(client_id, "请先发送 {\"username\": ...} 登录"),
         }
     }
     // 只要连接断开就移除用户
@@ -137,98 +475,743 @@ async fn handle_ws_connection(ws: warp::ws::WebSocket, chat_state: Arc<chat::Cha
         chat_state.remove_user(&name);
         chat_state.broadcast_user_list();
     }
-    chat_state.unregister_ws(ws_id);
+    chat_state.unregister_client(client_id);
+    queue.close();
+    finish_writer(writer).await;
+}
+
+/// HTTP 请求的用户：令牌放在 `Authorization: Bearer <令牌>` 或 `?token=` 中，
+/// 在 WebSocket 登录后通过 `uploadToken` 事件下发，随连接断开失效
+fn http_user(
+    chat_state: &chat::ChatState,
+    authorization: Option<&str>,
+    query: &HashMap<String, String>,
+) -> Option<String> {
+    let token = authorization
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .or_else(|| query.get("token").map(|token| token.as_str()))?;
+    chat_state.upload_user(token)
+}
+
+fn unauthorized() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "type": "error", "message": "请先登录" })),
+        warp::http::StatusCode::UNAUTHORIZED,
+    )
+}
+
+/// HTTP 上传：POST /upload?name=..&content=..，请求体为文件内容，上传者由令牌确定
+async fn handle_http_upload(
+    chat_state: Arc<chat::ChatState>,
+    addr: Option<SocketAddr>,
+    authorization: Option<String>,
+    query: HashMap<String, String>,
+    body: warp::hyper::body::Bytes,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let Some(username) = http_user(&chat_state, authorization.as_deref(), &query) else {
+        return unauthorized();
+    };
+    let username = username.as_str();
+    let retry_after = match chat_state.check_rate(ratelimit::Action::Upload, None, username, addr.map(|a| a.ip())) {
+        ratelimit::Verdict::Allowed => None,
+        ratelimit::Verdict::Throttled(retry_after) => Some(retry_after),
+        // HTTP 请求没有可断开的连接，同样按超限处理
+        ratelimit::Verdict::Disconnect => Some(std::time::Duration::ZERO),
+    };
+    if let Some(retry_after) = retry_after {
+        return warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "type": "rateLimited",
+                "action": "upload",
+                "retryAfterMs": retry_after.as_millis() as u64,
+                "message": "上传过于频繁，请稍后再试",
+            })),
+            warp::http::StatusCode::TOO_MANY_REQUESTS,
+        );
+    }
+    let name = query.get("name").map(|n| n.as_str()).unwrap_or_default();
+    let caption = query.get("content").map(|c| c.as_str());
+    let result = chat_state.share_file(username, name, caption, &body).await;
+    match result {
+        Ok(message) => warp::reply::with_status(warp::reply::json(&message), warp::http::StatusCode::CREATED),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "type": "error", "message": e.to_string() })),
+            warp::http::StatusCode::BAD_REQUEST,
+        ),
+    }
+}
+
+/// HTTP 下载：GET /files/<id>，和上传一样需要令牌
+async fn handle_http_download(
+    chat_state: Arc<chat::ChatState>,
+    id: String,
+    authorization: Option<String>,
+    query: HashMap<String, String>,
+) -> warp::reply::Response {
+    use warp::Reply;
+    if http_user(&chat_state, authorization.as_deref(), &query).is_none() {
+        return unauthorized().into_response();
+    }
+    match chat_state.blobs().get(&id).await {
+        Ok((data, content_type)) => {
+            let mut response = warp::reply::Response::new(data.into());
+            let headers = response.headers_mut();
+            headers.insert(warp::http::header::CONTENT_TYPE, warp::http::HeaderValue::from_static(content_type));
+            headers.insert("x-content-type-options", warp::http::HeaderValue::from_static("nosniff"));
+            response
+        }
+        Err(e) => warp::reply::with_status(e.to_string(), warp::http::StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
+/// WebSocket 写任务：发送广播消息、发送队列中的事件和输入提示
+async fn ws_writer(
+    mut ws_sender: futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>,
+    chat_state: Arc<chat::ChatState>,
+    client_id: usize,
+    queue: Arc<outbound::OutboundQueue>,
+    traffic: Arc<chat::Traffic>,
+) {
+    let mut rx = chat_state.subscribe();
+    let mut cursor = chat_state.cursor();
+    let mut typing_rx = chat_state.subscribe_ephemeral();
+    'outer: loop {
+        let lines = tokio::select! {
+            result = rx.recv() => match broadcast_lines(&chat_state, &mut cursor, None, result) {
+                Some(lines) => lines,
+                None => {
+                    let _ = ws_sender.send(warp::ws::Message::text(lag_disconnect_notice())).await;
+                    break;
+                }
+            },
+            line = queue.pop() => match line {
+                Some(line) => vec![line],
+                None => break,
+            },
+            Ok(typing) = typing_rx.recv() => {
+                if typing.client_id == Some(client_id) {
+                    continue;
+                }
+                vec![serde_json::to_string(&typing).unwrap()]
+            }
+        };
+        for line in lines {
+            traffic.sent.fetch_add(line.len() as u64, std::sync::atomic::Ordering::Relaxed);
+            if ws_sender.send(warp::ws::Message::text(line)).await.is_err() {
+                chat_state.metrics().record_ws_send_failure();
+                break 'outer;
+            }
+        }
+    }
+    queue.close();
+}
+
+/// 读取结束后等待写任务把队列中剩余的内容发完，超时则直接结束
+async fn finish_writer(mut writer: tokio::task::JoinHandle<()>) {
+    if tokio::time::timeout(WRITER_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
+    }
+}
+
+/// 处理广播通道的接收结果，返回需要发给客户端的 JSON 行。
+/// 滞后时先通知客户端漏掉的条数再补发；返回 None 表示应断开连接
+fn broadcast_lines(
+    chat_state: &chat::ChatState,
+    cursor: &mut chat::BroadcastCursor,
+    session: Option<&str>,
+    result: Result<ChatMessage, broadcast::error::RecvError>,
+) -> Option<Vec<String>> {
+    let mut lines = Vec::new();
+    let messages = match result {
+        Ok(message) if cursor.advance(&message) => vec![message],
+        Ok(_) => Vec::new(),
+        Err(broadcast::error::RecvError::Lagged(missed)) => {
+            let messages = chat_state.handle_lag(missed, cursor)?;
+            lines.push(serde_json::json!({
+                "type": "missedMessages",
+                "count": missed,
+            }).to_string());
+            messages
+        }
+        Err(broadcast::error::RecvError::Closed) => return None,
+    };
+    for message in messages {
+        if let Some(session) = session {
+            chat_state.track_delivery(session, &message);
+        }
+        lines.push(serde_json::to_string(&message).unwrap());
+    }
+    Some(lines)
+}
+
+fn lag_disconnect_notice() -> String {
+    serde_json::json!({
+        "type": "error",
+        "message": "接收消息过慢，连接已断开",
+    }).to_string()
+}
+
+/// 处理客户端发来的 JSON 指令，WebSocket 与 QUIC 共用。返回 true 表示应断开连接
+fn handle_client_command(
+    chat_state: &Arc<chat::ChatState>,
+    client_id: usize,
+    username: &mut String,
+    message: &serde_json::Value,
+) -> bool {
+    let msg_type = message.get("type").and_then(|t| t.as_str());
+    if msg_type != Some("logout") {
+        chat_state.touch(username);
+    }
+    // 会广播给其他人的操作需要限流，查询类指令和输入提示不限
+    let broadcasts = match msg_type {
+        Some("rename" | "status" | "edit" | "delete" | "reply" | "react" | "unreact") => true,
+        Some("kick" | "muteUser" | "unmuteUser" | "ban" | "unban" | "setRole") => true,
+        Some("approve" | "discardHeld") => true,
+        Some("logout" | "getThread" | "read" | "mute" | "unmute" | "getRooms" | "typing") => false,
+        Some("getHeld") => false,
+        _ => message.get("content").is_some(),
+    };
+    if broadcasts && !chat_state.enforce_rate(client_id, username, ratelimit::Action::Message) {
+        return false;
+    }
+    match msg_type {
+        Some("logout") => {
+            chat_state.remove_user(username);
+            chat_state.broadcast_user_list();
+            return true;
+        }
+        Some("rename") => {
+            if let Some(new_name) = message.get("username").and_then(|u| u.as_str()) {
+                match chat_state.rename_user(username, new_name) {
+                    Ok(new_name) => {
+                        tracing::Span::current().record("username", new_name.as_str());
+                        *username = new_name;
+                    }
+                    Err(e) => chat_state.send_error(client_id, e),
+                }
+            }
+            return false;
+        }
+        Some("status") => {
+            let status = message
+                .get("status")
+                .and_then(|s| s.as_str())
+                .unwrap_or("online")
+                .parse::<chat::UserStatus>();
+            let status_text = message
+                .get("text")
+                .and_then(|t| t.as_str())
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty());
+            if let Err(e) = status.and_then(|status| chat_state.set_status(username, status, status_text)) {
+                chat_state.send_error(client_id, e);
+            }
+            return false;
+        }
+        Some("edit") => {
+            if let (Some(id), Some(content)) = (
+                message.get("id").and_then(|i| i.as_str()),
+                message.get("content").and_then(|c| c.as_str()),
+            ) {
+                if let Err(e) = chat_state.edit_message(username, id, content) {
+                    chat_state.send_error(client_id, e);
+                }
+            }
+            return false;
+        }
+        Some("delete") => {
+            if let Some(id) = message.get("id").and_then(|i| i.as_str()) {
+                if let Err(e) = chat_state.delete_message(username, id) {
+                    chat_state.send_error(client_id, e);
+                }
+            }
+            return false;
+        }
+        Some("reply") => {
+            if let (Some(parent_id), Some(content)) = (
+                message.get("parentId").and_then(|i| i.as_str()),
+                message.get("content").and_then(|c| c.as_str()),
+            ) {
+                let quote = message.get("quote").and_then(|q| q.as_bool()).unwrap_or(false);
+                match chat_state.post_reply(username, parent_id, content, quote) {
+                    Ok(reply) => chat_state.unfurl(&reply),
+                    Err(e) => chat_state.send_error(client_id, e),
+                }
+            }
+            return false;
+        }
+        Some("getThread") => {
+            if let Some(id) = message.get("id").and_then(|i| i.as_str()) {
+                match chat_state.get_thread(id) {
+                    Ok((parent, replies)) => chat_state.send_to(client_id, serde_json::json!({
+                        "type": "thread",
+                        "parent": parent,
+                        "replies": replies,
+                    })),
+                    Err(e) => chat_state.send_error(client_id, e),
+                }
+            }
+            return false;
+        }
+        Some(kind @ ("react" | "unreact")) => {
+            if let (Some(id), Some(emoji)) = (
+                message.get("id").and_then(|i| i.as_str()),
+                message.get("emoji").and_then(|e| e.as_str()),
+            ) {
+                if let Err(e) = chat_state.react(username, id, emoji, kind == "react") {
+                    chat_state.send_error(client_id, e);
+                }
+            }
+            return false;
+        }
+        Some("read") => {
+            if let Some(id) = message.get("id").and_then(|i| i.as_str()) {
+                let room = message.get("room").and_then(|r| r.as_str()).unwrap_or(chat::DEFAULT_ROOM);
+                if let Err(e) = chat_state.mark_read(username, room, id) {
+                    chat_state.send_error(client_id, e);
+                }
+            }
+            return false;
+        }
+        Some(kind @ ("mute" | "unmute")) => {
+            let room = message.get("room").and_then(|r| r.as_str()).unwrap_or(chat::DEFAULT_ROOM);
+            chat_state.set_muted(username, room, kind == "mute");
+            chat_state.send_to(client_id, chat_state.room_list(username));
+            return false;
+        }
+        Some("getRooms") => {
+            chat_state.send_to(client_id, chat_state.room_list(username));
+            return false;
+        }
+        // 管理指令，权限检查在 ChatState 中进行
+        Some(kind @ ("kick" | "muteUser" | "unmuteUser" | "setRole")) => {
+            let room = message.get("room").and_then(|r| r.as_str()).unwrap_or(chat::DEFAULT_ROOM);
+            let reason = message.get("reason").and_then(|r| r.as_str());
+            if let Some(target) = message.get("username").and_then(|u| u.as_str()) {
+                let result = match kind {
+                    "kick" => chat_state.kick(username, room, target, reason),
+                    "muteUser" => {
+                        let duration = message.get("duration").and_then(|d| d.as_u64()).unwrap_or(0);
+                        chat_state.mute_user(username, room, target, std::time::Duration::from_secs(duration), reason)
+                    }
+                    "unmuteUser" => chat_state.unmute_user(username, room, target),
+                    _ => message
+                        .get("role")
+                        .and_then(|r| r.as_str())
+                        .unwrap_or_default()
+                        .parse::<moderation::Role>()
+                        .and_then(|role| chat_state.set_role(username, room, target, role)),
+                };
+                if let Err(e) = result {
+                    chat_state.send_error(client_id, e);
+                }
+            }
+            return false;
+        }
+        // 按用户名 {"username"} 或 IP {"ip"} 封禁，可选 duration（秒），不填为永久
+        Some(kind @ ("ban" | "unban")) => {
+            let room = message.get("room").and_then(|r| r.as_str()).unwrap_or(chat::DEFAULT_ROOM);
+            let target = if let Some(ip) = message.get("ip").and_then(|i| i.as_str()) {
+                ip.parse()
+                    .map(moderation::BanTarget::Ip)
+                    .map_err(|_| anyhow::anyhow!("无效的 IP 地址: {}", ip))
+            } else if let Some(target) = message.get("username").and_then(|u| u.as_str()) {
+                Ok(moderation::BanTarget::User(target.to_string()))
+            } else {
+                Err(anyhow::anyhow!("需要指定 username 或 ip"))
+            };
+            let result = target.and_then(|target| {
+                if kind == "ban" {
+                    let duration = message.get("duration").and_then(|d| d.as_u64()).map(std::time::Duration::from_secs);
+                    let reason = message.get("reason").and_then(|r| r.as_str());
+                    chat_state.ban(username, room, target, duration, reason)
+                } else {
+                    chat_state.unban(username, room, target)
+                }
+            });
+            if let Err(e) = result {
+                chat_state.send_error(client_id, e);
+            }
+            return false;
+        }
+        Some("getHeld") => {
+            match chat_state.held_messages(username) {
+                Ok(messages) => chat_state.send_to(client_id, serde_json::json!({
+                    "type": "heldMessages",
+                    "messages": messages,
+                })),
+                Err(e) => chat_state.send_error(client_id, e),
+            }
+            return false;
+        }
+        // 审核被过滤器拦下的消息
+        Some(kind @ ("approve" | "discardHeld")) => {
+            if let Some(id) = message.get("id").and_then(|i| i.as_str()) {
+                match chat_state.review_held(username, id, kind == "approve") {
+                    Ok(Some(message)) => chat_state.unfurl(&message),
+                    Ok(None) => {}
+                    Err(e) => chat_state.send_error(client_id, e),
+                }
+            }
+            return false;
+        }
+        Some("typing") => {
+            let typing = message.get("typing").and_then(|t| t.as_bool()).unwrap_or(true);
+            chat_state.set_typing(username, typing);
+            return false;
+        }
+        _ => {}
+    }
+    if let Some(content) = message.get("content").and_then(|c| c.as_str()) {
+        chat_state.set_typing(username, false);
+        match chat_state.post_message(username, content) {
+            Ok(message) => chat_state.unfurl(&message),
+            Err(e) => chat_state.send_error(client_id, e),
+        }
+    }
+    false
 }
 
 async fn handle_connection(
     conn: quinn::Connecting,
     chat_state: Arc<chat::ChatState>,
 ) -> Result<()> {
-    let connection = conn.await?;
+    let connection = match conn.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            chat_state.metrics().record_quic_handshake_error();
+            return Err(e.into());
+        }
+    };
     tracing::info!("New connection: {}", connection.remote_address());
+    // 该连接上登录的聊天会话，上传和下载文件都需要先登录
+    let login: Arc<std::sync::Mutex<Option<usize>>> = Arc::default();
     
-    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
-        let chat_state = chat_state.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_stream(&mut send, &mut recv, chat_state).await {
-                tracing::error!("Stream handling failed: {:?}", e);
+    loop {
+        tokio::select! {
+            stream = connection.accept_bi() => {
+                let Ok((send, recv)) = stream else { break };
+                let chat_state = chat_state.clone();
+                let connection = connection.clone();
+                let login = login.clone();
+                let connection_span = tracing::Span::current();
+                let span = tracing::info_span!("stream", id = send.id().index(), username = tracing::field::Empty);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_stream(connection, send, recv, chat_state, login, connection_span).await {
+                        tracing::error!("Stream handling failed: {:?}", e);
+                    }
+                }.instrument(span));
             }
-        });
+            // 单向流用于上传文件
+            stream = connection.accept_uni() => {
+                let Ok(recv) = stream else { break };
+                let chat_state = chat_state.clone();
+                let client_id = *login.lock().unwrap();
+                let span = tracing::info_span!("upload", id = recv.id().index());
+                tokio::spawn(handle_upload(recv, chat_state, client_id).instrument(span));
+            }
+        }
     }
     
     Ok(())
 }
 
+/// 接收单向流上传的文件：第一行是 JSON 头 {"name", "size", "content"}，之后是文件内容
+async fn handle_upload(
+    mut recv: quinn::RecvStream,
+    chat_state: Arc<chat::ChatState>,
+    client_id: Option<usize>,
+) {
+    let Some((client_id, username)) = client_id.and_then(|id| Some((id, chat_state.username_of(id)?))) else {
+        let _ = recv.stop(UPLOAD_REJECTED);
+        return;
+    };
+    if !chat_state.enforce_rate(client_id, &username, ratelimit::Action::Upload) {
+        let _ = recv.stop(UPLOAD_REJECTED);
+        return;
+    }
+    let max_size = chat_state.blobs().max_size();
+    let result = async {
+        let mut reader = BufReader::new(&mut recv);
+        let mut header = String::new();
+        (&mut reader).take(UPLOAD_HEADER_LIMIT).read_line(&mut header).await?;
+        let header: serde_json::Value = serde_json::from_str(&header)
+            .map_err(|_| anyhow::anyhow!("无效的上传请求"))?;
+        let name = header.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+        if header.get("size").and_then(|s| s.as_u64()).unwrap_or(0) > max_size {
+            anyhow::bail!("文件超过大小限制（{} 字节）", max_size);
+        }
+        // 多读一个字节用于判断是否超过限制
+        let mut data = Vec::new();
+        reader.take(max_size + 1).read_to_end(&mut data).await?;
+        if data.len() as u64 > max_size {
+            anyhow::bail!("文件超过大小限制（{} 字节）", max_size);
+        }
+        let caption = header.get("content").and_then(|c| c.as_str());
+        chat_state.share_file(&username, name, caption, &data).await
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::warn!("{} 上传文件失败: {:?}", username, e);
+        let _ = recv.stop(UPLOAD_REJECTED);
+        chat_state.send_error(client_id, e);
+    }
+}
+
+/// 在双向流上返回文件：先发一行 JSON 头 {"type": "file", ...}，之后是文件内容
+async fn handle_download(
+    mut send: quinn::SendStream,
+    chat_state: Arc<chat::ChatState>,
+    logged_in: bool,
+    id: &str,
+) -> Result<()> {
+    let file = if logged_in {
+        chat_state.blobs().get(id).await
+    } else {
+        Err(anyhow::anyhow!("请先登录"))
+    };
+    match file {
+        Ok((data, content_type)) => {
+            let header = serde_json::json!({
+                "type": "file",
+                "id": id,
+                "size": data.len(),
+                "contentType": content_type,
+            });
+            send.write_all(format!("{}\n", header).as_bytes()).await?;
+            send.write_all(&data).await?;
+        }
+        Err(e) => return reject_stream(send, e).await,
+    }
+    send.finish().await?;
+    Ok(())
+}
+
+/// 回复一个错误事件后结束流
+async fn reject_stream(mut send: quinn::SendStream, message: impl std::fmt::Display) -> Result<()> {
+    let error = serde_json::json!({
+        "type": "error",
+        "message": message.to_string(),
+    });
+    send.write_all(format!("{}\n", error).as_bytes()).await?;
+    send.finish().await?;
+    Ok(())
+}
+
+/// 单行的最大字节数：消息长度按字符计，JSON 转义后每个字符最多 6 字节
+fn max_line_bytes(chat_state: &chat::ChatState) -> usize {
+    chat_state.config().max_message_length * 6 + 1024
+}
+
 async fn handle_stream(
-    send: &mut quinn::SendStream,
-    recv: &mut quinn::RecvStream,
+    connection: quinn::Connection,
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
     chat_state: Arc<chat::ChatState>,
+    login: Arc<std::sync::Mutex<Option<usize>>>,
+    // 连接的 span，登录和改名时同样记录用户名
+    connection_span: tracing::Span,
 ) -> Result<()> {
-    let mut buf = vec![0u8; 1024];
-    let n = recv.read(&mut buf).await?;
+    // 每行一条：第一行是用户名，之后是纯文本消息或 JSON 指令
+    let mut lines = framing::LineReader::new(BufReader::new(recv), max_line_bytes(&chat_state));
     
-    if let Some(n) = n {
-        let username = String::from_utf8_lossy(&buf[..n]).to_string();
-        chat_state.add_user(username.clone());
-        
-        let message = ChatMessage {
-            username: username.clone(),
-            content: format!("{} 加入了聊天室", username),
-            timestamp: chrono::Utc::now(),
+    if let Some(line) = lines.next_line().await? {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return reject_stream(send, e).await,
+        };
+        // 下载文件的请求 {"type": "download", "id": ...} 单独占用一个流
+        if let Ok(request) = serde_json::from_str::<serde_json::Value>(&line) {
+            if request.get("type").and_then(|t| t.as_str()) == Some("download") {
+                let id = request.get("id").and_then(|i| i.as_str()).unwrap_or_default();
+                let logged_in = login.lock().unwrap().is_some();
+                return handle_download(send, chat_state, logged_in, id).await;
+            }
+        }
+        // 第一行也可以是 {"username": ..., "token": ..., "session": ...}，用于验证名字归属和断线重连
+        let (name, token, session) = match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(login) if login.is_object() => (
+                login.get("username").and_then(|u| u.as_str()).unwrap_or_default().to_string(),
+                login.get("token").and_then(|t| t.as_str()).map(|t| t.to_string()),
+                login.get("session").and_then(|s| s.as_str()).map(|s| s.to_string()),
+            ),
+            _ => (line, None, None),
+        };
+        let mut username = match chat_state.validate_username(&name) {
+            Ok(name) => name,
+            Err(e) => return reject_stream(send, e).await,
         };
+        if let Err(e) = chat_state.check_join(&username, Some(connection.remote_address().ip())) {
+            return reject_stream(send, e).await;
+        }
+        if let Some(Err(e)) = session.as_deref().map(|session| chat_state.check_session(&username, session)) {
+            return reject_stream(send, e).await;
+        }
+        // 登录前的流不计入：它们不监听关闭，只能等服务器关闭连接
+        let _connection = chat_state.track_connection();
+        let (client_id, queue) = chat_state.register_client(chat::Transport::Quic(connection.clone()));
+        if queue.is_closed() {
+            chat_state.unregister_client(client_id);
+            return reject_stream(send, "服务器正在关闭").await;
+        }
+        if let Err(e) = chat_state.add_user(username.clone(), client_id, token.as_deref()) {
+            chat_state.unregister_client(client_id);
+            return reject_stream(send, e).await;
+        }
+        tracing::Span::current().record("username", username.as_str());
+        connection_span.record("username", username.as_str());
+        tracing::info!("{} 加入聊天室", username);
+        // 先订阅广播再读取补发内容，期间的消息（包括自己的加入消息）不会漏掉
+        let rx = chat_state.subscribe();
+        let mut cursor = chat_state.cursor();
+        let (session_id, redeliver) = chat_state.open_session(&username, session.as_deref(), &mut cursor);
+        *login.lock().unwrap() = Some(client_id);
+        chat_state.broadcast_user_list();
+        chat_state.send_to(client_id, chat_state.room_list(&username));
+        
+        let message = ChatMessage::notice(format!("{} 加入了聊天室", username));
         
         chat_state.broadcast_message(message);
         
-        // 创建一个新的接收器来接收广播消息
-        let mut rx = chat_state.subscribe();
+        // 发送放在单独的任务里，慢连接不会阻塞读取
+        let writer = tokio::spawn(quic_writer(
+            connection.clone(),
+            send,
+            chat_state.clone(),
+            client_id,
+            queue.clone(),
+            SessionFeed {
+                id: session_id.clone(),
+                rx,
+                cursor,
+                redeliver,
+            },
+        ).in_current_span());
         
-        // 使用 tokio::select! 来处理消息接收和广播
         loop {
             tokio::select! {
-                // 处理广播消息
-                Ok(msg) = rx.recv() => {
-                    let msg_str = serde_json::to_string(&msg).unwrap() + "\n";
-                    if let Err(e) = send.write_all(msg_str.as_bytes()).await {
-                        tracing::error!("发送广播消息失败: {:?}", e);
-                        break;
-                    }
-                    if let Err(e) = send.flush().await {
-                        tracing::error!("刷新广播消息失败: {:?}", e);
-                        break;
+                // datagram 只接受输入提示
+                Ok(datagram) = connection.read_datagram() => {
+                    if let Ok(command) = serde_json::from_slice::<serde_json::Value>(&datagram) {
+                        if command.get("type").and_then(|t| t.as_str()) == Some("typing") {
+                            handle_client_command(&chat_state, client_id, &mut username, &command);
+                        }
                     }
                 }
                 // 处理用户输入
-                Ok(n) = recv.read(&mut buf) => {
-                    if let Some(n) = n {
-                        let content = String::from_utf8_lossy(&buf[..n]).to_string();
-                        let message = ChatMessage {
-                            username: username.clone(),
-                            content,
-                            timestamp: chrono::Utc::now(),
-                        };
-                        chat_state.broadcast_message(message);
-                    } else {
-                        break;
+                line = lines.next_line() => {
+                    match line {
+                        Ok(Some(Ok(line))) => {
+                            let command = match serde_json::from_str::<serde_json::Value>(&line) {
+                                Ok(value) if value.is_object() => value,
+                                _ => serde_json::json!({ "content": line }),
+                            };
+                            // 送达确认只对 QUIC 会话有意义
+                            if command.get("type").and_then(|t| t.as_str()) == Some("ack") {
+                                if let Some(id) = command.get("id").and_then(|i| i.as_str()) {
+                                    chat_state.ack(&session_id, id);
+                                }
+                                continue;
+                            }
+                            if handle_client_command(&chat_state, client_id, &mut username, &command) {
+                                break;
+                            }
+                            if command.get("type").and_then(|t| t.as_str()) == Some("rename") {
+                                connection_span.record("username", username.as_str());
+                            }
+                        }
+                        // 超长或不是 UTF-8 的行只报告错误，不断开连接
+                        Ok(Some(Err(e))) => chat_state.send_error(client_id, e),
+                        _ => break,
                     }
                 }
-                else => break,
+                // 写任务已退出，或发送队列溢出被断开
+                _ = queue.closed() => break,
             }
         }
         
+        chat_state.unregister_client(client_id);
+        login.lock().unwrap().take_if(|id| *id == client_id);
+        chat_state.close_session(&session_id);
         chat_state.remove_user(&username);
-        let message = ChatMessage {
-            username: username.clone(),
-            content: format!("{} 离开了聊天室", username),
-            timestamp: chrono::Utc::now(),
-        };
+        chat_state.broadcast_user_list();
+        let message = ChatMessage::notice(format!("{} 离开了聊天室", username));
         
         chat_state.broadcast_message(message);
+        queue.close();
+        finish_writer(writer).await;
     }
     
     Ok(())
 }
 
-fn configure_server() -> Result<ServerConfig> {
+/// QUIC 会话的广播订阅，在读取补发内容之前创建
+struct SessionFeed {
+    id: String,
+    rx: broadcast::Receiver<ChatMessage>,
+    cursor: chat::BroadcastCursor,
+    // 上次断线时未确认的消息
+    redeliver: Vec<ChatMessage>,
+}
+
+/// QUIC 写任务：广播消息和事件写入流，输入提示走不可靠的 datagram
+async fn quic_writer(
+    connection: quinn::Connection,
+    mut send: quinn::SendStream,
+    chat_state: Arc<chat::ChatState>,
+    client_id: usize,
+    queue: Arc<outbound::OutboundQueue>,
+    feed: SessionFeed,
+) {
+    let SessionFeed { id: session_id, mut rx, mut cursor, redeliver } = feed;
+    // 先告知客户端会话 id，再补发未确认的消息，都排在发送队列中的事件之前
+    let mut resume = format!("{}\n", serde_json::json!({
+        "type": "session",
+        "id": session_id,
+    }));
+    for message in redeliver {
+        chat_state.track_delivery(&session_id, &message);
+        resume += &(serde_json::to_string(&message).unwrap() + "\n");
+    }
+    if let Err(e) = send.write_all(resume.as_bytes()).await {
+        tracing::error!("发送会话信息失败: {:?}", e);
+        queue.close();
+        return;
+    }
+    let mut typing_rx = chat_state.subscribe_ephemeral();
+    loop {
+        let lines = tokio::select! {
+            result = rx.recv() => match broadcast_lines(&chat_state, &mut cursor, Some(&session_id), result) {
+                Some(lines) => lines,
+                None => {
+                    let _ = send.write_all((lag_disconnect_notice() + "\n").as_bytes()).await;
+                    break;
+                }
+            },
+            line = queue.pop() => match line {
+                Some(line) => vec![line],
+                None => break,
+            },
+            // 发送失败直接丢弃
+            Ok(typing) = typing_rx.recv() => {
+                if typing.client_id != Some(client_id) {
+                    let payload = serde_json::to_vec(&typing).unwrap();
+                    let _ = connection.send_datagram(payload.into());
+                }
+                continue;
+            }
+        };
+        let msg_str: String = lines.into_iter().map(|line| line + "\n").collect();
+        if let Err(e) = send.write_all(msg_str.as_bytes()).await {
+            tracing::error!("发送消息失败: {:?}", e);
+            break;
+        }
+    }
+    let _ = send.finish().await;
+    queue.close();
+}
+
+fn configure_server(config: &config::Config) -> Result<ServerConfig> {
     let cert = std::fs::read("cert.der")?;
     let key = std::fs::read("key.der")?;
     
@@ -238,8 +1221,15 @@ fn configure_server() -> Result<ServerConfig> {
     let mut server_config = ServerConfig::with_single_cert(vec![certificate], private_key)?;
     
     let mut transport_config = quinn::TransportConfig::default();
-    transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(5)));
+    // 心跳间隔不超过空闲超时的一半，正常连接不会因空闲被断开
+    let keep_alive = (config.quic_idle_timeout / 2).min(std::time::Duration::from_secs(5));
+    transport_config.keep_alive_interval(Some(keep_alive));
+    transport_config.max_idle_timeout(Some(config.quic_idle_timeout.try_into()?));
+    transport_config.max_concurrent_bidi_streams(config.max_streams_per_connection.into());
+    transport_config.max_concurrent_uni_streams(config.max_streams_per_connection.into());
     server_config.transport = Arc::new(transport_config);
+    // 协议层的硬上限；单个 IP 的限制和带原因的拒绝由应用层的准入检查负责
+    server_config.concurrent_connections(config.max_connections.try_into().unwrap_or(u32::MAX));
     
     Ok(server_config)
 }
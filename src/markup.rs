@@ -0,0 +1,331 @@
+use serde::{Serialize, Deserialize};
+use crate::preview::trim_url;
+
+/// 解析后的消息片段。只支持不嵌套的 Markdown 子集，
+/// 所有文本都按纯文本处理，内容中的 HTML 不会被解释
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Span {
+    Text { text: String },
+    Bold { text: String },
+    Italic { text: String },
+    Strike { text: String },
+    Code { text: String },
+    CodeBlock {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lang: Option<String>,
+    },
+    Link { text: String, url: String },
+    Mention { username: String },
+    LineBreak,
+}
+
+// 链接只允许这些协议，其余（如 javascript:）按纯文本显示
+const SAFE_SCHEMES: &[&str] = &["http://", "https://", "mailto:"];
+
+/// 解析消息内容，`usernames` 用于识别 @提及
+pub fn parse(content: &str, usernames: &[String]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let lines: Vec<&str> = content.split('\n').collect();
+    let mut i = 0;
+    while i < lines.len() {
+        if i > 0 {
+            spans.push(Span::LineBreak);
+        }
+        // ``` 开始的代码块，没有结束标记时按普通文本处理
+        if let Some(lang) = lines[i].trim_start().strip_prefix("```") {
+            if let Some(end) = (i + 1..lines.len()).find(|&j| lines[j].trim() == "```") {
+                let lang = lang.trim();
+                spans.push(Span::CodeBlock {
+                    text: lines[i + 1..end].join("\n"),
+                    lang: (!lang.is_empty()).then(|| lang.to_string()),
+                });
+                i = end + 1;
+                continue;
+            }
+        }
+        parse_inline(lines[i], usernames, &mut spans);
+        i += 1;
+    }
+    spans
+}
+
+/// 只有普通文本时不需要附带解析结果
+pub fn is_plain(spans: &[Span]) -> bool {
+    spans.iter().all(|span| matches!(span, Span::Text { .. }))
+}
+
+/// 被提及的用户，去重后按出现顺序排列
+pub fn mentions(spans: &[Span]) -> Vec<String> {
+    let mut users: Vec<String> = Vec::new();
+    for span in spans {
+        if let Span::Mention { username } = span {
+            if !users.contains(username) {
+                users.push(username.clone());
+            }
+        }
+    }
+    users
+}
+
+fn parse_inline(line: &str, usernames: &[String], spans: &mut Vec<Span>) {
+    let chars: Vec<char> = line.chars().collect();
+    let mut text = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        // 前一个字符是字母或数字时不识别单个 * _ 和 @，避免误伤 snake_case 和邮箱
+        let after_word = i > 0 && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+        let parsed = match c {
+            '\\' if chars.get(i + 1).is_some_and(|&n| "\\`*_~[]@".contains(n)) => {
+                text.push(chars[i + 1]);
+                i += 2;
+                continue;
+            }
+            '`' => delimited(&chars, i, "`").map(|(inner, end)| (Span::Code { text: inner }, end)),
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                delimited(&chars, i, "**").map(|(inner, end)| (Span::Bold { text: inner }, end))
+            }
+            '~' if chars.get(i + 1) == Some(&'~') => {
+                delimited(&chars, i, "~~").map(|(inner, end)| (Span::Strike { text: inner }, end))
+            }
+            '*' | '_' if !after_word => {
+                let delim = if c == '*' { "*" } else { "_" };
+                delimited(&chars, i, delim).map(|(inner, end)| (Span::Italic { text: inner }, end))
+            }
+            '[' => link(&chars, i),
+            '@' if !after_word => mention(&chars, i, usernames),
+            'h' | 'H' if !after_word => bare_url(&chars, i),
+            _ => None,
+        };
+        match parsed {
+            Some((span, end)) => {
+                if !text.is_empty() {
+                    spans.push(Span::Text { text: std::mem::take(&mut text) });
+                }
+                spans.push(span);
+                i = end;
+            }
+            None => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    if !text.is_empty() {
+        spans.push(Span::Text { text });
+    }
+}
+
+/// 从 `start` 开始查找 `pat`，返回其起始位置
+fn find(chars: &[char], start: usize, pat: &str) -> Option<usize> {
+    let pat: Vec<char> = pat.chars().collect();
+    (start..chars.len()).find(|&i| chars[i..].starts_with(&pat))
+}
+
+/// 解析 `delim` 包围的内容，返回内容和结束位置；内容不能为空或以空白开头
+fn delimited(chars: &[char], start: usize, delim: &str) -> Option<(String, usize)> {
+    let len = delim.chars().count();
+    let open = start + len;
+    let close = find(chars, open, delim)?;
+    if close == open || chars[open].is_whitespace() {
+        return None;
+    }
+    Some((chars[open..close].iter().collect(), close + len))
+}
+
+/// [文字](链接)
+fn link(chars: &[char], start: usize) -> Option<(Span, usize)> {
+    let text_end = find(chars, start + 1, "](")?;
+    let url_end = find(chars, text_end + 2, ")")?;
+    let text: String = chars[start + 1..text_end].iter().collect();
+    let url: String = chars[text_end + 2..url_end].iter().collect();
+    let url = url.trim();
+    if text.is_empty() || !is_safe_url(url) {
+        return None;
+    }
+    Some((Span::Link { text, url: url.to_string() }, url_end + 1))
+}
+
+/// 直接写出的 http/https 链接
+fn bare_url(chars: &[char], start: usize) -> Option<(Span, usize)> {
+    let end = (start..chars.len()).find(|&i| chars[i].is_whitespace()).unwrap_or(chars.len());
+    let word: String = chars[start..end].iter().collect();
+    let lower = word.to_ascii_lowercase();
+    if !lower.starts_with("http://") && !lower.starts_with("https://") {
+        return None;
+    }
+    let url = trim_url(&word);
+    if url.len() <= "https://".len() {
+        return None;
+    }
+    let span = Span::Link {
+        text: url.to_string(),
+        url: url.to_string(),
+    };
+    Some((span, start + url.chars().count()))
+}
+
+/// @用户名，匹配在线用户中最长的一个，后面不能紧跟字母或数字
+fn mention(chars: &[char], start: usize, usernames: &[String]) -> Option<(Span, usize)> {
+    let rest = &chars[start + 1..];
+    usernames
+        .iter()
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let name_chars: Vec<char> = name.chars().collect();
+            let boundary = rest
+                .get(name_chars.len())
+                .is_none_or(|&c| !(c.is_alphanumeric() || c == '_'));
+            (rest.starts_with(&name_chars) && boundary).then_some((name, name_chars.len()))
+        })
+        .max_by_key(|(_, len)| *len)
+        .map(|(name, len)| (Span::Mention { username: name.clone() }, start + 1 + len))
+}
+
+fn is_safe_url(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    SAFE_SCHEMES.iter().any(|scheme| lower.starts_with(scheme))
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Span {
+        Span::Text { text: s.to_string() }
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn unsafe_links_stay_text() {
+        for content in [
+            "[点我](javascript:alert(1))",
+            "[点我](JavaScript:alert(1))",
+            "[点我](data:text/html,<script>)",
+        ] {
+            assert_eq!(parse(content, &[]), vec![text(content)], "{}", content);
+        }
+        assert_eq!(
+            parse("[文档](https://example.com/a)", &[]),
+            vec![Span::Link {
+                text: "文档".to_string(),
+                url: "https://example.com/a".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn html_is_not_interpreted() {
+        let content = "<script>alert(1)</script><b>x</b>";
+        assert_eq!(parse(content, &[]), vec![text(content)]);
+    }
+
+    #[test]
+    fn unclosed_delimiters_stay_text() {
+        for content in ["**粗体", "*斜体", "~~删除", "`代码", "[链接", "** 空白**"] {
+            assert_eq!(parse(content, &[]), vec![text(content)], "{}", content);
+        }
+        // 链接缺少右括号时只识别其中的网址
+        assert_eq!(
+            parse("[链接](https://a.com", &[]),
+            vec![
+                text("[链接]("),
+                Span::Link {
+                    text: "https://a.com".to_string(),
+                    url: "https://a.com".to_string(),
+                },
+            ]
+        );
+        // 没有结束标记的代码块按普通行处理
+        assert_eq!(
+            parse("```rust\nlet x = 1;", &[]),
+            vec![text("```rust"), Span::LineBreak, text("let x = 1;")]
+        );
+    }
+
+    #[test]
+    fn inline_and_block_formatting() {
+        assert_eq!(
+            parse("a **b** _c_ ~~d~~ `e*f`", &[]),
+            vec![
+                text("a "),
+                Span::Bold { text: "b".to_string() },
+                text(" "),
+                Span::Italic { text: "c".to_string() },
+                text(" "),
+                Span::Strike { text: "d".to_string() },
+                text(" "),
+                Span::Code { text: "e*f".to_string() },
+            ]
+        );
+        assert_eq!(
+            parse("```rust\nfn main() {}\n```\n完", &[]),
+            vec![
+                Span::CodeBlock {
+                    text: "fn main() {}".to_string(),
+                    lang: Some("rust".to_string()),
+                },
+                Span::LineBreak,
+                text("完"),
+            ]
+        );
+        // snake_case 中的下划线不算斜体
+        assert!(is_plain(&parse("snake_case_name", &[])));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(parse(r"\*不是斜体\*", &[]), vec![text("*不是斜体*")]);
+        assert_eq!(parse(r"\@alice \\", &names(&["alice"])), vec![text(r"@alice \")]);
+        // 不需要转义的字符保留反斜杠
+        assert_eq!(parse(r"C:\dir", &[]), vec![text(r"C:\dir")]);
+    }
+
+    #[test]
+    fn mention_boundaries() {
+        let users = names(&["al", "alice", "bob_1"]);
+        assert_eq!(
+            parse("@alice 你好", &users),
+            vec![Span::Mention { username: "alice".to_string() }, text(" 你好")]
+        );
+        // 名字后紧跟字母、数字或下划线时不算提及
+        assert!(is_plain(&parse("@alicex @al_ @bob_12", &users)));
+        // 邮箱中的 @ 不算提及
+        assert!(is_plain(&parse("bob@alice.com", &users)));
+        assert_eq!(
+            parse("@bob_1,@al。", &users),
+            vec![
+                Span::Mention { username: "bob_1".to_string() },
+                text(","),
+                Span::Mention { username: "al".to_string() },
+                text("。"),
+            ]
+        );
+        let spans = parse("@al @alice @al", &users);
+        assert_eq!(mentions(&spans), names(&["al", "alice"]));
+        // 不在列表中的名字不算提及
+        assert!(is_plain(&parse("@carol", &users)));
+    }
+
+    #[test]
+    fn bare_urls_trim_punctuation() {
+        assert_eq!(
+            parse("看 https://example.com/a.", &[]),
+            vec![
+                text("看 "),
+                Span::Link {
+                    text: "https://example.com/a".to_string(),
+                    url: "https://example.com/a".to_string(),
+                },
+                text("."),
+            ]
+        );
+        assert!(is_plain(&parse("https:// httpx://a", &[])));
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 运行时计数器
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // 广播通道滞后发生的次数，以及因此漏掉的消息总数
+    pub broadcast_lag_events: AtomicU64,
+    pub broadcast_lag_messages: AtomicU64,
+    // 因滞后被断开的连接数
+    pub lag_disconnects: AtomicU64,
+    // 发送队列溢出的次数，以及因此被断开的连接数
+    pub queue_overflows: AtomicU64,
+    pub queue_disconnects: AtomicU64,
+    // 被限流的请求数，以及因多次超限被断开的连接数
+    pub rate_limited: AtomicU64,
+    pub rate_limit_disconnects: AtomicU64,
+    // 因连接数超限被拒绝的连接数
+    pub connections_rejected: AtomicU64,
+    // 广播的消息总数
+    pub messages: AtomicU64,
+    // WebSocket 发送失败的次数
+    pub ws_send_failures: AtomicU64,
+    // 握手失败的次数：QUIC 握手，以及无效的 WebSocket 升级请求
+    pub quic_handshake_errors: AtomicU64,
+    pub ws_handshake_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_lag(&self, missed: u64) {
+        self.broadcast_lag_events.fetch_add(1, Ordering::Relaxed);
+        self.broadcast_lag_messages.fetch_add(missed, Ordering::Relaxed);
+    }
+
+    pub fn record_lag_disconnect(&self) {
+        self.lag_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_queue_overflow(&self) {
+        self.queue_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_queue_disconnect(&self) {
+        self.queue_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limit_disconnect(&self) {
+        self.rate_limit_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ws_send_failure(&self) {
+        self.ws_send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_quic_handshake_error(&self) {
+        self.quic_handshake_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ws_handshake_error(&self) {
+        self.ws_handshake_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 以 Prometheus 格式输出所有计数器
+    pub fn expose(&self, out: &mut Exposition) {
+        let counter = |counter: &AtomicU64| [("", counter.load(Ordering::Relaxed) as f64)];
+        out.metric("chat_messages_total", "counter", "广播的消息总数", counter(&self.messages));
+        out.metric("chat_broadcast_lag_events_total", "counter", "广播通道滞后的次数", counter(&self.broadcast_lag_events));
+        out.metric("chat_broadcast_lag_messages_total", "counter", "因滞后漏掉的消息数", counter(&self.broadcast_lag_messages));
+        out.metric("chat_lag_disconnects_total", "counter", "因滞后被断开的连接数", counter(&self.lag_disconnects));
+        out.metric("chat_queue_overflows_total", "counter", "发送队列溢出的次数", counter(&self.queue_overflows));
+        out.metric("chat_queue_disconnects_total", "counter", "因发送队列溢出被断开的连接数", counter(&self.queue_disconnects));
+        out.metric("chat_rate_limited_total", "counter", "被限流的请求数", counter(&self.rate_limited));
+        out.metric("chat_rate_limit_disconnects_total", "counter", "因多次超限被断开的连接数", counter(&self.rate_limit_disconnects));
+        out.metric("chat_connections_rejected_total", "counter", "因连接数超限被拒绝的连接数", counter(&self.connections_rejected));
+        out.metric("chat_websocket_send_failures_total", "counter", "WebSocket 发送失败的次数", counter(&self.ws_send_failures));
+        out.metric(
+            "chat_handshake_errors_total",
+            "counter",
+            "握手失败的次数",
+            [
+                ("transport=\"quic\"", self.quic_handshake_errors.load(Ordering::Relaxed) as f64),
+                ("transport=\"websocket\"", self.ws_handshake_errors.load(Ordering::Relaxed) as f64),
+            ],
+        );
+    }
+}
+
+/// Prometheus 文本格式（0.0.4）的输出
+#[derive(Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    /// 写入一个指标，`samples` 为（标签，值），标签写成 `key="value"` 形式，没有标签时为空
+    pub fn metric<L: AsRef<str>>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (L, f64)>,
+    ) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let labels = labels.as_ref();
+            if labels.is_empty() {
+                let _ = writeln!(self.text, "{} {}", name, value);
+            } else {
+                let _ = writeln!(self.text, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+/// 房间内的角色，按权限从低到高排列
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // 只能查看，不能发言
+    Guest,
+    Member,
+    // 可以踢出、禁言、封禁权限更低的用户，删除任何人的消息
+    Moderator,
+    // 由配置指定，可以任免管理员
+    Owner,
+}
+
+impl Role {
+    pub fn label(self) -> &'static str {
+        match self {
+            Role::Guest => "访客",
+            Role::Member => "成员",
+            Role::Moderator => "管理员",
+            Role::Owner => "所有者",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "guest" => Ok(Role::Guest),
+            "member" => Ok(Role::Member),
+            "moderator" => Ok(Role::Moderator),
+            "owner" => Ok(Role::Owner),
+            _ => anyhow::bail!("未知的角色: {}", s),
+        }
+    }
+}
+
+/// 封禁对象：用户名或 IP
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanTarget {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::User(username) => f.write_str(username),
+            BanTarget::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ban {
+    pub target: BanTarget,
+    pub by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // 为空表示永久封禁
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > Utc::now())
+    }
+}
+
+/// 审计日志中的一条记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    pub actor: String,
+    // kick、mute、unmute、ban、unban、setRole、clearRoles、delete，以及审核消息的 approve、discard
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // 禁言或封禁的时长，为空表示不限时
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+    // 设置角色时的新角色
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+impl AuditEntry {
+    pub fn new(actor: &str, action: &str, target: impl ToString) -> Self {
+        Self {
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            room: None,
+            target: target.to_string(),
+            reason: None,
+            duration_secs: None,
+            role: None,
+        }
+    }
+}
+
+/// 角色、禁言和封禁的变更。本节点的修改通过 `ModerationStore::apply` 写入，
+/// 同一个变更再经消息总线发给其他节点
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum ModerationChange {
+    SetRole { room: String, username: String, role: Role },
+    ClearRoles { username: String },
+    Mute { room: String, username: String, until: DateTime<Utc> },
+    Unmute { room: String, username: String },
+    Ban { ban: Ban },
+    Unban { target: BanTarget },
+    Rename { old: String, new: String },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ModerationData {
+    // 房间 -> 用户名 -> 角色，没有记录的用户是普通成员
+    #[serde(default)]
+    roles: BTreeMap<String, BTreeMap<String, Role>>,
+    // 房间 -> 用户名 -> 禁言到期时间
+    #[serde(default)]
+    mutes: BTreeMap<String, BTreeMap<String, DateTime<Utc>>>,
+    #[serde(default)]
+    bans: Vec<Ban>,
+}
+
+/// 角色、禁言和封禁，保存为一个 JSON 文件；管理操作另外追加到审计日志
+pub struct ModerationStore {
+    path: PathBuf,
+    data: Mutex<ModerationData>,
+    audit: Mutex<File>,
+    // 配置中指定的所有者，在所有房间都是 Owner
+    owners: Vec<String>,
+}
+
+impl ModerationStore {
+    pub fn open(path: impl AsRef<Path>, audit_path: impl AsRef<Path>, owners: Vec<String>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let data = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            ModerationData::default()
+        };
+        let audit = OpenOptions::new().create(true).append(true).open(audit_path)?;
+        Ok(Self {
+            path,
+            data: Mutex::new(data),
+            audit: Mutex::new(audit),
+            owners,
+        })
+    }
+
+    pub fn role(&self, room: &str, username: &str) -> Role {
+        if self.owners.iter().any(|owner| owner == username) {
+            return Role::Owner;
+        }
+        let data = self.data.lock().unwrap();
+        data.roles
+            .get(room)
+            .and_then(|roles| roles.get(username))
+            .copied()
+            .unwrap_or(Role::Member)
+    }
+
+    fn set_role(&self, room: &str, username: &str, role: Role) {
+        let mut data = self.data.lock().unwrap();
+        let roles = data.roles.entry(room.to_string()).or_default();
+        if role == Role::Member {
+            roles.remove(username);
+        } else {
+            roles.insert(username.to_string(), role);
+        }
+        self.save(&data);
+    }
+
+    /// 移除该用户在所有房间的角色，返回是否有变化
+    fn clear_roles(&self, username: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let mut changed = false;
+        for roles in data.roles.values_mut() {
+            changed |= roles.remove(username).is_some();
+        }
+        if changed {
+            self.save(&data);
+        }
+        changed
+    }
+
+    fn mute(&self, room: &str, username: &str, until: DateTime<Utc>) {
+        let mut data = self.data.lock().unwrap();
+        data.mutes
+            .entry(room.to_string())
+            .or_default()
+            .insert(username.to_string(), until);
+        self.save(&data);
+    }
+
+    fn unmute(&self, room: &str, username: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let removed = data
+            .mutes
+            .get_mut(room)
+            .is_some_and(|mutes| mutes.remove(username).is_some());
+        if removed {
+            self.save(&data);
+        }
+        removed
+    }
+
+    /// 禁言到期时间，未被禁言或已到期时返回 None
+    pub fn muted_until(&self, room: &str, username: &str) -> Option<DateTime<Utc>> {
+        let data = self.data.lock().unwrap();
+        data.mutes
+            .get(room)?
+            .get(username)
+            .copied()
+            .filter(|until| *until > Utc::now())
+    }
+
+    /// 添加封禁，同一对象的旧封禁会被替换
+    fn ban(&self, ban: Ban) {
+        let mut data = self.data.lock().unwrap();
+        data.bans.retain(|b| b.target != ban.target && b.is_active());
+        data.bans.push(ban);
+        self.save(&data);
+    }
+
+    fn unban(&self, target: &BanTarget) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let before = data.bans.len();
+        data.bans.retain(|b| &b.target != target);
+        let removed = data.bans.len() != before;
+        if removed {
+            self.save(&data);
+        }
+        removed
+    }
+
+    /// 查找对该用户名或 IP 仍然有效的封禁
+    pub fn find_ban(&self, username: &str, ip: Option<IpAddr>) -> Option<Ban> {
+        let data = self.data.lock().unwrap();
+        data.bans
+            .iter()
+            .filter(|ban| ban.is_active())
+            .find(|ban| match &ban.target {
+                BanTarget::User(name) => name == username,
+                BanTarget::Ip(banned) => Some(*banned) == ip,
+            })
+            .cloned()
+    }
+
+    /// 该用户名在任一房间有角色或禁言记录（包括已到期的禁言）
+    pub fn has_records(&self, username: &str) -> bool {
+        let data = self.data.lock().unwrap();
+        data.roles.values().any(|roles| roles.contains_key(username))
+            || data.mutes.values().any(|mutes| mutes.contains_key(username))
+    }
+
+    /// 改名时带上角色和禁言，避免通过改名绕过禁言。返回是否有变化
+    fn rename(&self, old: &str, new: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let mut changed = false;
+        for roles in data.roles.values_mut() {
+            if let Some(role) = roles.remove(old) {
+                roles.insert(new.to_string(), role);
+                changed = true;
+            }
+        }
+        for mutes in data.mutes.values_mut() {
+            if let Some(until) = mutes.remove(old) {
+                mutes.insert(new.to_string(), until);
+                changed = true;
+            }
+        }
+        if changed {
+            self.save(&data);
+        }
+        changed
+    }
+
+    /// 应用一项变更，返回是否有变化
+    pub fn apply(&self, change: ModerationChange) -> bool {
+        match change {
+            ModerationChange::SetRole { room, username, role } => {
+                self.set_role(&room, &username, role);
+                true
+            }
+            ModerationChange::ClearRoles { username } => self.clear_roles(&username),
+            ModerationChange::Mute { room, username, until } => {
+                self.mute(&room, &username, until);
+                true
+            }
+            ModerationChange::Unmute { room, username } => self.unmute(&room, &username),
+            ModerationChange::Ban { ban } => {
+                self.ban(ban);
+                true
+            }
+            ModerationChange::Unban { target } => self.unban(&target),
+            ModerationChange::Rename { old, new } => self.rename(&old, &new),
+        }
+    }
+
+    /// 追加一条审计记录
+    pub fn audit(&self, entry: &AuditEntry) {
+        tracing::info!(
+            "管理操作: {} {} {}{}",
+            entry.actor,
+            entry.action,
+            entry.target,
+            entry.reason.as_deref().map(|r| format!("（{}）", r)).unwrap_or_default(),
+        );
+        let line = serde_json::to_string(entry).unwrap() + "\n";
+        if let Err(e) = self.audit.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::error!("写入审计日志失败: {:?}", e);
+        }
+    }
+
+    fn save(&self, data: &ModerationData) {
+        if let Err(e) = crate::storage::save_json(&self.path, data) {
+            tracing::error!("保存管理数据失败: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(dir: &Path) -> ModerationStore {
+        ModerationStore::open(dir.join("moderation.json"), dir.join("audit.jsonl"), vec!["olive".to_string()]).unwrap()
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("quic_chat_moderation_{}", ulid::Ulid::new()))
+    }
+
+    fn ban(target: BanTarget, expires_in: Option<chrono::Duration>) -> Ban {
+        Ban {
+            target,
+            by: "olive".to_string(),
+            reason: None,
+            created_at: Utc::now(),
+            expires_at: expires_in.map(|d| Utc::now() + d),
+        }
+    }
+
+    #[test]
+    fn roles_are_ordered_and_per_room() {
+        assert!(Role::Guest < Role::Member && Role::Member < Role::Moderator && Role::Moderator < Role::Owner);
+        let dir = temp_dir();
+        let store = store(&dir);
+        assert_eq!(store.role("lobby", "olive"), Role::Owner);
+        assert_eq!(store.role("other", "olive"), Role::Owner);
+        assert_eq!(store.role("lobby", "bob"), Role::Member);
+        store.set_role("lobby", "bob", Role::Moderator);
+        assert_eq!(store.role("lobby", "bob"), Role::Moderator);
+        assert_eq!(store.role("other", "bob"), Role::Member);
+        // 设回成员时不留记录
+        store.set_role("lobby", "bob", Role::Member);
+        assert!(!store.has_records("bob"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mutes_and_bans_expire() {
+        let dir = temp_dir();
+        let store = store(&dir);
+        let until = Utc::now() + chrono::Duration::minutes(5);
+        store.mute("lobby", "bob", until);
+        assert_eq!(store.muted_until("lobby", "bob"), Some(until));
+        assert_eq!(store.muted_until("other", "bob"), None);
+        store.mute("lobby", "carol", Utc::now() - chrono::Duration::seconds(1));
+        assert_eq!(store.muted_until("lobby", "carol"), None);
+        // 到期的禁言仍然算作记录，名字不会被别人改用
+        assert!(store.has_records("carol"));
+
+        store.ban(ban(BanTarget::User("bob".to_string()), Some(chrono::Duration::minutes(5))));
+        store.ban(ban(BanTarget::User("carol".to_string()), Some(chrono::Duration::seconds(-1))));
+        store.ban(ban(BanTarget::User("dave".to_string()), None));
+        assert!(store.find_ban("bob", None).is_some());
+        assert!(store.find_ban("carol", None).is_none());
+        assert!(store.find_ban("dave", None).is_some_and(|ban| ban.expires_at.is_none()));
+        // 添加新封禁时清掉过期的记录
+        assert_eq!(store.data.lock().unwrap().bans.len(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ip_bans_match_any_username() {
+        let dir = temp_dir();
+        let store = store(&dir);
+        let ip: IpAddr = "10.0.0.5".parse().unwrap();
+        store.ban(ban(BanTarget::Ip(ip), None));
+        assert!(store.find_ban("anyone", Some(ip)).is_some());
+        assert!(store.find_ban("anyone", Some("10.0.0.6".parse().unwrap())).is_none());
+        assert!(store.find_ban("anyone", None).is_none());
+        assert!(store.unban(&BanTarget::Ip(ip)));
+        assert!(!store.unban(&BanTarget::Ip(ip)));
+        assert!(store.find_ban("anyone", Some(ip)).is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rename_carries_roles_and_mutes() {
+        let dir = temp_dir();
+        let store = store(&dir);
+        let until = Utc::now() + chrono::Duration::minutes(5);
+        store.set_role("lobby", "bob", Role::Moderator);
+        store.mute("other", "bob", until);
+        assert!(store.apply(ModerationChange::Rename { old: "bob".to_string(), new: "robert".to_string() }));
+        assert_eq!(store.role("lobby", "robert"), Role::Moderator);
+        assert_eq!(store.muted_until("other", "robert"), Some(until));
+        assert_eq!(store.role("lobby", "bob"), Role::Member);
+        assert!(!store.has_records("bob"));
+        assert!(!store.apply(ModerationChange::Rename { old: "nobody".to_string(), new: "x".to_string() }));
+
+        // 重新打开后仍然有效
+        let store = self::store(&dir);
+        assert_eq!(store.role("lobby", "robert"), Role::Moderator);
+        assert_eq!(store.muted_until("other", "robert"), Some(until));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
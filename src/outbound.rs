@@ -0,0 +1,224 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use crate::config::OverflowPolicy;
+
+/// 入队结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Push {
+    Queued,
+    // 队列已满，按策略丢弃或合并了旧事件
+    Overflowed,
+    // 队列已满且策略要求断开，或队列早已关闭
+    Closed,
+}
+
+struct Outgoing {
+    line: String,
+    // 相同 key 的事件可以合并，只保留最新的一条
+    key: Option<String>,
+}
+
+struct QueueState {
+    items: VecDeque<Outgoing>,
+    closed: bool,
+}
+
+/// 单个连接的有界发送队列，由该连接的写任务消费
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    items_notify: Notify,
+    closed_notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            items_notify: Notify::new(),
+            closed_notify: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+        }
+    }
+
+    pub fn push(&self, event: &serde_json::Value) -> Push {
+        let item = Outgoing {
+            line: event.to_string(),
+            key: coalesce_key(event),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Push::Closed;
+        }
+
+        let mut result = Push::Queued;
+        if state.items.len() >= self.capacity {
+            result = Push::Overflowed;
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                }
+                OverflowPolicy::Disconnect => {
+                    drop(state);
+                    self.close();
+                    return Push::Closed;
+                }
+                OverflowPolicy::Coalesce => {
+                    // 优先用新事件替换同 key 的旧事件，其次丢弃最旧的可合并事件
+                    if let Some(existing) = item
+                        .key
+                        .as_ref()
+                        .and_then(|key| state.items.iter_mut().find(|i| i.key.as_ref() == Some(key)))
+                    {
+                        existing.line = item.line;
+                        return result;
+                    }
+                    match state.items.iter().position(|i| i.key.is_some()) {
+                        Some(pos) => {
+                            state.items.remove(pos);
+                        }
+                        None => {
+                            drop(state);
+                            self.close();
+                            return Push::Closed;
+                        }
+                    }
+                }
+            }
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.items_notify.notify_one();
+        result
+    }
+
+    /// 取出下一行。队列关闭且已清空时返回 None
+    pub async fn pop(&self) -> Option<String> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    return Some(item.line);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.items_notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.items_notify.notify_one();
+        self.closed_notify.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// 等待队列被关闭（写任务退出或因溢出被断开）
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.closed_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.is_closed() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// 只有状态类事件可以合并：用户列表、在线状态、未读数
+fn coalesce_key(event: &serde_json::Value) -> Option<String> {
+    match event.get("type")?.as_str()? {
+        kind @ ("userList" | "roomList") => Some(kind.to_string()),
+        "presence" => {
+            let username = event.get("user")?.get("username")?.as_str()?;
+            Some(format!("presence:{}", username))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(n: u32) -> serde_json::Value {
+        json!({"type": "message", "n": n})
+    }
+
+    fn presence(username: &str, status: &str) -> serde_json::Value {
+        json!({"type": "presence", "user": {"username": username, "status": status}})
+    }
+
+    // 取出队列中现有的全部内容
+    async fn drain(queue: &OutboundQueue) -> Vec<serde_json::Value> {
+        queue.close();
+        let mut items = Vec::new();
+        while let Some(line) = queue.pop().await {
+            items.push(serde_json::from_str(&line).unwrap());
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_the_head() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::DropOldest);
+        assert_eq!(queue.push(&message(1)), Push::Queued);
+        assert_eq!(queue.push(&message(2)), Push::Queued);
+        assert_eq!(queue.push(&message(3)), Push::Overflowed);
+        assert!(!queue.is_closed());
+        assert_eq!(drain(&queue).await, vec![message(2), message(3)]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::Disconnect);
+        queue.push(&message(1));
+        queue.push(&message(2));
+        assert_eq!(queue.push(&message(3)), Push::Closed);
+        assert!(queue.is_closed());
+        tokio::time::timeout(std::time::Duration::from_secs(1), queue.closed()).await.unwrap();
+        assert_eq!(queue.push(&message(4)), Push::Closed);
+        // 已经入队的内容仍然会发出
+        assert_eq!(drain(&queue).await, vec![message(1), message(2)]);
+    }
+
+    #[tokio::test]
+    async fn coalesce_replaces_pending_state_events() {
+        let queue = OutboundQueue::new(3, OverflowPolicy::Coalesce);
+        queue.push(&json!({"type": "userList", "users": ["a"]}));
+        queue.push(&presence("bob", "online"));
+        queue.push(&message(1));
+        // 同一用户的在线状态、用户列表就地替换为最新的
+        assert_eq!(queue.push(&presence("bob", "away")), Push::Overflowed);
+        assert_eq!(queue.push(&json!({"type": "userList", "users": ["a", "b"]})), Push::Overflowed);
+        // 没有同类事件时丢弃最旧的可合并事件
+        assert_eq!(queue.push(&presence("carol", "online")), Push::Overflowed);
+        assert_eq!(
+            drain(&queue).await,
+            vec![presence("bob", "away"), message(1), presence("carol", "online")]
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesce_disconnects_when_nothing_can_be_merged() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::Coalesce);
+        queue.push(&message(1));
+        queue.push(&message(2));
+        assert_eq!(queue.push(&presence("bob", "online")), Push::Closed);
+        assert!(queue.is_closed());
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::chat::ChatMessage;
+
+// 每个会话最多保留的未确认消息数量
+const MAX_PENDING: usize = 1000;
+
+struct Outbox {
+    // 会话所属的用户，只有同一用户才能恢复
+    username: String,
+    // 已发送但尚未确认的消息，按本地顺序号排序
+    pending: BTreeMap<u64, ChatMessage>,
+    // 最后确认的消息的顺序号
+    last_acked: u64,
+    // 连接断开的时间，重连后清空
+    detached_at: Option<Instant>,
+}
+
+/// QUIC 会话的发件箱，用于断线重连后补发未确认的消息
+pub struct Outboxes {
+    sessions: Mutex<HashMap<String, Outbox>>,
+}
+
+impl Outboxes {
+    pub fn new() -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 新建会话，顺序号不大于 `last_acked` 的消息视为已送达
+    pub fn create(&self, username: &str, last_acked: u64) -> String {
+        let id = ulid::Ulid::new().to_string();
+        self.sessions.lock().unwrap().insert(
+            id.clone(),
+            Outbox {
+                username: username.to_string(),
+                pending: BTreeMap::new(),
+                last_acked,
+                detached_at: None,
+            },
+        );
+        id
+    }
+
+    /// 会话所属的用户，会话不存在或已过期时返回 None
+    pub fn owner(&self, id: &str) -> Option<String> {
+        Some(self.sessions.lock().unwrap().get(id)?.username.clone())
+    }
+
+    /// 恢复 `username` 的会话，返回最后确认的顺序号和未确认的消息。会话属于其他用户时返回 None
+    pub fn resume(&self, id: &str, username: &str) -> Option<(u64, Vec<ChatMessage>)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let outbox = sessions.get_mut(id).filter(|outbox| outbox.username == username)?;
+        outbox.detached_at = None;
+        Some((outbox.last_acked, outbox.pending.values().cloned().collect()))
+    }
+
+    pub fn track(&self, id: &str, message: &ChatMessage) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(outbox) = sessions.get_mut(id) {
+            outbox.pending.insert(message.seq, message.clone());
+            while outbox.pending.len() > MAX_PENDING {
+                outbox.pending.pop_first();
+            }
+        }
+    }
+
+    /// 累积确认：`message_id` 及之前发送的消息都视为已送达。
+    /// 不在待确认列表中的 id（已确认过或未发送过）直接忽略
+    pub fn ack(&self, id: &str, message_id: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(outbox) = sessions.get_mut(id) {
+            let Some(seq) = outbox.pending.values().find(|m| m.id == message_id).map(|m| m.seq) else {
+                return;
+            };
+            outbox.pending.retain(|&pending, _| pending > seq);
+            outbox.last_acked = outbox.last_acked.max(seq);
+        }
+    }
+
+    /// 改名后会话随用户一起转到新名字下
+    pub fn rename(&self, old: &str, new: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        for outbox in sessions.values_mut().filter(|outbox| outbox.username == old) {
+            outbox.username = new.to_string();
+        }
+    }
+
+    pub fn detach(&self, id: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(outbox) = sessions.get_mut(id) {
+            outbox.detached_at = Some(Instant::now());
+        }
+    }
+
+    /// 丢弃断开超过 `retention` 的会话
+    pub fn expire(&self, retention: Duration) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, outbox| {
+            outbox.detached_at.is_none_or(|at| at.elapsed() <= retention)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, seq: u64) -> ChatMessage {
+        let mut message = ChatMessage::new("alice".to_string(), id.to_string());
+        message.id = id.to_string();
+        message.seq = seq;
+        message
+    }
+
+    #[test]
+    fn ack_follows_delivery_order_not_id_order() {
+        let outboxes = Outboxes::new();
+        let session = outboxes.create("alice", 0);
+        // 其他节点时钟偏慢，后送达的消息 id 反而更小
+        outboxes.track(&session, &message("03", 1));
+        outboxes.track(&session, &message("01", 2));
+        outboxes.track(&session, &message("02", 3));
+
+        outboxes.ack(&session, "03");
+        let (last_acked, pending) = outboxes.resume(&session, "alice").unwrap();
+        assert_eq!(last_acked, 1);
+        let ids: Vec<&str> = pending.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["01", "02"]);
+
+        outboxes.ack(&session, "01");
+        let (last_acked, pending) = outboxes.resume(&session, "alice").unwrap();
+        assert_eq!(last_acked, 2);
+        assert_eq!(pending.len(), 1);
+
+        // 重复或未知的确认不会回退
+        outboxes.ack(&session, "03");
+        outboxes.ack(&session, "zz");
+        assert_eq!(outboxes.resume(&session, "alice").unwrap().0, 2);
+    }
+
+    #[test]
+    fn sessions_resume_only_for_their_owner() {
+        let outboxes = Outboxes::new();
+        let session = outboxes.create("alice", 5);
+        outboxes.track(&session, &message("01", 6));
+        assert_eq!(outboxes.owner(&session).as_deref(), Some("alice"));
+        assert!(outboxes.resume(&session, "mallory").is_none());
+        assert!(outboxes.resume("unknown", "alice").is_none());
+
+        outboxes.rename("alice", "alicia");
+        assert!(outboxes.resume(&session, "alice").is_none());
+        let (last_acked, pending) = outboxes.resume(&session, "alicia").unwrap();
+        assert_eq!(last_acked, 5);
+        assert_eq!(pending.len(), 1);
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use serde::{Serialize, Deserialize};
+
+// 每条消息最多预览的链接数
+const MAX_LINKS_PER_MESSAGE: usize = 3;
+// 预览缓存的条目数和有效期。抓取失败的结果只缓存很短的时间，避免一次超时让链接长期没有预览
+const CACHE_CAPACITY: usize = 1000;
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+// 标题和描述的最大字符数
+const TITLE_MAX_CHARS: usize = 200;
+const DESCRIPTION_MAX_CHARS: usize = 500;
+// 最多跟随的重定向次数
+const MAX_REDIRECTS: usize = 3;
+
+/// 链接预览，取自页面的 OpenGraph 信息或标题
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+}
+
+/// 获取页面 HTML，实现需要自行限制大小
+pub trait PreviewFetcher: Send + Sync {
+    fn fetch<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String>>;
+}
+
+/// 通过 HTTP 抓取页面，只读取前 `max_bytes` 字节。
+/// 每一跳重定向都重新检查目标地址，并把连接固定到检查过的 IP，防止 DNS 重绑定
+pub struct HttpFetcher {
+    max_bytes: usize,
+}
+
+impl HttpFetcher {
+    pub fn new(max_bytes: usize) -> Result<Self> {
+        Ok(Self { max_bytes })
+    }
+
+    // 只用于一次请求：不自动跟随重定向，主机名解析到已检查过的地址
+    fn client_for(host: &str, addr: SocketAddr) -> Result<reqwest::Client> {
+        Ok(reqwest::Client::builder()
+            .user_agent("quic-chat-link-preview")
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve(host, addr)
+            .build()?)
+    }
+}
+
+impl PreviewFetcher for HttpFetcher {
+    fn fetch<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let mut url = reqwest::Url::parse(url)?;
+            let mut redirects = 0;
+            let mut response = loop {
+                if !matches!(url.scheme(), "http" | "https") {
+                    anyhow::bail!("不支持的链接协议 {}", url.scheme());
+                }
+                let addr = resolve_public(&url).await?;
+                let host = url.host_str().unwrap_or_default();
+                let response = Self::client_for(host, addr)?.get(url.clone()).send().await?;
+                if !response.status().is_redirection() {
+                    break response.error_for_status()?;
+                }
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    anyhow::bail!("重定向次数过多");
+                }
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|l| l.to_str().ok())
+                    .ok_or_else(|| anyhow::anyhow!("重定向缺少 Location"))?;
+                url = url.join(location)?;
+            };
+            let is_html = response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|t| t.to_str().ok())
+                .is_some_and(|t| t.starts_with("text/html"));
+            if !is_html {
+                anyhow::bail!("不是 HTML 页面");
+            }
+
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                let room = self.max_bytes - body.len();
+                body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+                if body.len() >= self.max_bytes {
+                    break;
+                }
+            }
+            Ok(String::from_utf8_lossy(&body).into_owned())
+        })
+    }
+}
+
+/// 解析链接的主机，所有地址都是公网地址时返回第一个，否则报错
+async fn resolve_public(url: &reqwest::Url) -> Result<SocketAddr> {
+    let host = url.host_str().ok_or_else(|| anyhow::anyhow!("链接缺少主机名"))?;
+    // IPv6 字面量带方括号
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        anyhow::bail!("不允许访问内网地址 {}", addr.ip());
+    }
+    addrs.into_iter().next().ok_or_else(|| anyhow::anyhow!("无法解析 {}", host))
+}
+
+/// 不允许抓取本机、内网和运营商级 NAT 地址
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // 0.0.0.0/8 和 100.64.0.0/10
+                || a == 0
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            // ::ffff:a.b.c.d 实际连接的是 IPv4 地址
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let segments = ip.segments();
+            // NAT64（64:ff9b::/96）和 6to4（2002::/16）会转发到嵌入的 IPv4 地址
+            let embedded = match segments {
+                [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some((high, low)),
+                [0x2002, high, low, ..] => Some((high, low)),
+                _ => None,
+            };
+            if let Some((high, low)) = embedded {
+                return is_public(IpAddr::V4(std::net::Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7 唯一本地地址和 fe80::/10 链路本地地址
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                // 64:ff9b:1::/48 本地 NAT64，嵌入位置不固定，一律拒绝
+                || segments[..3] == [0x64, 0xff9b, 1])
+        }
+    }
+}
+
+/// 抓取并缓存链接预览
+pub struct Unfurler {
+    fetcher: Arc<dyn PreviewFetcher>,
+    timeout: Duration,
+    // 链接 -> （过期时间，预览）
+    cache: Mutex<HashMap<String, (Instant, Option<LinkPreview>)>>,
+}
+
+impl Unfurler {
+    pub fn new(fetcher: Arc<dyn PreviewFetcher>, timeout: Duration) -> Self {
+        Self {
+            fetcher,
+            timeout,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 获取链接预览，页面没有可用信息或抓取失败时返回 None
+    pub async fn preview(&self, url: &str) -> Option<LinkPreview> {
+        if let Some((expires_at, preview)) = self.cache.lock().unwrap().get(url) {
+            if *expires_at > Instant::now() {
+                return preview.clone();
+            }
+        }
+
+        // 页面没有预览信息时同样按成功缓存
+        let (preview, ttl) = match tokio::time::timeout(self.timeout, self.fetcher.fetch(url)).await {
+            Ok(Ok(html)) => (parse_preview(url, &html), CACHE_TTL),
+            Ok(Err(e)) => {
+                tracing::debug!("抓取链接预览失败 {}: {:?}", url, e);
+                (None, FAILURE_TTL)
+            }
+            Err(_) => {
+                tracing::debug!("抓取链接预览超时 {}", url);
+                (None, FAILURE_TTL)
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CAPACITY {
+            let now = Instant::now();
+            cache.retain(|_, (expires_at, _)| *expires_at > now);
+            // 仍然满时淘汰最早过期的一条
+            if cache.len() >= CACHE_CAPACITY {
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, (expires_at, _))| *expires_at)
+                    .map(|(url, _)| url.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+        cache.insert(url.to_string(), (Instant::now() + ttl, preview.clone()));
+        preview
+    }
+}
+
+/// 找出消息中的 http/https 链接，去掉结尾的标点
+pub fn find_urls(content: &str) -> Vec<String> {
+    let mut urls: Vec<String> = Vec::new();
+    for word in content.split_whitespace() {
+        let Some(start) = word.find("http://").or_else(|| word.find("https://")) else {
+            continue;
+        };
+        let url = trim_url(&word[start..]);
+        if reqwest::Url::parse(url).is_ok() && !urls.iter().any(|u| u == url) {
+            urls.push(url.to_string());
+            if urls.len() == MAX_LINKS_PER_MESSAGE {
+                break;
+            }
+        }
+    }
+    urls
+}
+
+/// 去掉链接结尾的标点，这些标点通常属于句子而不是链接
+pub fn trim_url(url: &str) -> &str {
+    url.trim_end_matches(|c: char| {
+        matches!(c, '.' | ',' | ';' | ':' | '!' | '?' | ')' | ']' | '}' | '>' | '"' | '\'')
+            || matches!(c, '，' | '。' | '！' | '？' | '）' | '、')
+    })
+}
+
+/// 从 HTML 中提取 OpenGraph 信息，没有时退回到 <title> 和 description
+fn parse_preview(url: &str, html: &str) -> Option<LinkPreview> {
+    // 只转换 ASCII，字节位置与原文一致
+    let lower = html.to_ascii_lowercase();
+    let mut meta = HashMap::new();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<meta") {
+        let start = pos + start;
+        let Some(end) = lower[start..].find('>') else { break };
+        let attrs = attributes(&html[start + 5..start + end]);
+        let key = attrs.get("property").or_else(|| attrs.get("name"));
+        if let (Some(key), Some(content)) = (key, attrs.get("content")) {
+            meta.entry(key.to_ascii_lowercase()).or_insert_with(|| content.clone());
+        }
+        pos = start + end;
+    }
+
+    let title_tag = lower.find("<title").and_then(|start| {
+        let open = start + lower[start..].find('>')? + 1;
+        let close = open + lower[open..].find("</title")?;
+        Some(html[open..close].to_string())
+    });
+
+    let field = |keys: &[&str], max_chars: usize| {
+        keys.iter()
+            .find_map(|key| meta.get(*key))
+            .map(|value| clean_text(value, max_chars))
+            .filter(|value| !value.is_empty())
+    };
+    let title = field(&["og:title", "twitter:title"], TITLE_MAX_CHARS)
+        .or_else(|| title_tag.map(|t| clean_text(&t, TITLE_MAX_CHARS)).filter(|t| !t.is_empty()));
+    let description = field(&["og:description", "twitter:description", "description"], DESCRIPTION_MAX_CHARS);
+    if title.is_none() && description.is_none() {
+        return None;
+    }
+    // 图片只接受绝对地址，由客户端自行加载
+    let image = field(&["og:image", "twitter:image"], usize::MAX)
+        .filter(|image| image.starts_with("https://") || image.starts_with("http://"));
+
+    Some(LinkPreview {
+        url: url.to_string(),
+        title,
+        description,
+        image,
+        site_name: field(&["og:site_name"], TITLE_MAX_CHARS),
+    })
+}
+
+/// 解析标签中的属性，属性名转为小写
+fn attributes(tag: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            break;
+        }
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        let Some(after_eq) = rest.strip_prefix('=') else {
+            attrs.insert(name, String::new());
+            continue;
+        };
+        let after_eq = after_eq.trim_start();
+        let (value, remaining) = match after_eq.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let body = &after_eq[1..];
+                let end = body.find(quote).unwrap_or(body.len());
+                (&body[..end], &body[(end + 1).min(body.len())..])
+            }
+            _ => {
+                let end = after_eq.find(char::is_whitespace).unwrap_or(after_eq.len());
+                (&after_eq[..end], &after_eq[end..])
+            }
+        };
+        attrs.insert(name, value.to_string());
+        rest = remaining;
+    }
+    attrs
+}
+
+/// 合并空白并截断
+fn clean_text(text: &str, max_chars: usize) -> String {
+    decode_entities(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(max_chars)
+        .collect()
+}
+
+/// 解码常见的 HTML 实体
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 返回固定页面并记录抓取次数的替身
+    struct StaticFetcher {
+        html: &'static str,
+        calls: Mutex<usize>,
+    }
+
+    impl PreviewFetcher for StaticFetcher {
+        fn fetch<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<String>> {
+            *self.calls.lock().unwrap() += 1;
+            Box::pin(async move { Ok(self.html.to_string()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn unfurler_caches_previews() {
+        let fetcher = Arc::new(StaticFetcher {
+            html: r#"<html><head><meta property="og:title" content="Example"></head></html>"#,
+            calls: Mutex::new(0),
+        });
+        let unfurler = Unfurler::new(fetcher.clone(), Duration::from_secs(1));
+        let preview = unfurler.preview("https://example.com/").await.unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Example"));
+        unfurler.preview("https://example.com/").await.unwrap();
+        assert_eq!(*fetcher.calls.lock().unwrap(), 1);
+    }
+
+    // 总是失败的替身
+    struct FailingFetcher {
+        calls: Mutex<usize>,
+    }
+
+    impl PreviewFetcher for FailingFetcher {
+        fn fetch<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<String>> {
+            *self.calls.lock().unwrap() += 1;
+            Box::pin(async { anyhow::bail!("连接被拒绝") })
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_are_cached_briefly() {
+        let fetcher = Arc::new(FailingFetcher { calls: Mutex::new(0) });
+        let unfurler = Unfurler::new(fetcher.clone(), Duration::from_secs(1));
+        let url = "https://example.com/";
+        assert!(unfurler.preview(url).await.is_none());
+        assert!(unfurler.preview(url).await.is_none());
+        assert_eq!(*fetcher.calls.lock().unwrap(), 1);
+
+        let (expires_at, _) = unfurler.cache.lock().unwrap()[url].clone();
+        assert!(expires_at <= Instant::now() + FAILURE_TTL);
+        // 短期缓存过期后重新抓取
+        unfurler.cache.lock().unwrap().get_mut(url).unwrap().0 = Instant::now();
+        unfurler.preview(url).await;
+        assert_eq!(*fetcher.calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn parse_preview_prefers_opengraph() {
+        let html = r#"<title>Fallback</title>
+            <META Property="og:title" content="Tom &amp; Jerry">
+            <meta name='description' content='  some
+              text  '>
+            <meta property="og:image" content="javascript:alert(1)">"#;
+        let preview = parse_preview("https://example.com/", html).unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Tom & Jerry"));
+        assert_eq!(preview.description.as_deref(), Some("some text"));
+        assert_eq!(preview.image, None);
+    }
+
+    #[test]
+    fn parse_preview_falls_back_to_title() {
+        let preview = parse_preview("https://example.com/", "<title> Hello </title>").unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Hello"));
+        assert!(parse_preview("https://example.com/", "<p>nothing</p>").is_none());
+    }
+
+    #[test]
+    fn find_urls_trims_punctuation_and_dedups() {
+        let urls = find_urls("看看 https://example.com/a, 还有（https://example.com/a） http://x.org/b。");
+        assert_eq!(urls, vec!["https://example.com/a", "http://x.org/b"]);
+        assert!(find_urls("ftp://example.com javascript:alert(1)").is_empty());
+    }
+
+    #[test]
+    fn find_urls_limits_count() {
+        let urls = find_urls("http://a.com http://b.com http://c.com http://d.com");
+        assert_eq!(urls.len(), MAX_LINKS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn private_addresses_are_rejected() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "169.254.169.254",
+            "100.64.0.1",
+            "100.127.255.255",
+            "0.1.2.3",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "fd00::1",
+            "fe80::1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::5db8:d822",
+            "2002:7f00:1::",
+            "2002:a00:1:1::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} 应被拒绝", ip);
+        }
+        for ip in ["93.184.216.34", "100.128.0.1", "2606:2800:220:1::1", "64:ff9b::5db8:d822", "2002:5db8:d822::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{} 应被允许", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetcher_rejects_private_hosts() {
+        let fetcher = HttpFetcher::new(1024).unwrap();
+        for url in [
+            "http://127.0.0.1:9/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[64:ff9b::7f00:1]/",
+            "http://[2002:7f00:1::]/",
+            "http://localhost/",
+            "file:///etc/passwd",
+        ] {
+            assert!(fetcher.fetch(url).await.is_err(), "{} 应被拒绝", url);
+        }
+    }
+}
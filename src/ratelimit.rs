@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 同一个 IP 后面可能有多个用户（如 NAT），按 IP 计数的桶放宽这么多倍
+const IP_BUDGET_FACTOR: f64 = 4.0;
+// 统计超限次数的时间窗口，窗口内超限达到上限的连接会被断开
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// 令牌桶参数：每秒补充的令牌数和桶的容量（允许的突发数量）。
+/// 环境变量中写作 `每秒令牌数,容量`，例如 `1,10`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Budget {
+    pub const fn new(per_sec: f64, burst: f64) -> Self {
+        Self { per_sec, burst }
+    }
+
+    fn scaled(self, factor: f64) -> Self {
+        Self::new(self.per_sec * factor, self.burst * factor)
+    }
+}
+
+impl FromStr for Budget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (per_sec, burst) = s
+            .split_once(',')
+            .ok_or_else(|| anyhow::anyhow!("限流参数格式应为 每秒令牌数,容量: {}", s))?;
+        let budget = Self::new(per_sec.trim().parse()?, burst.trim().parse()?);
+        if !(budget.per_sec > 0.0 && budget.burst >= 1.0) {
+            anyhow::bail!("限流参数无效: {}", s);
+        }
+        Ok(budget)
+    }
+}
+
+/// 分别限流的操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    // 发消息、回复、编辑、表情回应等会广播给其他人的操作
+    Message,
+    Join,
+    Upload,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Message => "message",
+            Action::Join => "join",
+            Action::Upload => "upload",
+        }
+    }
+}
+
+/// 计数的对象
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateKey {
+    Session(usize),
+    User(String),
+    Ip(IpAddr),
+}
+
+/// 限流结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    // 超过限制，需要等待这么久才有新的令牌
+    Throttled(Duration),
+    // 短时间内多次超限，应断开连接
+    Disconnect,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_sec).min(budget.burst);
+        self.updated = now;
+    }
+}
+
+/// 按会话、用户名和 IP 分别计数的令牌桶限流
+pub struct RateLimiter {
+    messages: Budget,
+    joins: Budget,
+    uploads: Budget,
+    max_strikes: u32,
+    buckets: Mutex<HashMap<(Action, RateKey), Bucket>>,
+    // 超限次数和窗口开始时间
+    strikes: Mutex<HashMap<RateKey, (u32, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(messages: Budget, joins: Budget, uploads: Budget, max_strikes: u32) -> Self {
+        Self {
+            messages,
+            joins,
+            uploads,
+            max_strikes: max_strikes.max(1),
+            buckets: Mutex::new(HashMap::new()),
+            strikes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn budget(&self, action: Action, key: &RateKey) -> Budget {
+        let budget = match action {
+            Action::Message => self.messages,
+            Action::Join => self.joins,
+            Action::Upload => self.uploads,
+        };
+        match key {
+            RateKey::Ip(_) => budget.scaled(IP_BUDGET_FACTOR),
+            _ => budget,
+        }
+    }
+
+    /// 检查并消耗一个令牌。所有 key 都有令牌时才放行，否则都不扣；
+    /// 超限次数记在第一个 key 上
+    pub fn check(&self, action: Action, keys: &[RateKey]) -> Verdict {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let budget = self.budget(action, key);
+            let bucket = buckets.entry((action, key.clone())).or_insert(Bucket {
+                tokens: budget.burst,
+                updated: now,
+            });
+            bucket.refill(budget, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / budget.per_sec));
+            }
+        }
+        if wait.is_zero() {
+            for key in keys {
+                if let Some(bucket) = buckets.get_mut(&(action, key.clone())) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+            return Verdict::Allowed;
+        }
+        drop(buckets);
+
+        let Some(offender) = keys.first() else {
+            return Verdict::Throttled(wait);
+        };
+        let mut strikes = self.strikes.lock().unwrap();
+        let (count, since) = strikes.entry(offender.clone()).or_insert((0, now));
+        if now.duration_since(*since) > STRIKE_WINDOW {
+            *count = 0;
+            *since = now;
+        }
+        *count += 1;
+        if *count >= self.max_strikes {
+            strikes.remove(offender);
+            return Verdict::Disconnect;
+        }
+        Verdict::Throttled(wait)
+    }
+
+    /// 清理已经回满的桶和过期的超限记录
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|(action, key), bucket| {
+            let budget = self.budget(*action, key);
+            bucket.refill(budget, now);
+            bucket.tokens < budget.burst
+        });
+        self.strikes
+            .lock()
+            .unwrap()
+            .retain(|_, (_, since)| now.duration_since(*since) <= STRIKE_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_strikes: u32) -> RateLimiter {
+        RateLimiter::new(Budget::new(1.0, 2.0), Budget::new(1.0, 1.0), Budget::new(1.0, 1.0), max_strikes)
+    }
+
+    // 把所有桶和超限记录的时间往前推，模拟时间流逝
+    fn advance(limiter: &RateLimiter, by: Duration) {
+        for bucket in limiter.buckets.lock().unwrap().values_mut() {
+            bucket.updated = bucket.updated.checked_sub(by).unwrap();
+        }
+        for (_, since) in limiter.strikes.lock().unwrap().values_mut() {
+            *since = since.checked_sub(by).unwrap();
+        }
+    }
+
+    fn session(id: usize) -> RateKey {
+        RateKey::Session(id)
+    }
+
+    #[test]
+    fn parses_budgets() {
+        assert_eq!("1,10".parse::<Budget>().unwrap(), Budget::new(1.0, 10.0));
+        assert_eq!(" 0.5 , 3 ".parse::<Budget>().unwrap(), Budget::new(0.5, 3.0));
+        for bad in ["1", "a,1", "0,5", "-1,5", "1,0.5"] {
+            assert!(bad.parse::<Budget>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let limiter = limiter(100);
+        let keys = [session(1)];
+        assert_eq!(limiter.check(Action::Message, &keys), Verdict::Allowed);
+        assert_eq!(limiter.check(Action::Message, &keys), Verdict::Allowed);
+        let Verdict::Throttled(wait) = limiter.check(Action::Message, &keys) else {
+            panic!("桶空后应当限流");
+        };
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+
+        advance(&limiter, Duration::from_secs(1));
+        assert_eq!(limiter.check(Action::Message, &keys), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Message, &keys), Verdict::Throttled(_)));
+
+        // 回满后不会超过容量
+        advance(&limiter, Duration::from_secs(60));
+        assert_eq!(limiter.check(Action::Message, &keys), Verdict::Allowed);
+        assert_eq!(limiter.check(Action::Message, &keys), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Message, &keys), Verdict::Throttled(_)));
+    }
+
+    #[test]
+    fn check_is_all_or_nothing() {
+        let limiter = limiter(100);
+        let user = RateKey::User("alice".to_string());
+        // 同一用户的另一个会话用完了用户的令牌
+        for _ in 0..2 {
+            assert_eq!(limiter.check(Action::Message, &[session(2), user.clone()]), Verdict::Allowed);
+        }
+        assert!(matches!(
+            limiter.check(Action::Message, &[session(1), user.clone()]),
+            Verdict::Throttled(_)
+        ));
+        // 被拒绝时会话的令牌没有被扣
+        assert_eq!(limiter.check(Action::Message, &[session(1)]), Verdict::Allowed);
+        assert_eq!(limiter.check(Action::Message, &[session(1)]), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Message, &[session(1)]), Verdict::Throttled(_)));
+    }
+
+    #[test]
+    fn ip_and_action_buckets_are_separate() {
+        let limiter = limiter(100);
+        let ip = [RateKey::Ip("10.0.0.1".parse().unwrap())];
+        // IP 的容量放宽 IP_BUDGET_FACTOR 倍
+        for _ in 0..8 {
+            assert_eq!(limiter.check(Action::Message, &ip), Verdict::Allowed);
+        }
+        assert!(matches!(limiter.check(Action::Message, &ip), Verdict::Throttled(_)));
+        // 不同操作、不同 IP 各自计数
+        assert_eq!(limiter.check(Action::Join, &ip), Verdict::Allowed);
+        assert_eq!(limiter.check(Action::Message, &[RateKey::Ip("10.0.0.2".parse().unwrap())]), Verdict::Allowed);
+    }
+
+    #[test]
+    fn repeated_strikes_disconnect() {
+        let limiter = limiter(3);
+        let keys = [session(1), RateKey::User("alice".to_string())];
+        assert_eq!(limiter.check(Action::Join, &keys), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Join, &keys), Verdict::Throttled(_)));
+        assert!(matches!(limiter.check(Action::Join, &keys), Verdict::Throttled(_)));
+        assert_eq!(limiter.check(Action::Join, &keys), Verdict::Disconnect);
+        // 断开后重新计数
+        assert!(matches!(limiter.check(Action::Join, &keys), Verdict::Throttled(_)));
+
+        // 超过统计窗口的超限不再累计（同时桶已回满）
+        let limiter = self::limiter(3);
+        assert_eq!(limiter.check(Action::Join, &keys), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Join, &keys), Verdict::Throttled(_)));
+        assert!(matches!(limiter.check(Action::Join, &keys), Verdict::Throttled(_)));
+        advance(&limiter, STRIKE_WINDOW + Duration::from_secs(1));
+        assert_eq!(limiter.check(Action::Join, &keys), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Join, &keys), Verdict::Throttled(_)));
+    }
+
+    #[test]
+    fn sweep_drops_full_buckets_and_old_strikes() {
+        let limiter = limiter(100);
+        limiter.check(Action::Join, &[session(1)]);
+        limiter.check(Action::Join, &[session(1)]);
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+        assert_eq!(limiter.strikes.lock().unwrap().len(), 1);
+        advance(&limiter, STRIKE_WINDOW + Duration::from_secs(1));
+        limiter.sweep();
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+        assert!(limiter.strikes.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,11 @@
+use std::path::Path;
+use anyhow::Result;
+use serde::Serialize;
+
+/// 把数据整体写成 JSON 文件。先写临时文件再替换，避免写到一半时留下损坏的文件
+pub fn save_json(path: &Path, value: &impl Serialize) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
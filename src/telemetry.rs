@@ -0,0 +1,141 @@
+use std::str::FromStr;
+use anyhow::Result;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+/// 运行时修改日志过滤规则用的句柄
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// 日志输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    // 每行一个 JSON 对象，带上所在的 span（连接、流）及其字段
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("未知的日志格式: {}", s),
+        }
+    }
+}
+
+/// 初始化日志。过滤规则默认读取 RUST_LOG，可以通过返回的句柄在运行时修改；
+/// 指定了 OTLP 地址时，span 同时通过 OTLP/HTTP 导出
+pub fn init(format: LogFormat, otlp_endpoint: Option<&str>) -> Result<LogHandle> {
+    let (filter, handle) = reload::Layer::new(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    );
+    let otlp = match otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+                .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+                    opentelemetry_sdk::Resource::new([opentelemetry::KeyValue::new(
+                        "service.name",
+                        env!("CARGO_PKG_NAME"),
+                    )]),
+                ))
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with((format == LogFormat::Text).then(fmt::layer))
+        .with((format == LogFormat::Json).then(|| fmt::layer().json().with_current_span(true).with_span_list(true)))
+        .with(otlp)
+        .try_init()?;
+    if let Some(endpoint) = otlp_endpoint {
+        tracing::info!("链路追踪导出到 {}", endpoint);
+    }
+    Ok(handle)
+}
+
+/// 退出前把尚未导出的 span 发出去
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use crate::config::Config;
+
+    // 最简单的 HTTP 收集器：记下每个请求的路径和请求体，回复 200
+    async fn collector() -> (String, mpsc::UnboundedReceiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut data = Vec::new();
+                    let mut chunk = [0; 4096];
+                    loop {
+                        let n = socket.read(&mut chunk).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        data.extend_from_slice(&chunk[..n]);
+                        let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+                            continue;
+                        };
+                        let head = String::from_utf8_lossy(&data[..end]).to_lowercase();
+                        let length: usize = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map_or(0, |v| v.trim().parse().unwrap());
+                        if data.len() < end + 4 + length {
+                            continue;
+                        }
+                        let path = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+                        let _ = tx.send((path, data[end + 4..end + 4 + length].to_vec()));
+                        let _ = socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await;
+                        data.drain(..end + 4 + length);
+                    }
+                });
+            }
+        });
+        (format!("http://{}", addr), rx)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_spans_over_otlp_http() {
+        let (endpoint, mut requests) = collector().await;
+        std::env::set_var("CHAT_OTLP_ENDPOINT", &endpoint);
+        let config = Config::from_env();
+        std::env::remove_var("CHAT_OTLP_ENDPOINT");
+        init(LogFormat::Text, config.otlp_endpoint.as_deref()).unwrap();
+
+        tracing::info_span!("otlp_test_span", transport = "quic").in_scope(|| {
+            tracing::info!("inside the span");
+        });
+        // 关闭时把批量处理器里的 span 全部发出
+        tokio::task::spawn_blocking(shutdown).await.unwrap();
+
+        let (path, body) = tokio::time::timeout(Duration::from_secs(5), requests.recv())
+            .await
+            .expect("收集器没有收到 span")
+            .unwrap();
+        assert!(path.ends_with("/v1/traces"), "{}", path);
+        // protobuf 中的字符串按原样编码
+        for expected in [&b"otlp_test_span"[..], b"quic_chat_server"] {
+            assert!(body.windows(expected.len()).any(|w| w == expected), "{:?}", String::from_utf8_lossy(&body));
+        }
+    }
+}
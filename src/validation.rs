@@ -0,0 +1,101 @@
+use anyhow::Result;
+
+// 保留给服务器使用的用户名，不区分大小写
+const RESERVED_USERNAMES: &[&str] = &["system", "server", "admin"];
+// 状态说明的最大字符数
+pub const STATUS_TEXT_MAX_CHARS: usize = 100;
+
+/// 检查用户名：长度限制，只允许 ASCII 字母、数字和 `_` `-` `.`，不能使用保留名
+pub fn validate_username(name: &str, max_chars: usize) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        anyhow::bail!("用户名不能为空");
+    }
+    if name.chars().count() > max_chars {
+        anyhow::bail!("用户名过长（最多 {} 个字符）", max_chars);
+    }
+    // 只接受 ASCII，避免用西里尔字母等形近字冒充他人（如 "аdmin"）
+    if let Some(c) = name.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))) {
+        anyhow::bail!("用户名不能包含字符 {:?}", c);
+    }
+    if RESERVED_USERNAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(name)) {
+        anyhow::bail!("用户名 {} 是保留名", name);
+    }
+    Ok(name.to_string())
+}
+
+/// 清理消息内容：去掉控制字符和双向文本控制符，统一换行，检查长度
+pub fn sanitize_content(content: &str, max_chars: usize) -> Result<String> {
+    let content = strip_controls(&content.replace("\r\n", "\n"), true);
+    let content = content.trim();
+    if content.is_empty() {
+        anyhow::bail!("消息不能为空");
+    }
+    if content.chars().count() > max_chars {
+        anyhow::bail!("消息过长（最多 {} 个字符）", max_chars);
+    }
+    Ok(content.to_string())
+}
+
+/// 清理单行文本（如状态说明），超长部分截断
+pub fn sanitize_line(text: &str, max_chars: usize) -> String {
+    strip_controls(text, false).trim().chars().take(max_chars).collect()
+}
+
+fn strip_controls(text: &str, keep_newlines: bool) -> String {
+    text.chars()
+        .filter(|&c| {
+            if c == '\n' || c == '\t' {
+                return keep_newlines;
+            }
+            // U+202A..U+202E 和 U+2066..U+2069 可以让文本倒序显示，用来伪装内容
+            !(c.is_control() || ('\u{202a}'..='\u{202e}').contains(&c) || ('\u{2066}'..='\u{2069}').contains(&c))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames() {
+        assert_eq!(validate_username("  alice_1.b-c ", 16).unwrap(), "alice_1.b-c");
+        assert!(validate_username("   ", 16).is_err());
+        assert!(validate_username("abcdefghijklmnopq", 16).is_err());
+        assert!(validate_username("abcdefghijklmnop", 16).is_ok());
+        assert!(validate_username("a b", 16).is_err());
+        assert!(validate_username("a@b", 16).is_err());
+    }
+
+    #[test]
+    fn reserved_usernames_ignore_case() {
+        for name in ["system", "Server", "ADMIN"] {
+            assert!(validate_username(name, 16).is_err(), "{}", name);
+        }
+        assert!(validate_username("admin2", 16).is_ok());
+    }
+
+    #[test]
+    fn rejects_non_ascii_lookalikes() {
+        // 第一个字母是西里尔字母 а
+        assert!(validate_username("\u{430}dmin", 16).is_err());
+        assert!(validate_username("张三", 16).is_err());
+        assert!(validate_username("ｆｕｌｌ", 16).is_err());
+    }
+
+    #[test]
+    fn content_is_cleaned_and_limited() {
+        assert_eq!(sanitize_content(" a\r\nb\u{7}\tc ", 10).unwrap(), "a\nb\tc");
+        assert_eq!(sanitize_content("x\u{202e}y\u{2066}z", 10).unwrap(), "xyz");
+        assert!(sanitize_content(" \u{0} \n", 10).is_err());
+        assert!(sanitize_content("一二三四五", 5).is_ok());
+        assert!(sanitize_content("一二三四五六", 5).is_err());
+    }
+
+    #[test]
+    fn lines_drop_newlines_and_truncate() {
+        assert_eq!(sanitize_line(" busy\nnow\t\u{1b}[31m ", 100), "busynow[31m");
+        assert_eq!(sanitize_line("abcdef", 3), "abc");
+    }
+}